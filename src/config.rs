@@ -1,99 +1,1631 @@
+use crate::{hub::LIFECYCLE_SOURCE, mime::Attachment};
+use base64::Engine;
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    path::Path,
+    time::Duration,
+};
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time, Weekday};
+use time_tz::{timezones, OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, Tz};
+
+/// Example configuration with every type of source and destination, written by
+/// `--generate-config` as a starting point for new users.
+pub const TEMPLATE: &str = include_str!("../exampleconfig.json");
+/// Prefix of the `mappings` value that reads the mappings from a separate file
+const MAPPINGS_INCLUDE_PREFIX: &str = "@include:";
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigContainer {
-    pub destinations: HashMap<String, DestinationConfig>,
-    pub sources: HashMap<String, SourceConfig>,
+    pub destinations: HashMap<String, DestinationEntry>,
+    pub sources: HashMap<String, SourceEntry>,
     pub retryagent: Option<RetryAgentConfig>,
-    pub mappings: HashMap<String, Vec<String>>,
+    /// Further RetryAgents, used by the destinations that name them in their `retryagent`
+    pub retryagents: Option<HashMap<String, RetryAgentConfig>>,
+    pub mappings: HashMap<String, Vec<MappingTarget>>,
+    pub defaults: Option<DefaultsConfig>,
+    /// Log the full chain of causes and backtraces of errors
+    #[serde(default)]
+    pub verbose_errors: bool,
+    /// Maximum number of connections sources establish at the same time
+    pub max_connections: Option<usize>,
+    /// Destination that gets a mail when idlemail starts and when it stops
+    pub lifecycle_destination: Option<String>,
+    /// What happens to a mail every time its delivery to a destination fails, `retry` if not
+    /// set. The other policies replace retrying, they do not apply once retries are exhausted.
+    pub on_delivery_failure: Option<FailurePolicy>,
+    /// Directory failed mails are stored in, with `on_delivery_failure` set to `dead_letter`
+    pub dead_letter_dir: Option<String>,
+    /// Domain of the Message-IDs generated for mails that have none
+    pub message_id_domain: Option<String>,
+    /// Warn about ports that do not match the TLS mode, `true` if not set
+    pub port_warnings: Option<bool>,
+    /// Whether destinations listed twice in a mapping are warned about or rejected
+    pub duplicate_destinations: Option<DuplicateDestinations>,
+}
+
+/// Handling of destinations listed more than once in a mapping, see
+/// `ConfigContainer::duplicate_destinations`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateDestinations {
+    /// Logged as warning, each mail is delivered once
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    /// Rejected by the validation
+    #[serde(rename = "error")]
+    Error,
+}
+impl ConfigContainer {
+    /// Read and merge the given config files, see `merge_config`.
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<ConfigContainer, String> {
+        let mut config = Value::Object(Map::new());
+        for path in paths {
+            let path = path.as_ref();
+            let config_file = File::open(path)
+                .map_err(|e| format!("Failed to open config file {}: {}", path.display(), e))?;
+            let reader = BufReader::new(config_file);
+            let mut fragment: Value = serde_json::from_reader(reader)
+                .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?;
+            Self::include_mappings(&mut fragment, path)?;
+            Self::merge_config(&mut config, fragment)
+                .map_err(|e| format!("Failed to merge config file {}: {}", path.display(), e))?;
+        }
+        Self::apply_defaults(&mut config)?;
+        let config: ConfigContainer = serde_json::from_value(config)
+            .map_err(|e| format!("Failed to parse config file: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Likely misconfigurations of ports, which do not match the TLS mode of the connection
+    /// (e.g. TLS on port 143). They are only warned about, as servers can use any port.
+    pub fn port_warnings(&self) -> Vec<String> {
+        if self.port_warnings == Some(false) {
+            return Vec::new();
+        }
+        let mut warnings = Vec::new();
+        let mut srcnames: Vec<&String> = self.sources.keys().collect();
+        srcnames.sort();
+        for srcname in srcnames {
+            let connection = match &self.sources[srcname].config {
+                SourceConfig::ImapPoll(config) => &config.connection,
+                SourceConfig::ImapIdle(config) => &config.connection,
+                SourceConfig::Test(_) => continue,
+            };
+            // sockets are connected to without TLS, whatever the port
+            let Some(port) = connection.port else {
+                continue;
+            };
+            match (connection.encryption, port) {
+                (ImapEncryption::Tls, 143) => warnings.push(format!(
+                    "Source: {} connects with TLS to port 143, which is used for unencrypted IMAP and STARTTLS. IMAP over TLS usually uses port 993, for STARTTLS set encryption starttls",
+                    srcname
+                )),
+                (ImapEncryption::Starttls | ImapEncryption::None, 993) => warnings.push(format!(
+                    "Source: {} connects without TLS to port 993, which expects TLS from the start. Use encryption tls, or port 143",
+                    srcname
+                )),
+                _ => {}
+            }
+        }
+        let mut dstnames: Vec<&String> = self.destinations.keys().collect();
+        dstnames.sort();
+        for dstname in dstnames {
+            let DestinationConfig::Smtp(config) = &self.destinations[dstname].config else {
+                continue;
+            };
+            let port = config.port;
+            let warning = match (&config.encryption, port) {
+                (Encryption::Ssl, 25 | 587) => format!(
+                    "uses ssl (TLS from the start), but port {} expects starttls. Use port 465 for ssl, or starttls",
+                    port
+                ),
+                (Encryption::Starttls { .. } | Encryption::None, 465) => {
+                    "connects to port 465, which expects ssl (TLS from the start). Use ssl, or port 587 for starttls".to_owned()
+                }
+                (_, 143 | 993) => format!(
+                    "connects to port {}, which is used for IMAP, not SMTP. Use port 465 for ssl, or 587 for starttls",
+                    port
+                ),
+                _ => continue,
+            };
+            warnings.push(format!("Destination: {} {}", dstname, warning));
+        }
+        warnings
+    }
+    /// Destinations listed more than once in a mapping, e.g. after copying and pasting: twice
+    /// as destination for every folder, or twice in the `destinations` of one entry. The hub
+    /// delivers each mail once per destination anyway.
+    pub fn duplicate_destinations(&self) -> Vec<String> {
+        let mut duplicates = Vec::new();
+        let mut srcnames: Vec<&String> = self.mappings.keys().collect();
+        srcnames.sort();
+        for srcname in srcnames {
+            let mut unconditional = HashSet::new();
+            let mut repeated = Vec::new();
+            for target in &self.mappings[srcname] {
+                match target {
+                    MappingTarget::Destination(dstname) => {
+                        if !unconditional.insert(dstname) {
+                            repeated.push(dstname);
+                        }
+                    }
+                    MappingTarget::Filtered { destinations, .. } => {
+                        let mut listed = HashSet::new();
+                        repeated.extend(destinations.iter().filter(|d| !listed.insert(*d)));
+                    }
+                    MappingTarget::Pipeline { .. } => {}
+                }
+            }
+            let mut reported = HashSet::new();
+            for dstname in repeated.into_iter().filter(|d| reported.insert(*d)) {
+                duplicates.push(format!(
+                    "Mapping of source: {} lists destination: {} more than once, each mail is delivered to it once",
+                    srcname, dstname
+                ));
+            }
+        }
+        duplicates
+    }
+    /// Replace a `"mappings": "@include:<path>"` with the mappings read from that file.
+    /// Relative paths are resolved against the directory of the including config file.
+    fn include_mappings(config: &mut Value, config_path: &Path) -> Result<(), String> {
+        let include = match config.get("mappings").and_then(Value::as_str) {
+            Some(mappings) => match mappings.strip_prefix(MAPPINGS_INCLUDE_PREFIX) {
+                Some(include) => include.trim(),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let include_path = config_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(include);
+        let mappings_file = File::open(&include_path).map_err(|e| {
+            format!(
+                "Failed to open mappings file {}: {}",
+                include_path.display(),
+                e
+            )
+        })?;
+        let mappings: Value =
+            serde_json::from_reader(BufReader::new(mappings_file)).map_err(|e| {
+                format!(
+                    "Failed to parse mappings file {}: {}",
+                    include_path.display(),
+                    e
+                )
+            })?;
+        if !mappings.is_object() {
+            return Err(format!(
+                "Mappings file {} has to be a json object",
+                include_path.display()
+            ));
+        }
+        config["mappings"] = mappings;
+        Ok(())
+    }
+    /// Merge a later config file into the previous ones.
+    /// Entries of the named sections (e.g. a single source) are added, or replace an equally
+    /// named entry as a whole. All other top-level keys replace the previous value.
+    fn merge_config(config: &mut Value, fragment: Value) -> Result<(), String> {
+        const MERGED_SECTIONS: [&str; 4] = ["sources", "destinations", "mappings", "defaults"];
+        let (config, fragment) = match (config.as_object_mut(), fragment) {
+            (Some(config), Value::Object(fragment)) => (config, fragment),
+            _ => return Err("Config has to be a json object".to_string()),
+        };
+        for (key, value) in fragment {
+            match (config.get_mut(&key), value) {
+                (Some(Value::Object(section)), Value::Object(entries))
+                    if MERGED_SECTIONS.contains(&key.as_str()) =>
+                {
+                    section.extend(entries);
+                }
+                (_, value) => {
+                    config.insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Merge the optional `defaults` section into every source / destination
+    /// that supports the given keys. Keys that are set explicitly always win.
+    fn apply_defaults(config: &mut Value) -> Result<(), String> {
+        let defaults: DefaultsConfig = match config.get("defaults") {
+            Some(defaults) => serde_json::from_value(defaults.clone())
+                .map_err(|e| format!("Failed to parse defaults: {}", e))?,
+            None => return Ok(()),
+        };
+        if let Some(imap_defaults) = &defaults.imap {
+            let imap_defaults = serde_json::to_value(imap_defaults).unwrap();
+            Self::merge_defaults(
+                config,
+                "sources",
+                &["imap_poll", "imap_idle"],
+                &imap_defaults,
+            );
+        }
+        if let Some(smtp_defaults) = &defaults.smtp {
+            let smtp_defaults = serde_json::to_value(smtp_defaults).unwrap();
+            Self::merge_defaults(config, "destinations", &["smtp"], &smtp_defaults);
+        }
+        Ok(())
+    }
+    fn merge_defaults(config: &mut Value, section: &str, types: &[&str], defaults: &Value) {
+        let (entries, defaults) = match (config.get_mut(section), defaults.as_object()) {
+            (Some(Value::Object(entries)), Some(defaults)) => (entries, defaults),
+            _ => return,
+        };
+        let entries = entries.values_mut().filter_map(Value::as_object_mut);
+        for entry in entries.filter(|e| Self::entry_has_type(e, types)) {
+            // a socket replaces the server and port
+            let socket = entry.contains_key("socket_path");
+            for (key, value) in defaults {
+                if socket && (key == "server" || key == "port") {
+                    continue;
+                }
+                if !value.is_null() && !entry.contains_key(key) {
+                    entry.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+    fn entry_has_type(entry: &Map<String, Value>, types: &[&str]) -> bool {
+        matches!(entry.get("type"), Some(Value::String(t)) if types.contains(&t.as_str()))
+    }
+    fn validate(&self) -> Result<(), String> {
+        for (srcname, targets) in &self.mappings {
+            if !self.sources.contains_key(srcname) {
+                return Err(format!("Unknown source: {} specified in mappings", srcname));
+            }
+            for dstname in targets.iter().flat_map(MappingTarget::destinations) {
+                if !self.destinations.contains_key(dstname) {
+                    return Err(format!(
+                        "Unknown destination: {} specified in mappings",
+                        dstname
+                    ));
+                }
+            }
+            for target in targets {
+                if let MappingTarget::Filtered {
+                    folder: None,
+                    list_id: None,
+                    body: None,
+                    attachment: None,
+                    recipient: None,
+                    ..
+                } = target
+                {
+                    return Err(format!(
+                        "Mapping of source: {} has an entry with destinations, but neither folder, list_id, body, attachment nor recipient",
+                        srcname
+                    ));
+                }
+            }
+            for transformation in targets.iter().flat_map(MappingTarget::pipeline) {
+                transformation.validate()?;
+            }
+        }
+        if self.duplicate_destinations == Some(DuplicateDestinations::Error) {
+            if let Some(duplicate) = self.duplicate_destinations().into_iter().next() {
+                return Err(duplicate);
+            }
+        }
+        for srcname in self.sources.keys() {
+            if !self.mappings.contains_key(srcname) {
+                return Err(format!("Source: {} has no mapping", srcname));
+            }
+        }
+        for (srcname, src) in &self.sources {
+            if let SourceConfig::ImapIdle(ImapIdleSourceConfig {
+                max_fetch_per_wakeup: Some(0),
+                ..
+            }) = &src.config
+            {
+                return Err(format!(
+                    "Source: {} has a max_fetch_per_wakeup of 0",
+                    srcname
+                ));
+            }
+            if let SourceConfig::ImapPoll(config) = &src.config {
+                if config.max_per_poll == Some(0) {
+                    return Err(format!("Source: {} has a max_per_poll of 0", srcname));
+                }
+                if config.mark_skipped_seen && config.max_per_poll.is_none() {
+                    return Err(format!(
+                        "Source: {} has mark_skipped_seen, which requires max_per_poll",
+                        srcname
+                    ));
+                }
+            }
+            if let Some(AutoSubmittedHandling::Destinations(dstnames)) = &src.common.auto_submitted
+            {
+                if dstnames.is_empty() {
+                    return Err(format!(
+                        "Source: {} has no auto_submitted destinations, use \"skip\" to not forward auto-submitted mails",
+                        srcname
+                    ));
+                }
+                if let Some(dstname) = dstnames
+                    .iter()
+                    .find(|dstname| !self.destinations.contains_key(*dstname))
+                {
+                    return Err(format!(
+                        "Unknown destination: {} specified in auto_submitted of source: {}",
+                        dstname, srcname
+                    ));
+                }
+            }
+            if let Some(rate_limit) = &src.common.rate_limit {
+                if rate_limit.max_mails == 0 || rate_limit.period == 0 {
+                    return Err(format!(
+                        "Source: {} has a rate_limit with max_mails or period of 0",
+                        srcname
+                    ));
+                }
+            }
+            if let Some(quiet_hours) = &src.common.quiet_hours {
+                if quiet_hours.start.0 == quiet_hours.end.0 {
+                    return Err(format!(
+                        "Source: {} has quiet_hours that start when they end",
+                        srcname
+                    ));
+                }
+            }
+        }
+        for (dstname, dst) in &self.destinations {
+            if let Some(circuit_breaker) = &dst.common.circuit_breaker {
+                if circuit_breaker.failure_threshold == 0 {
+                    return Err(format!(
+                        "Destination: {} has a circuit_breaker failure_threshold of 0",
+                        dstname
+                    ));
+                }
+            }
+            if let Some(active_hours) = &dst.common.active_hours {
+                if active_hours.start.0 == active_hours.end.0 {
+                    return Err(format!(
+                        "Destination: {} has active_hours that start when they end",
+                        dstname
+                    ));
+                }
+                match self.destinations.get(&active_hours.overflow) {
+                    None => {
+                        return Err(format!(
+                            "Unknown destination: {} specified as overflow of destination: {}",
+                            active_hours.overflow, dstname
+                        ))
+                    }
+                    // a chain of overflows could loop back
+                    Some(overflow) if overflow.common.active_hours.is_some() => {
+                        return Err(format!(
+                            "Destination: {} has an overflow destination with active_hours itself: {}",
+                            dstname, active_hours.overflow
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+            if dst.common.debounce == Some(0) {
+                return Err(format!(
+                    "Destination: {} has a debounce of 0 seconds",
+                    dstname
+                ));
+            }
+            if let Some(ParseErrorPolicy::Destination(error_dstname)) = &dst.common.on_parse_error {
+                if !self.destinations.contains_key(error_dstname) {
+                    return Err(format!(
+                        "Unknown destination: {} specified in on_parse_error of destination: {}",
+                        error_dstname, dstname
+                    ));
+                }
+                if error_dstname == dstname {
+                    return Err(format!(
+                        "Destination: {} has itself as on_parse_error destination",
+                        dstname
+                    ));
+                }
+            }
+            match (&dst.common.reply_to, dst.common.reply_to_existing) {
+                (Some(reply_to), _) if reply_to.trim().is_empty() => {
+                    return Err(format!("Destination: {} has an empty reply_to", dstname));
+                }
+                (Some(reply_to), _) if reply_to.contains(['\r', '\n']) => {
+                    return Err(format!(
+                        "Destination: {} has a reply_to with line breaks",
+                        dstname
+                    ));
+                }
+                (None, Some(_)) => {
+                    return Err(format!(
+                        "Destination: {} has reply_to_existing without reply_to",
+                        dstname
+                    ));
+                }
+                _ => {}
+            }
+            let guarded_sources = dst.common.allowed_sources.iter().flatten();
+            let guarded_sources = guarded_sources.chain(dst.common.denied_sources.iter().flatten());
+            for srcname in guarded_sources {
+                if !self.sources.contains_key(srcname) {
+                    return Err(format!(
+                        "Unknown source: {} specified in source list of destination: {}",
+                        srcname, dstname
+                    ));
+                }
+            }
+        }
+        if let Some(dstname) = &self.lifecycle_destination {
+            if !self.destinations.contains_key(dstname) {
+                return Err(format!(
+                    "Unknown destination: {} specified as lifecycle_destination",
+                    dstname
+                ));
+            }
+            if self.sources.contains_key(LIFECYCLE_SOURCE) {
+                return Err(format!(
+                    "Source: {} has a name reserved for the mails to the lifecycle_destination",
+                    LIFECYCLE_SOURCE
+                ));
+            }
+        }
+        if self.max_connections == Some(0) {
+            return Err("max_connections has to be at least 1".to_string());
+        }
+        if let Some(domain) = &self.message_id_domain {
+            if domain.is_empty()
+                || domain
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "<>@\"".contains(c))
+            {
+                return Err(format!(
+                    "message_id_domain is not a valid domain: {}",
+                    domain
+                ));
+            }
+        }
+        match (self.on_delivery_failure, &self.dead_letter_dir) {
+            (Some(FailurePolicy::Retry), _)
+                if self.retryagent.is_none()
+                    && self
+                        .destinations
+                        .values()
+                        .any(|dst| dst.common.retryagent.is_none()) =>
+            {
+                return Err("on_delivery_failure retry requires a retryagent".to_string());
+            }
+            (Some(FailurePolicy::DeadLetter), Some(dir)) if !Path::new(dir).is_dir() => {
+                return Err(format!("dead_letter_dir is not a directory: {}", dir));
+            }
+            (Some(FailurePolicy::DeadLetter), Some(_)) => {}
+            (Some(FailurePolicy::DeadLetter), None) => {
+                return Err(
+                    "on_delivery_failure dead_letter requires a dead_letter_dir".to_string()
+                );
+            }
+            (_, Some(_)) => {
+                return Err(
+                    "dead_letter_dir is only used with on_delivery_failure dead_letter".to_string(),
+                );
+            }
+            _ => {}
+        }
+        for (srcname, src) in &self.sources {
+            let (connection, sort) = match &src.config {
+                SourceConfig::ImapPoll(config) => (Some(&config.connection), &config.sort),
+                SourceConfig::ImapIdle(config) => (Some(&config.connection), &config.sort),
+                SourceConfig::Test(_) => (None, &None),
+            };
+            if let Some(connection) = connection {
+                connection.validate(srcname)?;
+            }
+            let forwarded_keyword = match &src.config {
+                SourceConfig::ImapPoll(config) => {
+                    config.forwarded_keyword.as_ref().zip(Some(config.keep))
+                }
+                SourceConfig::ImapIdle(config) => {
+                    config.forwarded_keyword.as_ref().zip(Some(config.keep))
+                }
+                SourceConfig::Test(_) => None,
+            };
+            if let Some((keyword, keep)) = forwarded_keyword {
+                if !keep {
+                    return Err(format!(
+                        "Source: {} has a forwarded_keyword, which requires keep",
+                        srcname
+                    ));
+                }
+                // keywords are atoms, so they can not be system flags like \Seen
+                if !is_imap_atom(keyword) {
+                    return Err(format!(
+                        "Source: {} has an invalid forwarded_keyword: {}",
+                        srcname, keyword
+                    ));
+                }
+            }
+            let move_to = match &src.config {
+                SourceConfig::ImapPoll(config) => config.move_to.as_ref().zip(Some(config.keep)),
+                SourceConfig::ImapIdle(config) => config.move_to.as_ref().zip(Some(config.keep)),
+                SourceConfig::Test(_) => None,
+            };
+            if let Some((move_to, keep)) = move_to {
+                if keep {
+                    return Err(format!(
+                        "Source: {} has a move_to, which can not be combined with keep",
+                        srcname
+                    ));
+                }
+                if move_to.is_empty() || move_to.contains(['\r', '\n']) {
+                    return Err(format!(
+                        "Source: {} has an invalid move_to: {:?}",
+                        srcname, move_to
+                    ));
+                }
+            }
+            let readonly = match &src.config {
+                SourceConfig::ImapPoll(config) => Some((
+                    config.readonly,
+                    &config.state_file,
+                    config.keep,
+                    &config.forwarded_keyword,
+                    // mails left for later would be behind the tracked range
+                    config
+                        .max_per_poll
+                        .map(|_| "max_per_poll")
+                        .or(config.grace_minutes.map(|_| "grace_minutes")),
+                )),
+                SourceConfig::ImapIdle(config) => Some((
+                    config.readonly,
+                    &config.state_file,
+                    config.keep,
+                    &config.forwarded_keyword,
+                    config.max_fetch_per_wakeup.map(|_| "max_fetch_per_wakeup"),
+                )),
+                SourceConfig::Test(_) => None,
+            };
+            if let Some((readonly, state_file, keep, forwarded_keyword, max_fetch)) = readonly {
+                if readonly && !keep {
+                    return Err(format!(
+                        "Source: {} is readonly, which requires keep",
+                        srcname
+                    ));
+                }
+                if readonly && forwarded_keyword.is_some() {
+                    return Err(format!(
+                        "Source: {} is readonly, so it can not set a forwarded_keyword",
+                        srcname
+                    ));
+                }
+                // the tracked range of UIDs can not have gaps of mails left for later
+                if let Some(max_fetch) = max_fetch.filter(|_| readonly) {
+                    return Err(format!(
+                        "Source: {} is readonly, which does not support {}",
+                        srcname, max_fetch
+                    ));
+                }
+                if let Some(state_file) = state_file {
+                    if !readonly {
+                        return Err(format!(
+                            "Source: {} has a state_file, which requires readonly",
+                            srcname
+                        ));
+                    }
+                    let dir = Path::new(state_file).parent().unwrap_or(Path::new(""));
+                    if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                        return Err(format!(
+                            "Source: {} has a state_file in a missing directory: {}",
+                            srcname, state_file
+                        ));
+                    }
+                }
+            }
+            if let SourceConfig::ImapPoll(config) = &src.config {
+                if config.max_forward_age == Some(0) || config.delete_older_than == Some(0) {
+                    return Err(format!(
+                        "Source: {} has an age of 0 days, which includes all mails",
+                        srcname
+                    ));
+                }
+                if config.readonly && config.delete_older_than.is_some() {
+                    return Err(format!(
+                        "Source: {} is readonly, so it can not delete_older_than",
+                        srcname
+                    ));
+                }
+                // the readonly state is kept per connection
+                if config.readonly && config.folder_auth.is_some() {
+                    return Err(format!(
+                        "Source: {} is readonly, which does not support folder_auth",
+                        srcname
+                    ));
+                }
+            }
+            if let SourceConfig::ImapPoll(ImapPollSourceConfig {
+                search: Some(search),
+                ..
+            }) = &src.config
+            {
+                if !is_search_criteria(search) {
+                    return Err(format!(
+                        "Source: {} has invalid search criteria: {}",
+                        srcname, search
+                    ));
+                }
+            }
+            let progress_every = match &src.config {
+                SourceConfig::ImapPoll(config) => config.progress_every,
+                SourceConfig::ImapIdle(config) => config.progress_every,
+                SourceConfig::Test(_) => None,
+            };
+            if progress_every == Some(0) {
+                return Err(format!("Source: {} has a progress_every of 0", srcname));
+            }
+            let on_new_mail_command = match &src.config {
+                SourceConfig::ImapPoll(config) => config.on_new_mail_command.as_ref(),
+                SourceConfig::ImapIdle(config) => config.on_new_mail_command.as_ref(),
+                SourceConfig::Test(_) => None,
+            };
+            if let Some(command) = on_new_mail_command {
+                match command.first() {
+                    None => {
+                        return Err(format!(
+                            "Source: {} has an empty on_new_mail_command",
+                            srcname
+                        ))
+                    }
+                    Some(program) if !command_exists(program) => {
+                        return Err(format!(
+                            "Source: {} has an on_new_mail_command that does not exist: {}",
+                            srcname, program
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+            let folder_auths = match &src.config {
+                SourceConfig::ImapPoll(config) => config.folder_auth.as_deref().unwrap_or_default(),
+                _ => &[],
+            };
+            for folder_auth in folder_auths {
+                if folder_auth.folder.trim_matches('/').is_empty() {
+                    return Err(format!(
+                        "Source: {} has a folder_auth without folder",
+                        srcname
+                    ));
+                }
+                ImapConnectionConfig::validate_auth(srcname, &folder_auth.auth)?;
+            }
+            if let Some(sort) = sort {
+                let keys: Vec<&str> = sort.split_whitespace().collect();
+                let valid = !keys.is_empty()
+                    && keys.iter().enumerate().all(|(i, key)| {
+                        let key = key.to_ascii_uppercase();
+                        match keys.get(i + 1) {
+                            // REVERSE applies to the following key
+                            Some(_) if key == "REVERSE" => true,
+                            _ => IMAP_SORT_KEYS.contains(&key.as_str()),
+                        }
+                    });
+                if !valid {
+                    return Err(format!(
+                        "Source: {} has invalid sort criteria: {}. Valid keys are: REVERSE, {}",
+                        srcname,
+                        sort,
+                        IMAP_SORT_KEYS.join(", ")
+                    ));
+                }
+            }
+        }
+        for (dstname, dst) in &self.destinations {
+            if let DestinationConfig::Smtp(SmtpDestinationConfig {
+                auth: Some(AuthMethod::Preauth),
+                ..
+            }) = &dst.config
+            {
+                return Err(format!(
+                    "Destination: {} uses preauth, which is only supported by IMAP sources",
+                    dstname
+                ));
+            }
+            if let DestinationConfig::Smtp(SmtpDestinationConfig {
+                auth: Some(AuthMethod::XOAuth2 { .. }),
+                ..
+            }) = &dst.config
+            {
+                return Err(format!(
+                    "Destination: {} uses xoauth2, which is only supported by IMAP sources",
+                    dstname
+                ));
+            }
+            if let DestinationConfig::Smtp(config) = &dst.config {
+                match (&config.summary, config.forward_mode) {
+                    (None, ForwardMode::Summary) => {
+                        return Err(format!(
+                            "Destination: {} uses forward_mode summary, but has no summary settings",
+                            dstname
+                        ))
+                    }
+                    (Some(_), mode) if mode != ForwardMode::Summary => {
+                        return Err(format!(
+                            "Destination: {} has summary settings, but does not use forward_mode summary",
+                            dstname
+                        ))
+                    }
+                    (Some(summary), _) if summary.max_chars == 0 => {
+                        return Err(format!(
+                            "Destination: {} has a summary max_chars of 0",
+                            dstname
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            if let DestinationConfig::Smtp(SmtpDestinationConfig {
+                recipient_lookup: Some(lookup),
+                ..
+            }) = &dst.config
+            {
+                match lookup {
+                    RecipientLookupConfig::File { path } if !Path::new(path).is_file() => {
+                        return Err(format!(
+                            "Destination: {} has a recipient_lookup file that does not exist: {}",
+                            dstname, path
+                        ))
+                    }
+                    RecipientLookupConfig::Ldap(ldap) => {
+                        if !ldap.url.starts_with("ldap://") && !ldap.url.starts_with("ldaps://") {
+                            return Err(format!(
+                                "Destination: {} has a recipient_lookup url that is neither ldap:// nor ldaps://: {}",
+                                dstname, ldap.url
+                            ));
+                        }
+                        if ldap
+                            .filter
+                            .as_ref()
+                            .is_some_and(|filter| !filter.contains("{}"))
+                        {
+                            return Err(format!(
+                                "Destination: {} has a recipient_lookup filter without {{}} for the address",
+                                dstname
+                            ));
+                        }
+                        if ldap.bind_dn.is_some() != ldap.bind_password.is_some() {
+                            return Err(format!(
+                                "Destination: {} has a recipient_lookup with only one of bind_dn and bind_password",
+                                dstname
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if let DestinationConfig::Smtp(SmtpDestinationConfig {
+                dkim: Some(dkim), ..
+            }) = &dst.config
+            {
+                if !Path::new(&dkim.private_key).is_file() {
+                    return Err(format!(
+                        "Destination: {} has a DKIM private_key that does not exist: {}",
+                        dstname, dkim.private_key
+                    ));
+                }
+            }
+            if let DestinationConfig::Discord(DiscordDestinationConfig {
+                fallback_charset: Some(charset),
+                ..
+            }) = &dst.config
+            {
+                if !crate::mime::is_known_charset(charset) {
+                    return Err(format!(
+                        "Destination: {} has an unknown fallback_charset: {}",
+                        dstname, charset
+                    ));
+                }
+            }
+            if let DestinationConfig::Xmpp(config) = &dst.config {
+                let (local, domain) = config.account();
+                if local.is_empty() || domain.is_empty() {
+                    return Err(format!(
+                        "Destination: {} has a jid without user or domain: {}",
+                        dstname, config.jid
+                    ));
+                }
+                if config.recipient.is_empty() || config.recipient.contains(char::is_whitespace) {
+                    return Err(format!(
+                        "Destination: {} has an invalid recipient: {}",
+                        dstname, config.recipient
+                    ));
+                }
+            }
+            if let DestinationConfig::Exec(config) = &dst.config {
+                if config.max_concurrent == Some(0) {
+                    return Err(format!(
+                        "Destination: {} has to allow at least 1 concurrent child",
+                        dstname
+                    ));
+                }
+            }
+            if let DestinationConfig::Sendmail(config) = &dst.config {
+                let path = config.path.as_deref().unwrap_or("/usr/sbin/sendmail");
+                if !command_exists(path) {
+                    return Err(format!(
+                        "Destination: {} has a sendmail path that does not exist: {}",
+                        dstname, path
+                    ));
+                }
+                if config.recipients.as_ref().is_some_and(Vec::is_empty) {
+                    return Err(format!("Destination: {} has no recipients", dstname));
+                }
+            }
+            #[cfg(unix)]
+            if let DestinationConfig::Fifo(config) = &dst.config {
+                use std::os::unix::fs::FileTypeExt;
+                let is_fifo = std::fs::metadata(&config.path)
+                    .map(|m| m.file_type().is_fifo())
+                    .unwrap_or(false);
+                if !is_fifo {
+                    return Err(format!(
+                        "Destination: {} has a path that is not a named pipe: {}",
+                        dstname, config.path
+                    ));
+                }
+            }
+            if let DestinationConfig::Archive(config) = &dst.config {
+                if !Path::new(&config.path).is_dir() {
+                    return Err(format!(
+                        "Destination: {} has a path that is not a directory: {}",
+                        dstname, config.path
+                    ));
+                }
+            }
+        }
+        let named_retryagents = self.retryagents.iter().flatten();
+        let retryagents = self
+            .retryagent
+            .iter()
+            .map(|config| ("retryagent".to_owned(), config))
+            .chain(
+                named_retryagents.map(|(name, config)| (format!("retryagents: {}", name), config)),
+            );
+        let mut retry_paths = HashSet::new();
+        for (name, config) in retryagents {
+            config.validate().map_err(|e| format!("{}: {}", name, e))?;
+            if let RetryAgentConfig::Filesystem(config) = config {
+                // every agent would load (and send) the mails of the others
+                if !retry_paths.insert(&config.path) {
+                    return Err(format!(
+                        "{}: path is already used by another RetryAgent: {}",
+                        name, config.path
+                    ));
+                }
+            }
+        }
+        for (dstname, dst) in &self.destinations {
+            if let Some(retryagent) = &dst.common.retryagent {
+                if !self
+                    .retryagents
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| name == retryagent)
+                {
+                    return Err(format!(
+                        "Destination: {} has an unknown retryagent: {}",
+                        dstname, retryagent
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum AuthMethod {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "plain")]
+    Plain { user: String, password: String },
+    #[serde(rename = "login")]
+    Login { user: String, password: String },
+    /// IMAP only: the server greets with PREAUTH (e.g. a local authenticating proxy)
+    #[serde(rename = "preauth")]
+    Preauth,
+    /// IMAP only: OAuth2 bearer token, printed by `token_command` (program and arguments),
+    /// given as `access_token`, or read from the environment variable `access_token_env`
+    #[serde(rename = "xoauth2")]
+    XOAuth2 {
+        user: String,
+        token_command: Option<Vec<String>>,
+        access_token: Option<String>,
+        access_token_env: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum Encryption {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "ssl")]
+    Ssl,
+    #[serde(rename = "starttls")]
+    Starttls {
+        /// Abort, if the server does not offer STARTTLS, instead of continuing unencrypted.
+        /// `true` if not set.
+        require_tls: Option<bool>,
+    },
+}
+
+/// How an IMAP source encrypts the connection to its server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImapEncryption {
+    /// TLS from the start (IMAPS, usually port 993)
+    #[default]
+    #[serde(rename = "tls")]
+    Tls,
+    /// Upgrade the connection with STARTTLS before logging in (usually port 143). Fails, if
+    /// the server does not offer it.
+    #[serde(rename = "starttls")]
+    Starttls,
+    /// Mails and credentials are sent unencrypted
+    #[serde(rename = "none")]
+    None,
+}
+
+/// Protocol version of a TLS connection. TLS 1.3 cannot be configured, since native-tls
+/// does not support it as a bound.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+}
+
+/// What happens to a mail, when delivering it to a destination failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Queue it in the retryagent, to try again later
+    #[default]
+    #[serde(rename = "retry")]
+    Retry,
+    /// Store it in the `dead_letter_dir`, without trying again
+    #[serde(rename = "dead_letter")]
+    DeadLetter,
+    /// Discard it
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+/// One entry in the list of destinations a source is mapped to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MappingTarget {
+    /// Destination for mail from every folder
+    Destination(String),
+    /// Destinations only for mail matching all of the given patterns
+    Filtered {
+        /// Pattern for the path of the folder the mail was found in
+        #[serde(default)]
+        folder: Option<String>,
+        /// Pattern for the identifier of the mailing list the mail was sent to
+        #[serde(default)]
+        list_id: Option<String>,
+        /// Regular expression searched for in the text of the mail
+        #[serde(default)]
+        body: Option<BodyPattern>,
+        /// Pattern for the filename or MIME type of one of the mail's attachments
+        #[serde(default)]
+        attachment: Option<String>,
+        /// Pattern for one of the addresses the mail was sent to
+        #[serde(default)]
+        recipient: Option<String>,
+        destinations: Vec<String>,
+    },
+    /// Destination that receives the mail after it passed through the transformations,
+    /// optionally only for mail matching the patterns
+    Pipeline {
+        #[serde(default)]
+        folder: Option<String>,
+        #[serde(default)]
+        list_id: Option<String>,
+        #[serde(default)]
+        body: Option<BodyPattern>,
+        #[serde(default)]
+        attachment: Option<String>,
+        #[serde(default)]
+        recipient: Option<String>,
+        pipeline: Vec<Transformation>,
+        destination: String,
+    },
+}
+impl MappingTarget {
+    pub fn destinations(&self) -> &[String] {
+        match self {
+            MappingTarget::Destination(dstname) => std::slice::from_ref(dstname),
+            MappingTarget::Filtered { destinations, .. } => destinations,
+            MappingTarget::Pipeline { destination, .. } => std::slice::from_ref(destination),
+        }
+    }
+
+    /// Transformations applied, in order, before the mail is handed to the destinations.
+    pub fn pipeline(&self) -> &[Transformation] {
+        match self {
+            MappingTarget::Pipeline { pipeline, .. } => pipeline,
+            _ => &[],
+        }
+    }
+
+    /// The regular expression for the text of the mail, if this target has one.
+    pub fn body_pattern(&self) -> Option<&BodyPattern> {
+        match self {
+            MappingTarget::Destination(_) => None,
+            MappingTarget::Filtered { body, .. } | MappingTarget::Pipeline { body, .. } => {
+                body.as_ref()
+            }
+        }
+    }
+
+    /// The pattern for the mail's attachments, if this target has one.
+    pub fn attachment_pattern(&self) -> Option<&str> {
+        match self {
+            MappingTarget::Destination(_) => None,
+            MappingTarget::Filtered { attachment, .. }
+            | MappingTarget::Pipeline { attachment, .. } => attachment.as_deref(),
+        }
+    }
+
+    /// The pattern for the mail's recipients, if this target has one.
+    pub fn recipient_pattern(&self) -> Option<&str> {
+        match self {
+            MappingTarget::Destination(_) => None,
+            MappingTarget::Filtered { recipient, .. }
+            | MappingTarget::Pipeline { recipient, .. } => recipient.as_deref(),
+        }
+    }
+
+    /// Whether mail from the given folder, sent to the given mailing list and recipients,
+    /// with the given text and attachments, is routed to this target's destinations. Mail
+    /// without folder (from sources that don't know folders), list or text does not match
+    /// a pattern for it.
+    pub fn matches(
+        &self,
+        folder: Option<&str>,
+        list_id: Option<&str>,
+        body: Option<&str>,
+        attachments: &[Attachment],
+        recipients: &[String],
+    ) -> bool {
+        let (folder_pattern, list_id_pattern, body_pattern, attachment_pattern) = match self {
+            MappingTarget::Destination(_) => return true,
+            MappingTarget::Filtered {
+                folder,
+                list_id,
+                body,
+                attachment,
+                ..
+            }
+            | MappingTarget::Pipeline {
+                folder,
+                list_id,
+                body,
+                attachment,
+                ..
+            } => (folder, list_id, body, attachment),
+        };
+        let folder_matches = match (folder_pattern, folder) {
+            (None, _) => true,
+            (Some(pattern), Some(folder)) => glob_match(pattern, folder),
+            (Some(_), None) => false,
+        };
+        // list identifiers are domain names, so they are compared case-insensitively
+        let list_id_matches = match (list_id_pattern, list_id) {
+            (None, _) => true,
+            (Some(pattern), Some(list_id)) => {
+                glob_match(&pattern.to_lowercase(), &list_id.to_lowercase())
+            }
+            (Some(_), None) => false,
+        };
+        let body_matches = match (body_pattern, body) {
+            (None, _) => true,
+            (Some(pattern), Some(body)) => pattern.0.is_match(body),
+            (Some(_), None) => false,
+        };
+        let attachment_matches = match attachment_pattern {
+            None => true,
+            Some(pattern) => attachments.iter().any(|a| a.matches(pattern)),
+        };
+        // the recipients are lowercased already
+        let recipient_matches = match self.recipient_pattern() {
+            None => true,
+            Some(pattern) => {
+                let pattern = pattern.to_lowercase();
+                recipients.iter().any(|r| glob_match(&pattern, r))
+            }
+        };
+        folder_matches && list_id_matches && body_matches && attachment_matches && recipient_matches
+    }
+}
+
+/// Regular expression for the text of a mail. It is compiled when the configuration is read,
+/// so invalid expressions are reported right away.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct BodyPattern(regex::Regex);
+impl TryFrom<String> for BodyPattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        regex::Regex::new(&pattern).map(BodyPattern)
+    }
+}
+impl From<BodyPattern> for String {
+    fn from(pattern: BodyPattern) -> Self {
+        pattern.0.as_str().to_owned()
+    }
+}
+
+/// One stage of a mapping's pipeline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum Transformation {
+    /// Prepend a header to the message
+    #[serde(rename = "add_header")]
+    AddHeader { name: String, value: String },
+    /// Remove all parts that are marked as attachment
+    #[serde(rename = "strip_attachments")]
+    StripAttachments,
+    /// Put a prefix in front of the subject
+    #[serde(rename = "subject_prefix")]
+    SubjectPrefix { prefix: String },
+    /// Split a multipart/digest into the messages it encloses
+    #[serde(rename = "explode_digest")]
+    ExplodeDigest,
+}
+impl Transformation {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Transformation::AddHeader { name, value } => {
+                let valid_name =
+                    !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+                if !valid_name {
+                    return Err(format!("Invalid header name: {:?} in add_header", name));
+                }
+                if value.contains(['\r', '\n']) {
+                    return Err(format!(
+                        "Header value of {} may not contain line breaks",
+                        name
+                    ));
+                }
+            }
+            Transformation::SubjectPrefix { prefix } => {
+                if prefix.contains(['\r', '\n']) {
+                    return Err("Subject prefix may not contain line breaks".to_string());
+                }
+            }
+            Transformation::StripAttachments | Transformation::ExplodeDigest => {}
+        }
+        Ok(())
+    }
+}
+
+/// Whether `value` can be sent as atom in IMAP commands (RFC 3501, 9)
+fn is_imap_atom(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"(){%*\"\\]".contains(&b))
+}
+
+/// Whether `criteria` can be appended to a SEARCH command: not empty, on a single line,
+/// with closed quoted strings and balanced parentheses. The criteria themselves are
+/// checked by the server.
+fn is_search_criteria(criteria: &str) -> bool {
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in criteria.chars() {
+        match c {
+            '\r' | '\n' | '\0' => return false,
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+    !criteria.trim().is_empty() && !quoted && depth == 0
+}
+
+/// Whether `program` is an executable path, or can be found in `PATH`.
+fn command_exists(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Match a `/`-delimited folder path against a pattern, in which `*` matches any sequence.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == path,
+        Some((prefix, rest)) => {
+            let path = match path.strip_prefix(prefix) {
+                Some(path) => path,
+                None => return false,
+            };
+            (0..=path.len())
+                .filter(|i| path.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &path[i..]))
+        }
+    }
+}
+
+// #############
+// # Defaults
+// #############
+
+/// Values inherited by every IMAP source that does not set them itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImapDefaultsConfig {
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub auth: Option<AuthMethod>,
+}
+
+/// Values inherited by every SMTP destination that does not set them itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpDefaultsConfig {
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub encryption: Option<Encryption>,
+    pub auth: Option<AuthMethod>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub imap: Option<ImapDefaultsConfig>,
+    pub smtp: Option<SmtpDefaultsConfig>,
+}
+
+// #############
+// # Sources
+// #############
+
+/// Upper bound for the `pool_size` of IMAP sources. Most providers refuse more
+/// than 10-20 concurrent connections per account.
+pub const IMAP_POOL_SIZE_MAX: usize = 10;
+/// Sort keys of the IMAP SORT extension (RFC 5256)
+const IMAP_SORT_KEYS: [&str; 7] = ["ARRIVAL", "CC", "DATE", "FROM", "SIZE", "SUBJECT", "TO"];
+
+/// Where the IMAP server is reached, see `ImapConnectionConfig::endpoint`
+#[derive(Debug, Clone)]
+pub enum ImapEndpoint {
+    Server {
+        server: String,
+        port: u16,
+    },
+    /// Unix socket of the IMAP server, connected to without TLS
+    Socket {
+        socket_path: String,
+    },
+}
+
+/// Settings of the connection to the IMAP server, flattened into the configuration of the
+/// IMAP sources. Unknown fields are rejected by the source configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImapConnectionConfig {
+    /// Not set when connecting to `socket_path`
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    /// Unix socket of the IMAP server, connected to without TLS instead of `server` and `port`
+    pub socket_path: Option<String>,
+    #[serde(default)]
+    pub encryption: ImapEncryption,
+    pub auth: AuthMethod,
+    /// Number of sessions used to fetch mails in parallel
+    pub pool_size: Option<usize>,
+    /// Lowest TLS version accepted for the connection to the server
+    pub min_tls_version: Option<TlsVersion>,
+    /// Highest TLS version offered to the server
+    pub max_tls_version: Option<TlsVersion>,
+    /// Hostname sent for SNI and expected in the server's certificate, if not `server`
+    pub tls_hostname: Option<String>,
+    /// Additional CA certificate (PEM file) trusted for the server's certificate
+    pub ca_cert: Option<String>,
+    /// Accept any certificate, e.g. a self-signed one. Defaults to `false`
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// Accept certificates issued for another hostname. Defaults to `false`
+    #[serde(default)]
+    pub accept_invalid_hostnames: bool,
+    /// Extensions to ENABLE after login, e.g. `UTF8=ACCEPT`
+    pub enable: Option<Vec<String>>,
+    /// Milliseconds to wait after the server greeting before logging in
+    pub login_delay_ms: Option<u64>,
 }
-impl ConfigContainer {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ConfigContainer, String> {
-        let config_file =
-            File::open(path).map_err(|e| format!("Failed to open config file: {}", e))?;
-        let reader = BufReader::new(config_file);
-        let config: ConfigContainer = serde_json::from_reader(reader)
-            .map_err(|e| format!("Failed to parse config file: {}", e))?;
-        config.validate()?;
-        Ok(config)
+
+impl ImapConnectionConfig {
+    /// The server and port, or the socket. `None` unless exactly one of them is set.
+    pub fn endpoint(&self) -> Option<ImapEndpoint> {
+        match (&self.server, self.port, &self.socket_path) {
+            (Some(server), Some(port), None) => Some(ImapEndpoint::Server {
+                server: server.clone(),
+                port,
+            }),
+            (None, None, Some(socket_path)) => Some(ImapEndpoint::Socket {
+                socket_path: socket_path.clone(),
+            }),
+            _ => None,
+        }
     }
-    fn validate(&self) -> Result<(), String> {
-        for (srcname, dsts) in &self.mappings {
-            if !self.sources.contains_key(srcname) {
-                return Err(format!("Unknown source: {} specified in mappings", srcname));
+    fn validate(&self, srcname: &str) -> Result<(), String> {
+        Self::validate_auth(srcname, &self.auth)?;
+        if let Some(pool_size) = self.pool_size {
+            if !(1..=IMAP_POOL_SIZE_MAX).contains(&pool_size) {
+                return Err(format!(
+                    "Source: {} has pool_size {}, which has to be between 1 and {}",
+                    srcname, pool_size, IMAP_POOL_SIZE_MAX
+                ));
             }
-            for dstname in dsts {
-                if !self.destinations.contains_key(dstname) {
+        }
+        for extension in self.enable.iter().flatten() {
+            // the names are sent as atoms in the ENABLE command
+            if !is_imap_atom(extension) {
+                return Err(format!(
+                    "Source: {} has an invalid extension in enable: {}",
+                    srcname, extension
+                ));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_tls_version, self.max_tls_version) {
+            if min > max {
+                return Err(format!(
+                    "Source: {} has a min_tls_version above its max_tls_version",
+                    srcname
+                ));
+            }
+        }
+        if let Some(tls_hostname) = &self.tls_hostname {
+            if tls_hostname.is_empty() || tls_hostname.contains(char::is_whitespace) {
+                return Err(format!(
+                    "Source: {} has an invalid tls_hostname: {}",
+                    srcname, tls_hostname
+                ));
+            }
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            if !Path::new(ca_cert).is_file() {
+                return Err(format!(
+                    "Source: {} has a ca_cert that does not exist: {}",
+                    srcname, ca_cert
+                ));
+            }
+        }
+        let tls_settings = self.tls_hostname.is_some()
+            || self.min_tls_version.is_some()
+            || self.max_tls_version.is_some()
+            || self.ca_cert.is_some()
+            || self.accept_invalid_certs
+            || self.accept_invalid_hostnames;
+        match (self.endpoint(), self.encryption) {
+            (None, _) if self.socket_path.is_some() => {
+                return Err(format!(
+                    "Source: {} has a socket_path and a server or port, use either",
+                    srcname
+                ));
+            }
+            (None, _) => {
+                return Err(format!(
+                    "Source: {} needs a server and port, or a socket_path",
+                    srcname
+                ));
+            }
+            (Some(ImapEndpoint::Socket { .. }), encryption) => {
+                if tls_settings || encryption != ImapEncryption::Tls {
                     return Err(format!(
-                        "Unknown destination: {} specified in mappings",
-                        dstname
+                        "Source: {} connects to its socket_path without TLS, it can not have encryption or TLS settings (tls_hostname, min_tls_version, max_tls_version, ca_cert, accept_invalid_certs, accept_invalid_hostnames)",
+                        srcname
+                    ));
+                }
+                if !cfg!(unix) {
+                    return Err(format!(
+                        "Source: {} has a socket_path, which is only supported on Unix",
+                        srcname
                     ));
                 }
             }
-        }
-        for srcname in self.sources.keys() {
-            if !self.mappings.contains_key(srcname) {
-                return Err(format!("Source: {} has no mapping", srcname));
+            (Some(ImapEndpoint::Server { .. }), ImapEncryption::None) if tls_settings => {
+                return Err(format!(
+                    "Source: {} uses encryption none, it can not have TLS settings (tls_hostname, min_tls_version, max_tls_version, ca_cert, accept_invalid_certs, accept_invalid_hostnames)",
+                    srcname
+                ));
             }
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Checks of `auth`, which are also needed for the credentials of `folder_auth`
+    fn validate_auth(srcname: &str, auth: &AuthMethod) -> Result<(), String> {
+        let AuthMethod::XOAuth2 {
+            token_command,
+            access_token,
+            access_token_env,
+            ..
+        } = auth
+        else {
+            return Ok(());
+        };
+        if access_token.is_some() && access_token_env.is_some() {
+            return Err(format!(
+                "Source: {} has both an access_token and an access_token_env, use either",
+                srcname
+            ));
         }
-        if let Some(RetryAgentConfig::Filesystem(config)) = &self.retryagent {
-            if !Path::new(&config.path).exists() {
-                return Err("FilesystemRetryAgent: Path does not exist".to_string());
+        if token_command.is_some() == (access_token.is_some() || access_token_env.is_some()) {
+            return Err(format!(
+                "Source: {} needs either a token_command, an access_token or an access_token_env for xoauth2",
+                srcname
+            ));
+        }
+        if let Some(variable) = access_token_env {
+            if std::env::var_os(variable).is_none() {
+                return Err(format!(
+                    "Source: {} has an access_token_env that is not set: {}",
+                    srcname, variable
+                ));
             }
         }
-        Ok(())
+        match token_command.as_ref().map(|command| command.first()) {
+            Some(None) => Err(format!("Source: {} has an empty token_command", srcname)),
+            Some(Some(program)) if !command_exists(program) => Err(format!(
+                "Source: {} has a token_command that does not exist: {}",
+                srcname, program
+            )),
+            _ => Ok(()),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
-#[serde(tag = "type")]
-pub enum AuthMethod {
-    #[serde(rename = "none")]
-    None,
-    #[serde(rename = "plain")]
-    Plain { user: String, password: String },
-    #[serde(rename = "login")]
-    Login { user: String, password: String },
+pub struct ImapPollSourceConfig {
+    #[serde(flatten)]
+    pub connection: ImapConnectionConfig,
+    pub interval: u64,
+    pub keep: bool,
+    /// SORT criteria for unseen mails, e.g. `DATE`
+    pub sort: Option<String>,
+    /// Seconds to keep retrying the first connection at startup, before the source gives up
+    pub startup_retry: Option<u64>,
+    /// Forward only the first of multiple mails with the same Message-ID found in one cycle
+    #[serde(default)]
+    pub dedup_batch: bool,
+    /// Use one connection with all other sources sharing this flag and the same connection
+    /// settings
+    #[serde(default)]
+    pub share_connection: bool,
+    /// Folders with any of these LIST attributes (e.g. `\Trash`) are not searched for mails
+    pub exclude_attributes: Option<Vec<String>>,
+    /// With `keep`, mark forwarded mails with this keyword (e.g. `$Forwarded`) instead of
+    /// marking them as read
+    pub forwarded_keyword: Option<String>,
+    /// Without `keep`, move forwarded mails to this mailbox instead of deleting them
+    pub move_to: Option<String>,
+    /// Additional SEARCH criteria unseen mails have to match, e.g. `TEXT "invoice"`
+    pub search: Option<String>,
+    /// Only forward mails with an attachment whose filename or MIME type matches, e.g. `*.pdf`
+    pub attachment_filter: Option<String>,
+    /// Days since their arrival, after which unseen mails are not forwarded anymore
+    pub max_forward_age: Option<u32>,
+    /// Days since their arrival, after which mails are deleted, whether forwarded or not
+    pub delete_older_than: Option<u32>,
+    /// Never change the mailboxes, forwarded mails are tracked by idlemail instead
+    #[serde(default)]
+    pub readonly: bool,
+    /// With `readonly`, file keeping track of the forwarded mails across restarts
+    pub state_file: Option<String>,
+    /// Log in with other credentials for these folders and their subfolders
+    pub folder_auth: Option<Vec<FolderAuthConfig>>,
+    /// With `keep` and no filter, the source stops at startup if more mails are unread
+    pub max_initial: Option<usize>,
+    /// Forward any number of unread mails at startup, see `max_initial`
+    #[serde(default)]
+    pub confirm_bulk: bool,
+    /// Seconds without a poll, after which the connection is closed until the next poll
+    pub close_idle_after: Option<u64>,
+    /// Process unseen mails in reverse order, the newest first
+    #[serde(default)]
+    pub newest_first: bool,
+    /// Maximum number of mails forwarded per poll, the others are left for the next one
+    pub max_per_poll: Option<usize>,
+    /// Mark the mails beyond `max_per_poll` as read, instead of leaving them for the next poll
+    #[serde(default)]
+    pub mark_skipped_seen: bool,
+    /// Program and arguments run after every cycle in which mails were found
+    pub on_new_mail_command: Option<Vec<String>>,
+    /// Log the progress of a cycle after every this many mails fetched from a mailbox
+    pub progress_every: Option<usize>,
+    /// Times fetching a single mail is retried, before it is skipped until the next cycle
+    pub fetch_retries: Option<u32>,
+    /// Minutes a mail has to stay unread after its arrival, before it is forwarded
+    pub grace_minutes: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
-#[serde(tag = "type")]
-pub enum Encryption {
-    #[serde(rename = "none")]
-    None,
-    #[serde(rename = "ssl")]
-    Ssl,
-    #[serde(rename = "starttls")]
-    Starttls,
+impl ImapPollSourceConfig {
+    /// Identifies the account and all settings of the connection, see `share_connection`
+    pub fn connection_key(&self) -> String {
+        serde_json::to_string(&self.connection).expect("Configuration is serializable")
+    }
 }
 
-// #############
-// # Sources
-// #############
-
+/// Credentials for one subtree of the folders of a poll source
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
-pub struct ImapPollSourceConfig {
-    pub server: String,
-    pub port: u16,
-    pub interval: u64,
-    pub keep: bool,
+pub struct FolderAuthConfig {
+    /// `/`-delimited path of the folder
+    pub folder: String,
     pub auth: AuthMethod,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ImapIdleSourceConfig {
-    pub server: String,
-    pub port: u16,
+    #[serde(flatten)]
+    pub connection: ImapConnectionConfig,
     pub path: String,
     pub renewinterval: u64,
     pub keep: bool,
-    pub auth: AuthMethod,
+    /// SORT criteria for unseen mails, e.g. `DATE`
+    pub sort: Option<String>,
+    /// Seconds to keep retrying the first connection at startup, before the source gives up
+    pub startup_retry: Option<u64>,
+    /// Forward only the first of multiple mails with the same Message-ID found in one cycle
+    #[serde(default)]
+    pub dedup_batch: bool,
+    /// Folders with any of these LIST attributes (e.g. `\Trash`) are not searched for mails
+    pub exclude_attributes: Option<Vec<String>>,
+    /// With `keep`, mark forwarded mails with this keyword (e.g. `$Forwarded`) instead of
+    /// marking them as read
+    pub forwarded_keyword: Option<String>,
+    /// Without `keep`, move forwarded mails to this mailbox instead of deleting them
+    pub move_to: Option<String>,
+    /// Only forward mails with an attachment whose filename or MIME type matches, e.g. `*.pdf`
+    pub attachment_filter: Option<String>,
+    /// Maximum number of mails fetched per cycle, the rest follows in the next one
+    pub max_fetch_per_wakeup: Option<usize>,
+    /// Never change the mailbox, forwarded mails are tracked by idlemail instead
+    #[serde(default)]
+    pub readonly: bool,
+    /// With `readonly`, file keeping track of the forwarded mails across restarts
+    pub state_file: Option<String>,
+    /// Poll every `renewinterval` seconds, if the server does not support IDLE
+    #[serde(default)]
+    pub fallback_to_poll: bool,
+    /// Program and arguments run after every cycle in which mails were found
+    pub on_new_mail_command: Option<Vec<String>>,
+    /// Log the progress of a cycle after every this many mails fetched from a mailbox
+    pub progress_every: Option<usize>,
+    /// Times fetching a single mail is retried, before it is skipped until the next cycle
+    pub fetch_retries: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -115,6 +1647,161 @@ pub enum SourceConfig {
     ImapIdle(ImapIdleSourceConfig),
 }
 
+/// Options that apply to every source, independent of its type.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SourceCommonConfig {
+    /// Inject an `X-Idlemail-Source` header naming the source into every mail.
+    #[serde(default)]
+    pub tag_source: bool,
+    /// Hold the source's mails in the hub during these hours, and deliver them afterwards
+    pub quiet_hours: Option<QuietHoursConfig>,
+    /// Count the mails of every folder, for the shutdown report
+    #[serde(default)]
+    pub folder_statistics: bool,
+    /// What happens to mails with an `Auto-Submitted` header, e.g. vacation replies and bounces
+    pub auto_submitted: Option<AutoSubmittedHandling>,
+    /// Maximum number of mails forwarded within a period, e.g. for an account that is flooded
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// Maximum number of mails a source forwards within a sliding window of `period` seconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_mails: usize,
+    pub period: u64,
+    /// What happens to the mails beyond the limit
+    pub excess: Option<RateLimitExcess>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitExcess {
+    /// Not forwarded at all
+    #[default]
+    #[serde(rename = "drop")]
+    Drop,
+    /// Held back, until the window has room for them
+    #[serde(rename = "delay")]
+    Delay,
+}
+
+/// Handling of automatically sent mails, see `SourceCommonConfig::auto_submitted`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum AutoSubmittedHandling {
+    /// Routed by the mappings, like any other mail
+    #[default]
+    #[serde(rename = "forward")]
+    Forward,
+    /// Not delivered anywhere
+    #[serde(rename = "skip")]
+    Skip,
+    /// Delivered to these destinations instead of the mapped ones
+    #[serde(rename = "destinations")]
+    Destinations(Vec<String>),
+}
+
+/// Daily period during which a source's mails are held, e.g. from 22:00 to 07:00.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// IANA name of the timezone the times are in, e.g. `Europe/Berlin`
+    pub timezone: Timezone,
+}
+impl QuietHoursConfig {
+    /// How long the current quiet hours last, or `None` if it is not quiet hours.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_at(OffsetDateTime::now_utc())
+    }
+
+    fn remaining_at(&self, now: OffsetDateTime) -> Option<Duration> {
+        let local = now.to_timezone(self.timezone.tz());
+        let (start, end, time) = (self.start.0, self.end.0, local.time());
+        let quiet = if start <= end {
+            start <= time && time < end
+        } else {
+            // across midnight
+            start <= time || time < end
+        };
+        if !quiet {
+            return None;
+        }
+        let end_date = if time < end {
+            local.date()
+        } else {
+            local.date().next_day()?
+        };
+        let end_local = PrimitiveDateTime::new(end_date, end);
+        let end = match end_local.assume_timezone(self.timezone.tz()) {
+            OffsetResult::Some(end) => end,
+            // the end is on a clock turned back (DST ends), it is reached the first time
+            OffsetResult::Ambiguous(first, _) => first,
+            // the end is skipped by a clock turned forward (DST starts). Quiet hours end with
+            // the switch, which is where the end lands with the offset from before it.
+            OffsetResult::None => end_local.assume_offset(local.offset()),
+        };
+        Some((end - now).try_into().unwrap_or_default())
+    }
+}
+
+/// Wall clock time, written as `HH:MM`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(Time);
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split_once(':')
+            .filter(|(hour, minute)| hour.len() <= 2 && minute.len() == 2)
+            .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+            .and_then(|(hour, minute)| Time::from_hms(hour, minute, 0).ok())
+            .map(TimeOfDay)
+            .ok_or_else(|| format!("Invalid time of day: {}, expected HH:MM", value))
+    }
+}
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", time.0.hour(), time.0.minute())
+    }
+}
+
+/// Timezone of the IANA database, by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timezone(String);
+impl Timezone {
+    fn tz(&self) -> &'static Tz {
+        timezones::get_by_name(&self.0).expect("Timezone is checked when parsing")
+    }
+}
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match timezones::get_by_name(&name) {
+            Some(_) => Ok(Timezone(name)),
+            None => Err(format!("Unknown timezone: {}", name)),
+        }
+    }
+}
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        timezone.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceEntry {
+    // see DestinationEntry
+    #[serde(flatten)]
+    pub common: SourceCommonConfig,
+    #[serde(flatten)]
+    pub config: SourceConfig,
+}
+
 // #############
 // # Destinations
 // #############
@@ -127,6 +1814,82 @@ pub struct SmtpDestinationConfig {
     pub encryption: Encryption,
     pub auth: Option<AuthMethod>,
     pub recipient: String,
+    /// Re-encode 8bit mail to 7bit, if the server does not support 8BITMIME
+    #[serde(default)]
+    pub force_7bit: bool,
+    #[serde(default)]
+    pub forward_mode: ForwardMode,
+    /// Settings for `forward_mode` summary
+    pub summary: Option<SummaryConfig>,
+    /// DKIM-sign mails before sending them
+    pub dkim: Option<DkimConfig>,
+    /// Lowest TLS version accepted for the connection to the server
+    pub min_tls_version: Option<TlsVersion>,
+    /// Look up the recipients by the addresses the mail was sent to, instead of `recipient`
+    pub recipient_lookup: Option<RecipientLookupConfig>,
+}
+
+/// Alias table mapping the addresses a mail was sent to onto the addresses it is delivered to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum RecipientLookupConfig {
+    /// File with lines `<address>: <recipient>, <recipient>, ...`, read for every mail
+    #[serde(rename = "file")]
+    File { path: String },
+    #[serde(rename = "ldap")]
+    Ldap(Box<LdapLookupConfig>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LdapLookupConfig {
+    /// e.g. `ldaps://ldap.example.org`
+    pub url: String,
+    /// Bound anonymously, if not set
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Entry below which the aliases are searched
+    pub base: String,
+    /// `{}` is replaced with the address, `(mail={})` if not set
+    pub filter: Option<String>,
+    /// Attribute of the found entries holding the recipients, e.g. `mailForwardingAddress`
+    pub attribute: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DkimConfig {
+    pub domain: String,
+    pub selector: String,
+    /// Path of the PEM-encoded RSA private key
+    pub private_key: String,
+}
+
+/// How the SMTP destination delivers a mail.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardMode {
+    /// Send the original message as-is
+    #[default]
+    #[serde(rename = "inline")]
+    Inline,
+    /// Wrap the original message as message/rfc822 attachment into a new message
+    #[serde(rename = "attachment")]
+    Attachment,
+    /// Send only a short plain text summary of the message, see `SummaryConfig`
+    #[serde(rename = "summary")]
+    Summary,
+}
+
+/// Shape of the summary sent with `ForwardMode::Summary`, e.g. to SMS gateways.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SummaryConfig {
+    /// Maximum number of characters of the whole summary
+    pub max_chars: usize,
+    /// Start the summary with the sender
+    #[serde(default)]
+    pub include_from: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -141,6 +1904,252 @@ pub struct ExecDestinationConfig {
     pub executable: String,
     pub arguments: Option<Vec<String>>,
     pub environment: Option<HashMap<String, String>>,
+    /// Maximum number of children running at the same time, 1 if not set
+    pub max_concurrent: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SendmailDestinationConfig {
+    /// Path of the sendmail binary, `/usr/sbin/sendmail` if not set
+    pub path: Option<String>,
+    /// Envelope sender (`-f`)
+    pub sender: Option<String>,
+    /// Deliver to these addresses, instead of the recipients in the mail's headers (`-t`)
+    pub recipients: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordDestinationConfig {
+    pub webhook_url: String,
+    /// Overrides the webhook's default username
+    pub username: Option<String>,
+    /// Overrides the webhook's default avatar
+    pub avatar_url: Option<String>,
+    /// Charset of text parts that declare no charset, or an unknown one
+    pub fallback_charset: Option<String>,
+}
+
+#[cfg(unix)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FifoDestinationConfig {
+    /// Path of an existing named pipe
+    pub path: String,
+    /// Seconds to wait for a reader to open the pipe, before the mail is queued for retry
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveDestinationConfig {
+    /// Existing directory, below which the dated directories are created
+    pub path: String,
+    /// Directory levels of the date, `day` if not set
+    pub partition: Option<ArchivePartition>,
+    /// Timezone of the date the mails are archived at, UTC if not set
+    pub timezone: Option<Timezone>,
+}
+impl ArchiveDestinationConfig {
+    /// Date in the configured timezone at the instant `now`
+    pub fn date_at(&self, now: OffsetDateTime) -> Date {
+        match &self.timezone {
+            Some(timezone) => now.to_timezone(timezone.tz()).date(),
+            None => now.date(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct XmppDestinationConfig {
+    /// Account idlemail logs in with, e.g. `idlemail@example.org`
+    pub jid: String,
+    pub password: String,
+    /// JID the summaries of the mails are sent to
+    pub recipient: String,
+    /// Host to connect to, the domain of `jid` if not set
+    pub server: Option<String>,
+    /// Port to connect to, 5222 if not set
+    pub port: Option<u16>,
+}
+impl XmppDestinationConfig {
+    /// The local part (the username) and the domain of `jid`
+    pub fn account(&self) -> (&str, &str) {
+        let bare = self.jid.split('/').next().unwrap_or_default();
+        bare.split_once('@').unwrap_or(("", bare))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchivePartition {
+    /// `YYYY`
+    #[serde(rename = "year")]
+    Year,
+    /// `YYYY/MM`
+    #[serde(rename = "month")]
+    Month,
+    /// `YYYY/MM/DD`
+    #[default]
+    #[serde(rename = "day")]
+    Day,
+}
+
+/// Options that apply to every destination, independent of its type.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DestinationCommonConfig {
+    /// If set, only mail from these sources is delivered to the destination.
+    pub allowed_sources: Option<Vec<String>>,
+    /// Mail from these sources is never delivered to the destination.
+    pub denied_sources: Option<Vec<String>>,
+    /// Stop attempting deliveries for a while, after the destination failed repeatedly
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Remove headers requesting read receipts before delivery
+    #[serde(default)]
+    pub strip_receipts: bool,
+    /// Outside of these hours, mails go to an overflow destination instead
+    pub active_hours: Option<ActiveHoursConfig>,
+    /// Text appended to the text parts of every mail, `{source}` and `{folder}` are replaced
+    pub footer: Option<String>,
+    /// Name of the entry in `retryagents` queueing failed mails, instead of `retryagent`
+    pub retryagent: Option<String>,
+    /// Reply-To set on every mail, `{from}` is replaced with the mail's From header
+    pub reply_to: Option<String>,
+    /// Whether `reply_to` replaces a Reply-To the mail has already
+    pub reply_to_existing: Option<ReplyToExisting>,
+    /// Seconds to collect the mails of a source, which are then delivered as one mail
+    pub debounce: Option<u64>,
+    /// What happens to mails with malformed MIME, if the destination transforms its mails
+    pub on_parse_error: Option<ParseErrorPolicy>,
+}
+
+/// Handling of mails with malformed MIME, see `DestinationCommonConfig::on_parse_error`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum ParseErrorPolicy {
+    /// Transformed anyway, as far as parsing could work around the problems
+    #[default]
+    #[serde(rename = "transform")]
+    Transform,
+    /// Delivered to the destination unchanged
+    #[serde(rename = "pass")]
+    Pass,
+    /// Not delivered to the destination
+    #[serde(rename = "drop")]
+    Drop,
+    /// Delivered unchanged to this destination instead
+    #[serde(rename = "destination")]
+    Destination(String),
+}
+
+/// What happens to the Reply-To header of a mail, if the destination sets `reply_to`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyToExisting {
+    /// Keep it, replies go where the sender asked for
+    #[default]
+    #[serde(rename = "preserve")]
+    Preserve,
+    #[serde(rename = "overwrite")]
+    Overwrite,
+}
+
+/// Daily period in which a destination receives mails, e.g. business hours.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ActiveHoursConfig {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// IANA name of the timezone the times are in, e.g. `Europe/Berlin`
+    pub timezone: Timezone,
+    /// Days on which the period starts. Every day, if not set.
+    pub days: Option<Vec<Day>>,
+    /// Destination that receives the mails outside of the active hours
+    pub overflow: String,
+}
+impl ActiveHoursConfig {
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(OffsetDateTime::now_utc())
+    }
+
+    fn is_active_at(&self, now: OffsetDateTime) -> bool {
+        let local = now.to_timezone(self.timezone.tz());
+        let (start, end, time) = (self.start.0, self.end.0, local.time());
+        let on_day = |date: Option<Date>| match (&self.days, date) {
+            (None, _) => true,
+            (Some(days), Some(date)) => days.contains(&Day::from(date.weekday())),
+            (Some(_), None) => false,
+        };
+        if start <= end {
+            start <= time && time < end && on_day(Some(local.date()))
+        } else {
+            // across midnight, the hours after midnight belong to the day before
+            (start <= time && on_day(Some(local.date())))
+                || (time < end && on_day(local.date().previous_day()))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Day {
+    #[serde(rename = "mon")]
+    Monday,
+    #[serde(rename = "tue")]
+    Tuesday,
+    #[serde(rename = "wed")]
+    Wednesday,
+    #[serde(rename = "thu")]
+    Thursday,
+    #[serde(rename = "fri")]
+    Friday,
+    #[serde(rename = "sat")]
+    Saturday,
+    #[serde(rename = "sun")]
+    Sunday,
+}
+impl From<Weekday> for Day {
+    fn from(weekday: Weekday) -> Self {
+        match weekday {
+            Weekday::Monday => Day::Monday,
+            Weekday::Tuesday => Day::Tuesday,
+            Weekday::Wednesday => Day::Wednesday,
+            Weekday::Thursday => Day::Thursday,
+            Weekday::Friday => Day::Friday,
+            Weekday::Saturday => Day::Saturday,
+            Weekday::Sunday => Day::Sunday,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed deliveries, after which the circuit opens
+    pub failure_threshold: u32,
+    /// Seconds without delivery attempts, before a single mail is sent to probe the destination
+    pub cooldown: u64,
+}
+impl DestinationCommonConfig {
+    pub fn accepts_source(&self, srcname: &str) -> bool {
+        let allowed = match &self.allowed_sources {
+            Some(allowed_sources) => allowed_sources.iter().any(|s| s == srcname),
+            None => true,
+        };
+        let denied = match &self.denied_sources {
+            Some(denied_sources) => denied_sources.iter().any(|s| s == srcname),
+            None => false,
+        };
+        allowed && !denied
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DestinationEntry {
+    // common options have to be flattened first, so they are consumed before
+    // the type-specific config rejects them as unknown fields.
+    #[serde(flatten)]
+    pub common: DestinationCommonConfig,
+    #[serde(flatten)]
+    pub config: DestinationConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -153,6 +2162,17 @@ pub enum DestinationConfig {
     Smtp(SmtpDestinationConfig),
     #[serde(rename = "exec")]
     Exec(ExecDestinationConfig),
+    #[serde(rename = "discord")]
+    Discord(DiscordDestinationConfig),
+    #[serde(rename = "sendmail")]
+    Sendmail(SendmailDestinationConfig),
+    #[cfg(unix)]
+    #[serde(rename = "fifo")]
+    Fifo(FifoDestinationConfig),
+    #[serde(rename = "archive")]
+    Archive(ArchiveDestinationConfig),
+    #[serde(rename = "xmpp")]
+    Xmpp(XmppDestinationConfig),
 }
 
 // #############
@@ -163,13 +2183,56 @@ pub enum DestinationConfig {
 #[serde(deny_unknown_fields)]
 pub struct MemoryRetryAgentConfig {
     pub delay: u64,
+    /// Up to this many seconds are randomly added to `delay`, to spread out retries
+    pub jitter: Option<u64>,
+    /// Maximum number of mails kept in RAM. Further mails are spilled to `spill_path`.
+    pub max_queue_len: Option<usize>,
+    pub spill_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct FilesystemRetryAgentConfig {
     pub delay: u64,
+    /// Up to this many seconds are randomly added to `delay`, to spread out retries
+    pub jitter: Option<u64>,
     pub path: String,
+    /// Encrypt the mails stored in `path` with this key
+    #[serde(default)]
+    pub encryption_key: Option<KeySource>,
+}
+
+/// Where to read a base64-encoded 256bit key from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub enum KeySource {
+    #[serde(rename = "file")]
+    File(String),
+    #[serde(rename = "env")]
+    Env(String),
+}
+impl KeySource {
+    pub const KEY_LEN: usize = 32;
+
+    pub fn load(&self) -> Result<Vec<u8>, String> {
+        let encoded = match self {
+            KeySource::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read key file: {}\n{}", path, e))?,
+            KeySource::Env(var) => std::env::var(var)
+                .map_err(|_| format!("Environment variable for key is not set: {}", var))?,
+        };
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| format!("Key is not valid base64: {}", e))?;
+        if key.len() != Self::KEY_LEN {
+            return Err(format!(
+                "Key has to be {} bytes long, but is {}",
+                Self::KEY_LEN,
+                key.len()
+            ));
+        }
+        Ok(key)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -181,3 +2244,325 @@ pub enum RetryAgentConfig {
     #[serde(rename = "filesystem")]
     Filesystem(FilesystemRetryAgentConfig),
 }
+impl RetryAgentConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            RetryAgentConfig::Filesystem(config) => {
+                if !Path::new(&config.path).exists() {
+                    return Err("FilesystemRetryAgent: Path does not exist".to_string());
+                }
+                if let Some(key_source) = &config.encryption_key {
+                    key_source
+                        .load()
+                        .map_err(|e| format!("FilesystemRetryAgent: {}", e))?;
+                }
+            }
+            RetryAgentConfig::Memory(config) => match (&config.max_queue_len, &config.spill_path) {
+                (Some(_), None) => {
+                    return Err("MemoryRetryAgent: max_queue_len requires a spill_path".to_string())
+                }
+                (None, Some(_)) => {
+                    return Err("MemoryRetryAgent: spill_path requires a max_queue_len".to_string())
+                }
+                (Some(0), _) => {
+                    return Err("MemoryRetryAgent: max_queue_len has to be at least 1".to_string())
+                }
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_template_parses() {
+        // not validated, since that checks paths on this system (e.g. the fifo)
+        if let Err(err) = serde_json::from_str::<ConfigContainer>(TEMPLATE) {
+            panic!("Template does not match the configuration types: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_include_mappings() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("conf")).unwrap();
+        std::fs::write(dir.path().join("conf/mappings.json"), r#"{"src": ["dst"]}"#).unwrap();
+        let config_path = dir.path().join("conf/config.json");
+        let mut config = serde_json::json!({ "mappings": "@include: mappings.json" });
+        ConfigContainer::include_mappings(&mut config, &config_path).unwrap();
+        assert_eq!(config["mappings"], serde_json::json!({"src": ["dst"]}));
+
+        let mut config = serde_json::json!({ "mappings": "@include:missing.json" });
+        assert!(ConfigContainer::include_mappings(&mut config, &config_path).is_err());
+    }
+
+    #[test]
+    fn test_port_warnings() {
+        let smtp = |port: u16, encryption: Value| {
+            serde_json::json!({
+                "type": "smtp", "server": "smtp.example.org", "port": port,
+                "encryption": encryption, "recipient": "me@example.org"
+            })
+        };
+        let mut config = serde_json::json!({
+            "sources": {
+                "plain": {
+                    "type": "imap_poll", "server": "imap.example.org", "port": 143, "interval": 60,
+                    "keep": true, "auth": { "type": "login", "user": "me", "password": "secret" }
+                },
+                "starttls": {
+                    "type": "imap_poll", "server": "imap.example.org", "port": 993, "interval": 60,
+                    "encryption": "starttls", "keep": true,
+                    "auth": { "type": "login", "user": "me", "password": "secret" }
+                }
+            },
+            "destinations": {
+                "implicit": smtp(587, serde_json::json!({ "type": "ssl" })),
+                "starttls": smtp(465, serde_json::json!({ "type": "starttls" })),
+                "submission": smtp(587, serde_json::json!({ "type": "starttls" })),
+                "smtps": smtp(465, serde_json::json!({ "type": "ssl" }))
+            },
+            "mappings": {}
+        });
+        let warnings = |config: &Value| {
+            serde_json::from_value::<ConfigContainer>(config.clone())
+                .unwrap()
+                .port_warnings()
+        };
+        let found = warnings(&config);
+        assert_eq!(found.len(), 4);
+        assert!(found[0].starts_with("Source: plain connects with TLS to port 143"));
+        assert!(found[1].starts_with("Source: starttls connects without TLS to port 993"));
+        assert!(found[2].starts_with("Destination: implicit uses ssl"));
+        assert!(found[3].starts_with("Destination: starttls connects to port 465"));
+
+        config["port_warnings"] = Value::Bool(false);
+        assert!(warnings(&config).is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_destinations() {
+        let mut config = serde_json::json!({
+            "sources": { "account": { "type": "test", "delay": 0, "interval": 60 } },
+            "destinations": {
+                "a": { "type": "test", "fail_n_first": 0 },
+                "b": { "type": "test", "fail_n_first": 0 }
+            },
+            "mappings": {
+                "account": [
+                    "a", "b", "a", "a",
+                    { "folder": "INBOX", "destinations": ["b", "b"] },
+                    { "folder": "Lists/*", "destinations": ["a"] }
+                ]
+            }
+        });
+        let parsed = |config: &Value| serde_json::from_value::<ConfigContainer>(config.clone());
+        let duplicates = parsed(&config).unwrap().duplicate_destinations();
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates[0].starts_with("Mapping of source: account lists destination: a "));
+        assert!(duplicates[1].starts_with("Mapping of source: account lists destination: b "));
+        assert!(parsed(&config).unwrap().validate().is_ok());
+
+        config["duplicate_destinations"] = Value::from("error");
+        assert!(parsed(&config).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_socket_path() {
+        let mut config = serde_json::json!({
+            "sources": {
+                "local": {
+                    "type": "imap_idle", "socket_path": "/run/dovecot/imap.sock", "path": "INBOX",
+                    "renewinterval": 600, "keep": true,
+                    "auth": { "type": "login", "user": "me", "password": "secret" }
+                }
+            },
+            "destinations": { "t": { "type": "test", "fail_n_first": 0 } },
+            "mappings": { "local": ["t"] }
+        });
+        let validate = |config: &Value| {
+            serde_json::from_value::<ConfigContainer>(config.clone())
+                .unwrap()
+                .validate()
+        };
+        assert!(validate(&config).is_ok());
+
+        // the server and port of the defaults are for the other sources
+        let mut with_defaults = config.clone();
+        with_defaults["defaults"] =
+            serde_json::json!({ "imap": { "server": "imap.example.org", "port": 993 } });
+        ConfigContainer::apply_defaults(&mut with_defaults).unwrap();
+        assert!(validate(&with_defaults).is_ok());
+
+        config["sources"]["local"]["tls_hostname"] = Value::from("imap.example.org");
+        assert!(validate(&config).is_err());
+
+        config["sources"]["local"]["server"] = Value::from("imap.example.org");
+        config["sources"]["local"]["port"] = Value::from(993);
+        assert!(validate(&config).is_err());
+
+        config["sources"]["local"]["socket_path"] = Value::Null;
+        assert!(validate(&config).is_ok());
+
+        config["sources"]["local"]
+            .as_object_mut()
+            .unwrap()
+            .remove("port");
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn test_connection_key() {
+        let source = serde_json::json!({
+            "type": "imap_poll", "server": "imap.example.org", "port": 993, "interval": 60,
+            "keep": true, "auth": { "type": "login", "user": "me", "password": "secret" }
+        });
+        let key = |source: &Value| match serde_json::from_value(source.clone()).unwrap() {
+            SourceConfig::ImapPoll(config) => config.connection_key(),
+            _ => unreachable!(),
+        };
+        // the options of a source do not prevent sharing its connection
+        let mut other = source.clone();
+        other["search"] = Value::from("TEXT \"invoice\"");
+        other["newest_first"] = Value::from(true);
+        other["grace_minutes"] = Value::from(5);
+        assert_eq!(key(&source), key(&other));
+
+        other["port"] = Value::from(143);
+        other["encryption"] = Value::from("starttls");
+        assert_ne!(key(&source), key(&other));
+    }
+
+    #[test]
+    fn test_xoauth2_token() {
+        let mut config = serde_json::json!({
+            "sources": {
+                "gmail": {
+                    "type": "imap_poll", "server": "imap.gmail.com", "port": 993, "interval": 60,
+                    "keep": true,
+                    "auth": { "type": "xoauth2", "user": "me@gmail.com", "access_token": "ya29.x" }
+                }
+            },
+            "destinations": { "t": { "type": "test", "fail_n_first": 0 } },
+            "mappings": { "gmail": ["t"] }
+        });
+        let validate = |config: &Value| {
+            serde_json::from_value::<ConfigContainer>(config.clone())
+                .unwrap()
+                .validate()
+        };
+        assert!(validate(&config).is_ok());
+
+        config["sources"]["gmail"]["auth"]["access_token_env"] = Value::from("PATH");
+        assert!(validate(&config).unwrap_err().contains("use either"));
+
+        config["sources"]["gmail"]["auth"]["access_token"] = Value::Null;
+        assert!(validate(&config).is_ok());
+
+        config["sources"]["gmail"]["auth"]["access_token_env"] = Value::Null;
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn test_ca_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_cert = dir.path().join("ca.pem");
+        let mut config = serde_json::json!({
+            "sources": {
+                "internal": {
+                    "type": "imap_poll", "server": "mail.internal", "port": 993, "interval": 60,
+                    "keep": true, "ca_cert": ca_cert,
+                    "auth": { "type": "login", "user": "me", "password": "secret" }
+                }
+            },
+            "destinations": { "t": { "type": "test", "fail_n_first": 0 } },
+            "mappings": { "internal": ["t"] }
+        });
+        let validate = |config: &Value| {
+            serde_json::from_value::<ConfigContainer>(config.clone())
+                .unwrap()
+                .validate()
+        };
+        assert!(validate(&config).unwrap_err().contains("does not exist"));
+
+        std::fs::write(&ca_cert, "-----BEGIN CERTIFICATE-----").unwrap();
+        assert!(validate(&config).is_ok());
+
+        config["sources"]["internal"]["encryption"] = Value::from("none");
+        assert!(validate(&config).is_err());
+    }
+
+    fn quiet_hours(start: &str, end: &str) -> QuietHoursConfig {
+        serde_json::from_value(serde_json::json!({
+            "start": start, "end": end, "timezone": "Europe/Berlin"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let night = quiet_hours("22:00", "07:00");
+        let minutes = |now| night.remaining_at(now).map(|d| d.as_secs() / 60);
+        assert_eq!(minutes(datetime!(2024-01-10 05:00 UTC)), Some(60));
+        assert_eq!(minutes(datetime!(2024-01-10 21:30 UTC)), Some(8 * 60 + 30));
+        assert_eq!(minutes(datetime!(2024-01-10 06:00 UTC)), None);
+        assert_eq!(minutes(datetime!(2024-01-10 12:00 UTC)), None);
+
+        let lunch = quiet_hours("12:00", "13:00");
+        assert_eq!(
+            lunch
+                .remaining_at(datetime!(2024-01-10 11:15 UTC))
+                .map(|d| d.as_secs()),
+            Some(45 * 60)
+        );
+        assert_eq!(lunch.remaining_at(datetime!(2024-01-10 12:00 UTC)), None);
+    }
+
+    #[test]
+    fn test_quiet_hours_dst() {
+        let night = quiet_hours("22:00", "02:30");
+        // 02:30 is skipped when DST starts, the quiet hours end with the switch to 03:00
+        let remaining = night.remaining_at(datetime!(2024-03-30 22:00 UTC));
+        assert_eq!(remaining, Some(Duration::from_secs(3 * 3600 + 1800)));
+        // 02:30 happens twice when DST ends, the quiet hours end the first time
+        let remaining = night.remaining_at(datetime!(2024-10-26 20:00 UTC));
+        assert_eq!(remaining, Some(Duration::from_secs(4 * 3600 + 1800)));
+    }
+
+    #[test]
+    fn test_active_hours() {
+        let business: ActiveHoursConfig = serde_json::from_value(serde_json::json!({
+            "start": "09:00", "end": "17:00", "timezone": "Europe/Berlin",
+            "days": ["mon", "tue", "wed", "thu", "fri"], "overflow": "oncall"
+        }))
+        .unwrap();
+        // Wednesday
+        assert!(business.is_active_at(datetime!(2024-01-10 08:00 UTC)));
+        assert!(!business.is_active_at(datetime!(2024-01-10 07:59 UTC)));
+        assert!(!business.is_active_at(datetime!(2024-01-10 16:00 UTC)));
+        // Saturday
+        assert!(!business.is_active_at(datetime!(2024-01-13 10:00 UTC)));
+
+        let night: ActiveHoursConfig = serde_json::from_value(serde_json::json!({
+            "start": "22:00", "end": "06:00", "timezone": "UTC", "days": ["fri"], "overflow": "x"
+        }))
+        .unwrap();
+        // Friday night, continuing into Saturday morning
+        assert!(night.is_active_at(datetime!(2024-01-12 23:00 UTC)));
+        assert!(night.is_active_at(datetime!(2024-01-13 05:00 UTC)));
+        assert!(!night.is_active_at(datetime!(2024-01-12 05:00 UTC)));
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        assert!(TimeOfDay::try_from("7:05".to_owned()).is_ok());
+        assert!(TimeOfDay::try_from("24:00".to_owned()).is_err());
+        assert!(TimeOfDay::try_from("12:5".to_owned()).is_err());
+        assert!(Timezone::try_from("Mars/Olympus_Mons".to_owned()).is_err());
+    }
+}
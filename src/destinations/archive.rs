@@ -0,0 +1,182 @@
+use crate::{
+    config::{ArchiveDestinationConfig, ArchivePartition},
+    hub::{DestinationMessage, HubDestinationChannel, Mail, MailAgent},
+};
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, error, info, trace};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    thread,
+};
+use time::{Date, OffsetDateTime};
+
+use super::MailDestination;
+
+/// Maximum length of the file name taken from the Message-ID, before the extension
+const FILE_NAME_MAX_LEN: usize = 128;
+/// Number of suffixes (`-1`, `-2`, …) tried, if the file name is taken already
+const MAX_COLLISIONS: usize = 1000;
+
+pub struct ArchiveDestination {
+    log_target: String,
+    config: ArchiveDestinationConfig,
+    worker: Option<thread::JoinHandle<()>>,
+}
+impl ArchiveDestination {
+    pub fn new(name: String, config: &ArchiveDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Archive[{}]", name),
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    /// Directory of the mails archived on `date`, below `base`.
+    fn directory(base: &Path, partition: ArchivePartition, date: Date) -> PathBuf {
+        let mut directory = base.join(format!("{:04}", date.year()));
+        if partition != ArchivePartition::Year {
+            directory.push(format!("{:02}", u8::from(date.month())));
+        }
+        if partition == ArchivePartition::Day {
+            directory.push(format!("{:02}", date.day()));
+        }
+        directory
+    }
+
+    /// File name (without extension) for the mail, from its Message-ID. Characters that
+    /// are not safe in file names are replaced. Mails without Message-ID use their hash.
+    fn file_name(mail: &Mail) -> String {
+        let message_id = mail
+            .message_id
+            .as_deref()
+            .map(|message_id| message_id.trim_start_matches('<').trim_end_matches('>'))
+            .unwrap_or_default();
+        let name: String = message_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "@._+-".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(FILE_NAME_MAX_LEN)
+            .collect();
+        if name.is_empty() || name.starts_with('.') {
+            mail.hash.clone()
+        } else {
+            name
+        }
+    }
+
+    /// Create a new file for the mail in `directory`. If a file with the mail's name exists
+    /// already (e.g. the same mail arrived twice), a numbered suffix is added.
+    fn create(directory: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+        for collision in 0..MAX_COLLISIONS {
+            let path = match collision {
+                0 => directory.join(format!("{}.eml.gz", name)),
+                n => directory.join(format!("{}-{}.eml.gz", name, n)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} files named {} exist already", MAX_COLLISIONS, name),
+        ))
+    }
+
+    /// Write the gzip-compressed mail into the directory of `date`, returning its path.
+    fn archive(config: &ArchiveDestinationConfig, mail: &Mail, date: Date) -> io::Result<PathBuf> {
+        let directory = Self::directory(
+            Path::new(&config.path),
+            config.partition.unwrap_or_default(),
+            date,
+        );
+        fs::create_dir_all(&directory)?;
+        let (path, file) = Self::create(&directory, &Self::file_name(mail))?;
+        let result = (|| {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            io::copy(&mut mail.reader(), &mut encoder)?;
+            encoder.finish()?.sync_all()
+        })();
+        if let Err(e) = result {
+            // a truncated archive is worse than none, the mail is retried
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        Ok(path)
+    }
+}
+impl MailAgent for ArchiveDestination {
+    fn join(&mut self) {
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("Thread exited with errors");
+    }
+}
+impl MailDestination for ArchiveDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        self.worker = Some(thread::spawn(move || {
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                let date = config.date_at(OffsetDateTime::now_utc());
+                match ArchiveDestination::archive(&config, &mail, date) {
+                    Ok(path) => {
+                        debug!(target: &log_target, "Wrote {}", path.display());
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        channel.notify_sent(mail, format!("Archived as {}", path.display()));
+                    }
+                    Err(err) => {
+                        error!(target: &log_target, "Error while archiving mail {}: {}", mail, err);
+                        channel.notify_failed_send(mail);
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use io::Read;
+    use time::macros::date;
+
+    #[test]
+    fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ArchiveDestinationConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            partition: None,
+            timezone: None,
+        };
+        let data = b"Message-ID: <a/b@example.org>\r\nSubject: test\r\n\r\nbody\r\n";
+        let mail = Mail::from_rfc822("src".to_owned(), &data[..]);
+
+        let first = ArchiveDestination::archive(&config, &mail, date!(2024 - 03 - 07)).unwrap();
+        let second = ArchiveDestination::archive(&config, &mail, date!(2024 - 03 - 07)).unwrap();
+        let day = dir.path().join("2024/03/07");
+        assert_eq!(first, day.join("a_b@example.org.eml.gz"));
+        assert_eq!(second, day.join("a_b@example.org-1.eml.gz"));
+
+        let mut archived = Vec::new();
+        GzDecoder::new(File::open(first).unwrap())
+            .read_to_end(&mut archived)
+            .unwrap();
+        assert_eq!(archived, data);
+    }
+}
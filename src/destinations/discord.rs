@@ -0,0 +1,235 @@
+use crate::{
+    config::DiscordDestinationConfig,
+    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    mime,
+};
+use log::{error, info, trace, warn};
+use serde_json::{json, Value};
+use std::{sync::Arc, thread, time::Duration};
+
+use super::{truncate, MailDestination};
+
+// Discord's limits for embed fields. The description is cut shorter than allowed (4096),
+// to stay below the limit of 6000 characters for the whole embed.
+const TITLE_MAX_LEN: usize = 256;
+const AUTHOR_MAX_LEN: usize = 256;
+const DESCRIPTION_MAX_LEN: usize = 2048;
+const FOOTER_MAX_LEN: usize = 256;
+/// Rate limits with a longer Retry-After are handed to the RetryAgent instead of waiting.
+const RATE_LIMIT_MAX_WAIT: Duration = Duration::from_secs(60);
+
+enum SendError {
+    /// The webhook rejected the message. Retrying will not help.
+    Permanent(String),
+    Temporary(String),
+}
+
+pub struct DiscordDestination {
+    log_target: String,
+    config: DiscordDestinationConfig,
+    worker: Option<thread::JoinHandle<()>>,
+}
+impl DiscordDestination {
+    pub fn new(name: String, config: &DiscordDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Discord[{}]", name),
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    /// Build the webhook payload, with one embed summarizing the mail.
+    fn build_payload(config: &DiscordDestinationConfig, data: &[u8]) -> Value {
+        let message = mime::Entity::parse(data);
+        let header = |name| {
+            message
+                .header(name)
+                .map(|value| mime::decode_header_words(&value))
+                .unwrap_or_default()
+        };
+        // Discord rejects empty fields, so these are left out
+        let mut embed = json!({});
+        let subject = header("Subject");
+        if !subject.is_empty() {
+            embed["title"] = json!(truncate(&subject, TITLE_MAX_LEN));
+        }
+        let text = message
+            .text_content_with_fallback(config.fallback_charset.as_deref())
+            .unwrap_or_default();
+        let text = match text.trim() {
+            "" => "(no text content)",
+            text => text,
+        };
+        embed["description"] = json!(truncate(text, DESCRIPTION_MAX_LEN));
+        let from = header("From");
+        if !from.is_empty() {
+            embed["author"] = json!({ "name": truncate(&from, AUTHOR_MAX_LEN) });
+        }
+        let date = header("Date");
+        if !date.is_empty() {
+            embed["footer"] = json!({ "text": truncate(&date, FOOTER_MAX_LEN) });
+        }
+
+        let mut payload = json!({ "embeds": [embed] });
+        if let Some(username) = &config.username {
+            payload["username"] = json!(username);
+        }
+        if let Some(avatar_url) = &config.avatar_url {
+            payload["avatar_url"] = json!(avatar_url);
+        }
+        payload
+    }
+
+    fn send(
+        agent: &ureq::Agent,
+        config: &DiscordDestinationConfig,
+        payload: &Value,
+        log_target: &str,
+    ) -> Result<String, SendError> {
+        loop {
+            match agent.post(&config.webhook_url).send_json(payload) {
+                Ok(response) => {
+                    return Ok(format!("{} {}", response.status(), response.status_text()))
+                }
+                Err(ureq::Error::Status(429, response)) => {
+                    // Discord sends the delay both as header and in the json body
+                    let retry_after = match response.header("Retry-After") {
+                        Some(retry_after) => retry_after.parse::<f64>().ok(),
+                        None => response
+                            .into_json::<Value>()
+                            .ok()
+                            .and_then(|body| body["retry_after"].as_f64()),
+                    };
+                    let retry_after = retry_after
+                        .map(Duration::from_secs_f64)
+                        .unwrap_or(RATE_LIMIT_MAX_WAIT);
+                    if retry_after > RATE_LIMIT_MAX_WAIT {
+                        return Err(SendError::Temporary(format!(
+                            "Rate limited for {}s",
+                            retry_after.as_secs()
+                        )));
+                    }
+                    info!(
+                        target: log_target,
+                        "Rate limited, retrying in {:.1}s",
+                        retry_after.as_secs_f64()
+                    );
+                    thread::sleep(retry_after);
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let msg = format!(
+                        "Webhook responded with {}: {}",
+                        status,
+                        response.into_string().unwrap_or_default()
+                    );
+                    return Err(if (400..500).contains(&status) {
+                        SendError::Permanent(msg)
+                    } else {
+                        SendError::Temporary(msg)
+                    });
+                }
+                Err(err) => return Err(SendError::Temporary(err.to_string())),
+            }
+        }
+    }
+}
+impl MailAgent for DiscordDestination {
+    fn join(&mut self) {
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("Thread exited with errors");
+    }
+}
+impl MailDestination for DiscordDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        self.worker = Some(thread::spawn(move || {
+            let tls_connector = match native_tls::TlsConnector::new() {
+                Ok(tls_connector) => tls_connector,
+                Err(err) => {
+                    error!(target: &log_target, "Failed to initialize TLS: {}", err);
+                    return;
+                }
+            };
+            let agent = ureq::AgentBuilder::new()
+                .tls_connector(Arc::new(tls_connector))
+                .timeout(Duration::from_secs(60))
+                .build();
+
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                let payload = DiscordDestination::build_payload(&config, &mail.data);
+                match DiscordDestination::send(&agent, &config, &payload, &log_target) {
+                    Ok(response) => {
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        channel.notify_sent(mail, response);
+                    }
+                    Err(SendError::Permanent(err)) => {
+                        warn!(target: &log_target, "Discord does not accept mail {}, will not try again:\n{}", mail, err);
+                        channel.notify_rejected(mail, err);
+                    }
+                    Err(SendError::Temporary(err)) => {
+                        error!(target: &log_target, "Error while sending mail {}:\n{}", mail, err);
+                        channel.notify_failed_send(mail);
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        // multibyte characters are counted once, and never cut in half
+        assert_eq!(truncate("Grüße", 5), "Grüße");
+        assert_eq!(truncate("Grüße!", 5), "Grüß…");
+        assert_eq!(truncate("日本語のテキスト", 4), "日本語…");
+        assert_eq!(truncate("👍👍👍", 3), "👍👍👍");
+        assert_eq!(truncate("👍👍👍👍", 3), "👍👍…");
+    }
+
+    #[test]
+    fn test_build_payload() {
+        let config = DiscordDestinationConfig {
+            webhook_url: "https://discord.example.org/api/webhooks/1/token".to_owned(),
+            username: Some("idlemail".to_owned()),
+            avatar_url: None,
+            fallback_charset: None,
+        };
+        let data = format!(
+            "Subject: =?utf-8?q?Gr=C3=BC=C3=9Fe?=\r\nFrom: Alice <alice@example.org>\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            "ä".repeat(DESCRIPTION_MAX_LEN + 1)
+        );
+        let payload = DiscordDestination::build_payload(&config, data.as_bytes());
+        let description = format!("{}…", "ä".repeat(DESCRIPTION_MAX_LEN - 1));
+        assert_eq!(
+            payload,
+            json!({
+                "embeds": [{
+                    "title": "Grüße",
+                    "description": description,
+                    "author": { "name": "Alice <alice@example.org>" }
+                }],
+                "username": "idlemail"
+            })
+        );
+
+        // Discord rejects empty fields
+        let payload = DiscordDestination::build_payload(&config, b"\r\n");
+        assert_eq!(
+            payload["embeds"],
+            json!([{ "description": "(no text content)" }])
+        );
+    }
+}
@@ -1,16 +1,20 @@
 use crate::{
     config::ExecDestinationConfig,
-    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    hub::{DestinationMessage, DestinationNotifier, HubDestinationChannel, Mail, MailAgent},
 };
 use log::{debug, error, info, log_enabled, trace, Level as log_level};
 use std::{
-    io::{Read, Write},
+    io::{self, Read},
     process::{Command, Stdio},
+    sync::mpsc,
     thread,
 };
 
 use super::MailDestination;
 
+/// Maximum number of bytes of the child's output kept for the debug log
+const CHILD_LOG_MAX_LEN: u64 = 64 * 1024;
+
 pub struct ExecDestination {
     name: String,
     log_target: String,
@@ -26,6 +30,115 @@ impl ExecDestination {
             worker: None,
         }
     }
+
+    /// Spawn a child for the mail, pipe the mail into it and report the outcome.
+    fn deliver(
+        config: &ExecDestinationConfig,
+        name: &str,
+        log_target: &str,
+        mail: Mail,
+        notifier: &DestinationNotifier,
+    ) {
+        // spawn the process with the apropriate configuration (args, env, ..)
+        let mut exec_config = Command::new(&config.executable);
+        exec_config.stdin(Stdio::piped()).stdout(Stdio::piped());
+        if let Some(arguments) = config.arguments.as_ref() {
+            exec_config.args(arguments);
+        }
+        if let Some(environment) = config.environment.as_ref() {
+            exec_config.envs(environment);
+        }
+        exec_config.env("IDLEMAIL_DESTINATION", name);
+        exec_config.env("IDLEMAIL_SOURCE", &mail.from_src);
+
+        match exec_config.spawn() {
+            Ok(mut child) => {
+                // The mail is piped to the child from a second thread, while its output
+                // is drained here. Doing one after the other deadlocks, as soon as the
+                // child writes more output than fits into the pipe before having consumed
+                // all of its input.
+                let stdin = child.stdin.take();
+                let data = mail.data.clone();
+                let writer = thread::spawn(move || {
+                    stdin.map(|mut stdin| {
+                        // pipe the mail to the child in chunks, instead of one big write.
+                        // stdin is closed when done, so the child sees the end of the mail
+                        io::copy(&mut &data[..], &mut stdin)
+                    })
+                });
+                let child_log = child
+                    .stdout
+                    .take()
+                    .map(|stdout| drain_output(stdout, log_enabled!(log_level::Debug)));
+                let pipe_result = writer.join().expect("Thread exited with errors");
+                // wait for child to exit
+                let child_result = child.wait();
+                if let Some(Ok(child_log)) = child_log {
+                    if log_enabled!(log_level::Debug) {
+                        // if debug log is enabled, print child output
+                        // we do this manually to ensure, that child-output is one block in the log
+                        // child messages randomly mixed in would be ugly
+                        debug!(target: &format!("{}[Child]", log_target), "{}", child_log);
+                    }
+                }
+                match pipe_result {
+                    Some(Ok(_)) => {
+                        // we successfully opened stdin, and piped the mail to the child
+                        // handle child exit status
+                        match child_result {
+                            Ok(res) => {
+                                debug!(
+                                    target: log_target,
+                                    "Successfully sent mail {} to child", mail
+                                );
+                                if res.success() {
+                                    info!(
+                                        target: log_target,
+                                        "Child exited with: {} (mail {})",
+                                        res.code().unwrap_or(0),
+                                        mail
+                                    );
+                                    let response =
+                                        format!("Child exited with: {}", res.code().unwrap_or(0));
+                                    notifier.notify_sent(mail, response);
+                                    return;
+                                } else {
+                                    error!(
+                                        target: log_target,
+                                        "Child exited with: {} (mail {})",
+                                        res.code().unwrap_or(-1),
+                                        mail
+                                    );
+                                }
+                            }
+                            Err(err) => error!(
+                                target: log_target,
+                                "Child exited with error (mail {}): {}", mail, err
+                            ),
+                        }
+                    }
+                    Some(Err(err)) => {
+                        // piping stdin to the child went wrong
+                        error!(
+                            target: log_target,
+                            "Error while piping mail {} to spawned process: {}", mail, err
+                        )
+                    }
+                    None => {
+                        error!(target: log_target, "Failed to open stdin of child process")
+                    }
+                }
+            }
+            Err(err) => {
+                error!(
+                    target: log_target,
+                    "Error while spawning configured executable: {}", err
+                );
+            }
+        }
+        // if we made it here (we return on success), something went wrong
+        notifier.notify_failed_send(mail);
+    }
 }
 impl MailAgent for ExecDestination {
     fn join(&mut self) {
@@ -45,99 +158,56 @@ impl MailDestination for ExecDestination {
         let log_target = self.log_target.clone();
         let config = self.config.clone();
         self.worker = Some(thread::spawn(move || {
+            // every running child holds one permit, further mails wait in the queue
+            let max_concurrent = config.max_concurrent.unwrap_or(1);
+            let (permit_send, permits) = mpsc::channel();
+            for _ in 0..max_concurrent {
+                permit_send.send(()).unwrap();
+            }
+            let mut children: Vec<thread::JoinHandle<()>> = Vec::new();
             while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
-                // spawn the process with the apropriate configuration (args, env, ..)
-                let mut exec_config = Command::new(&config.executable);
-                exec_config.stdin(Stdio::piped()).stdout(Stdio::piped());
-                if let Some(arguments) = config.arguments.as_ref() {
-                    exec_config.args(arguments);
-                }
-                if let Some(environment) = config.environment.as_ref() {
-                    exec_config.envs(environment);
+                if permits.try_recv().is_err() {
+                    debug!(target: &log_target, "{} children running, mail {} has to wait", max_concurrent, mail);
+                    permits.recv().unwrap();
                 }
-                exec_config.env("IDLEMAIL_DESTINATION", &name);
-                exec_config.env("IDLEMAIL_SOURCE", &mail.from_src);
-
-                match exec_config.spawn() {
-                    Ok(ref mut child) => {
-                        match child.stdin.as_mut().map(|stdin| stdin.write(&mail.data)) {
-                            Some(Ok(_)) => {
-                                // we successfully opened stdin, and piped the mail to the child
-                                // wait for child to exit
-                                let child_result = child.wait();
-                                if log_enabled!(log_level::Debug) {
-                                    // if debug log is enabled, print child output
-                                    // we do this manually to ensure, that child-output is one block in the log
-                                    // child messages randomly mixed in would be ugly
-                                    if let Some(stdout) = child.stdout.as_mut() {
-                                        let mut child_log = String::new();
-                                        if stdout.read_to_string(&mut child_log).is_ok() {
-                                            debug!(
-                                                target: &format!("{}[Child]", log_target),
-                                                "{}", child_log
-                                            );
-                                        }
-                                    }
-                                }
-                                // handle child exit status
-                                match child_result {
-                                    Ok(res) => {
-                                        debug!(
-                                            target: &log_target,
-                                            "Successfully sent mail to child"
-                                        );
-                                        if res.success() {
-                                            info!(
-                                                target: &log_target,
-                                                "Child exited with: {}",
-                                                res.code().unwrap_or(0)
-                                            );
-                                            continue;
-                                        } else {
-                                            error!(
-                                                target: &log_target,
-                                                "Child exited with: {}",
-                                                res.code().unwrap_or(-1)
-                                            );
-                                        }
-                                    }
-                                    Err(err) => error!(
-                                        target: &log_target,
-                                        "Child exited with error: {}", err
-                                    ),
-                                }
-                            }
-                            Some(Err(err)) => {
-                                // piping stdin to the child went wrong
-                                error!(
-                                    target: &log_target,
-                                    "Error while piping mail to spawned process: {}", err
-                                )
-                            }
-                            None => {
-                                error!(target: &log_target, "Failed to open stdin of child process")
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        error!(
-                            target: &log_target,
-                            "Error while spawning configured executable: {}", err
-                        );
-                    }
-                }
-                // if we made it here (we continue on success), something went wrong
-                channel.notify_failed_send(mail);
+                children.retain(|child| !child.is_finished());
+                let (name, log_target, config) = (name.clone(), log_target.clone(), config.clone());
+                let (notifier, permit_send) = (channel.notifier(), permit_send.clone());
+                children.push(thread::spawn(move || {
+                    ExecDestination::deliver(&config, &name, &log_target, mail, &notifier);
+                    let _ = permit_send.send(());
+                }));
+            }
+            for child in children {
+                child.join().expect("Thread exited with errors");
             }
             info!(target: &log_target, "Stopping");
         }));
     }
 }
 
+/// Read the output of a child until it closes stdout. If `keep` is set, the start of the
+/// output (at most `CHILD_LOG_MAX_LEN` bytes) is returned for the log, the rest is discarded.
+fn drain_output(mut stdout: impl Read, keep: bool) -> io::Result<String> {
+    let mut kept = Vec::new();
+    if keep {
+        stdout
+            .by_ref()
+            .take(CHILD_LOG_MAX_LEN)
+            .read_to_end(&mut kept)?;
+    }
+    let discarded = io::copy(&mut stdout, &mut io::sink())?;
+    let mut output = String::from_utf8_lossy(&kept).into_owned();
+    if keep && discarded > 0 {
+        output.push_str(&format!("\n({} more bytes of output omitted)", discarded));
+    }
+    Ok(output)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::hub::Mail;
+    use crate::hub::{HubMessage, Mail};
     use lettre::{
         message::{header, Mailbox, MultiPart, SinglePart},
         Message,
@@ -145,9 +215,11 @@ mod tests {
     use std::{
         collections::HashMap,
         fs::{File, Permissions},
+        io::Write,
         os::unix::prelude::PermissionsExt,
         path::PathBuf,
         sync::mpsc,
+        time::Duration,
     };
     use tempfile::TempDir;
     use test_case::test_case;
@@ -232,6 +304,7 @@ mod tests {
                 executable: executable_path.to_string_lossy().to_string(),
                 arguments: Some(cliargs.into_iter().map(|s| s.to_owned()).collect()),
                 environment: Some(env),
+                max_concurrent: None,
             },
         );
         let (ra_send, ra_recv) = mpsc::channel();
@@ -247,6 +320,85 @@ mod tests {
         } // drop dst_send here, this signals the destination to exit
         execdst.join();
         let res = ra_recv.try_recv();
-        res.is_err()
+        matches!(res, Ok(HubMessage::MailSent { .. }))
+    }
+
+    #[test]
+    fn test_large_mail_with_large_output() {
+        // both far larger than a pipe's buffer
+        let mut data = b"Subject: large\r\n\r\n".to_vec();
+        data.extend(b"0123456789abcdef\r\n".repeat(128 * 1024));
+        let mail = Mail::from_rfc822("unit-test source 0".to_owned(), data);
+        let (_dir, executable_path) = prepare_validation_script("#!/bin/bash\ncat\n");
+
+        let mut execdst = ExecDestination::new(
+            "unit-test exec dst".to_owned(),
+            &ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                arguments: None,
+                environment: None,
+                max_concurrent: None,
+            },
+        );
+        let (ra_send, ra_recv) = mpsc::channel();
+        let (dst_send, dst_recv) = mpsc::channel();
+        execdst.start(HubDestinationChannel {
+            name: "unit-test exec dst".to_owned(),
+            sender: ra_send,
+            recv: dst_recv,
+        });
+        dst_send.send(DestinationMessage::Mail { mail }).unwrap();
+        // a deadlock would never answer
+        let res = ra_recv.recv_timeout(Duration::from_secs(30));
+        assert!(matches!(res, Ok(HubMessage::MailSent { .. })));
+        drop(dst_send);
+        execdst.join();
+    }
+
+    #[test_case(1 => 1)]
+    #[test_case(2 => 2)]
+    fn test_max_concurrent(max_concurrent: usize) -> usize {
+        let (dir, executable_path) = prepare_validation_script(
+            "#!/bin/bash\ncd $(dirname \"$0\")\necho start >> log\nsleep 0.5\necho end >> log\n",
+        );
+
+        let mut execdst = ExecDestination::new(
+            "unit-test exec dst".to_owned(),
+            &ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                arguments: None,
+                environment: None,
+                max_concurrent: Some(max_concurrent),
+            },
+        );
+        let (ra_send, ra_recv) = mpsc::channel();
+        {
+            let (dst_send, dst_recv) = mpsc::channel();
+            execdst.start(HubDestinationChannel {
+                name: "unit-test exec dst".to_owned(),
+                sender: ra_send,
+                recv: dst_recv,
+            });
+            for _ in 0..3 {
+                let mail = create_testmail("unit-test source 0".to_owned());
+                dst_send.send(DestinationMessage::Mail { mail }).unwrap();
+            }
+        }
+        execdst.join();
+        assert_eq!(ra_recv.try_iter().count(), 3);
+
+        // highest number of children running at the same time
+        let log = std::fs::read_to_string(dir.path().join("log")).unwrap();
+        let mut running = 0;
+        let mut max_running = 0;
+        for line in log.lines() {
+            running = if line == "start" {
+                running + 1
+            } else {
+                running - 1
+            };
+            max_running = max_running.max(running);
+        }
+        max_running
     }
 }
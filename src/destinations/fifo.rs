@@ -0,0 +1,110 @@
+use crate::{
+    config::FifoDestinationConfig,
+    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+};
+use log::{debug, error, info, trace};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::MailDestination;
+
+/// Seconds to wait for a reader, if the configuration does not specify a timeout
+const DEFAULT_TIMEOUT: u64 = 10;
+/// Interval in which opening the pipe is reattempted while there is no reader
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct FifoDestination {
+    log_target: String,
+    config: FifoDestinationConfig,
+    worker: Option<thread::JoinHandle<()>>,
+}
+impl FifoDestination {
+    pub fn new(name: String, config: &FifoDestinationConfig) -> Self {
+        Self {
+            log_target: format!("FifoDst[{}]", name),
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    /// Open the pipe for writing, waiting at most `timeout` for a reader to appear.
+    /// Opening blocks until there is a reader, so the pipe is opened non-blocking instead,
+    /// which fails (ENXIO) as long as nobody has the pipe opened for reading.
+    fn open(path: &str, timeout: Duration) -> io::Result<File> {
+        let deadline = Instant::now() + timeout;
+        let fifo = loop {
+            match OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)
+            {
+                Ok(fifo) => break fifo,
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("No reader opened the pipe within {}s", timeout.as_secs()),
+                        ));
+                    }
+                    thread::sleep(OPEN_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        // The mail is written with blocking writes, so a slow reader is not an error
+        let fd = fifo.as_raw_fd();
+        // SAFETY: fd is a valid, open file descriptor owned by `fifo`
+        let result = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fifo)
+    }
+}
+impl MailAgent for FifoDestination {
+    fn join(&mut self) {
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("Thread exited with errors");
+    }
+}
+impl MailDestination for FifoDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        self.worker = Some(thread::spawn(move || {
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                // every mail is written with its own open / close, so the reader sees
+                // the end of the mail as end of file
+                let result = FifoDestination::open(&config.path, timeout)
+                    .and_then(|mut fifo| io::copy(&mut mail.reader(), &mut fifo));
+                match result {
+                    Ok(len) => {
+                        debug!(target: &log_target, "Wrote {} bytes to {}", len, config.path);
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        channel.notify_sent(mail, format!("Wrote {} bytes", len));
+                    }
+                    Err(err) => {
+                        error!(target: &log_target, "Error while writing mail {} to {}: {}", mail, config.path, err);
+                        channel.notify_failed_send(mail);
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
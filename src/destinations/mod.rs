@@ -1,9 +1,26 @@
 use crate::hub::{HubDestinationChannel, MailAgent};
 
+pub mod archive;
+pub mod discord;
 pub mod exec;
+#[cfg(unix)]
+pub mod fifo;
+pub mod recipient_lookup;
+pub mod sendmail;
 pub mod smtp;
 pub mod testdst;
+pub mod xmpp;
 
 pub trait MailDestination: MailAgent {
     fn start(&mut self, channel: HubDestinationChannel);
 }
+
+/// Cut the text to at most `max_len` characters, marking the cut with an ellipsis.
+pub fn truncate(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(max_len - 1).collect();
+    truncated.push('…');
+    truncated
+}
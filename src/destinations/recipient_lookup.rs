@@ -0,0 +1,147 @@
+use crate::config::{LdapLookupConfig, RecipientLookupConfig};
+use ldap3::{ldap_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use std::{collections::HashSet, fs, time::Duration};
+
+/// Default LDAP filter, `{}` is replaced with the address
+const DEFAULT_LDAP_FILTER: &str = "(mail={})";
+/// Time the LDAP server has to accept the connection and answer
+const LDAP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Resolves the addresses a mail was sent to (e.g. `info@example.org`) into the addresses it
+/// is delivered to, using an alias table that is maintained outside of idlemail.
+pub struct RecipientLookup {
+    config: RecipientLookupConfig,
+}
+impl RecipientLookup {
+    pub fn new(config: RecipientLookupConfig) -> Self {
+        Self { config }
+    }
+
+    /// The recipients of all `addresses` known to the alias table, in order and without
+    /// duplicates. Empty, if none of them is known. Fails, if the table can not be read.
+    pub fn resolve(&self, addresses: &[String]) -> Result<Vec<String>, String> {
+        let recipients = match &self.config {
+            RecipientLookupConfig::File { path } => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read recipient_lookup file {}: {}", path, e))?;
+                resolve_aliases(&content, addresses)
+            }
+            RecipientLookupConfig::Ldap(config) => resolve_ldap(config, addresses)
+                .map_err(|e| format!("Failed to look up recipients in {}: {}", config.url, e))?,
+        };
+        let mut seen = HashSet::new();
+        Ok(recipients
+            .into_iter()
+            .filter(|recipient| seen.insert(recipient.to_lowercase()))
+            .collect())
+    }
+}
+
+/// Look up `addresses` in an alias file. Each line maps an address (or `@domain`, for all
+/// addresses of the domain without a line of their own) onto a comma separated list of
+/// recipients, like `/etc/aliases`. Lines starting with whitespace continue the list of
+/// the previous line, `#` starts a comment.
+fn resolve_aliases(content: &str, addresses: &[String]) -> Vec<String> {
+    let mut aliases: Vec<(String, Vec<String>)> = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let list = if line.starts_with([' ', '\t']) {
+            line
+        } else if let Some((key, list)) = line.split_once(':') {
+            aliases.push((key.trim().to_lowercase(), Vec::new()));
+            list
+        } else {
+            continue;
+        };
+        if let Some((_, recipients)) = aliases.last_mut() {
+            recipients.extend(
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|recipient| !recipient.is_empty())
+                    .map(str::to_owned),
+            );
+        }
+    }
+    let find = |key: &str| aliases.iter().find(|(alias, _)| alias == key);
+    addresses
+        .iter()
+        .filter_map(|address| {
+            let address = address.to_lowercase();
+            let domain = address.rsplit_once('@').map(|(_, domain)| domain)?;
+            find(&address).or_else(|| find(&format!("@{}", domain)))
+        })
+        .flat_map(|(_, recipients)| recipients.iter().cloned())
+        .collect()
+}
+
+/// Search an entry for each of `addresses` with the configured filter, and collect the values
+/// of the configured attribute of the found entries.
+fn resolve_ldap(
+    config: &LdapLookupConfig,
+    addresses: &[String],
+) -> Result<Vec<String>, ldap3::LdapError> {
+    let settings = LdapConnSettings::new().set_conn_timeout(LDAP_TIMEOUT);
+    let mut ldap = LdapConn::with_settings(settings, &config.url)?;
+    ldap.with_timeout(LDAP_TIMEOUT);
+    if let (Some(dn), Some(password)) = (&config.bind_dn, &config.bind_password) {
+        ldap.simple_bind(dn, password)?.success()?;
+    }
+    let filter = config.filter.as_deref().unwrap_or(DEFAULT_LDAP_FILTER);
+    let mut recipients = Vec::new();
+    for address in addresses {
+        let filter = filter.replace("{}", &ldap_escape(address.as_str()));
+        let (entries, _) = ldap
+            .search(
+                &config.base,
+                Scope::Subtree,
+                &filter,
+                vec![&config.attribute],
+            )?
+            .success()?;
+        for entry in entries {
+            // servers return the attribute in the case of their schema
+            let values = SearchEntry::construct(entry)
+                .attrs
+                .into_iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&config.attribute))
+                .flat_map(|(_, values)| values);
+            recipients.extend(values);
+        }
+    }
+    let _ = ldap.unbind();
+    Ok(recipients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_aliases() {
+        let aliases = "# forwarding aliases\n\
+            info@example.org: alice@example.com, bob@example.com\n\
+            Sales@Example.org: carol@example.com,\n\
+            \tdave@example.com # on holiday until May\n\
+            @example.net: catchall@example.com\n";
+        let resolve = |addresses: &[&str]| {
+            let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+            resolve_aliases(aliases, &addresses)
+        };
+        assert_eq!(
+            resolve(&["sales@example.org", "unknown@example.org"]),
+            ["carol@example.com", "dave@example.com"]
+        );
+        assert_eq!(
+            resolve(&["INFO@example.org", "x@example.net"]),
+            [
+                "alice@example.com",
+                "bob@example.com",
+                "catchall@example.com"
+            ]
+        );
+        assert!(resolve(&["unknown@example.org"]).is_empty());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{
+    config::SendmailDestinationConfig,
+    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+};
+use log::{debug, error, info, trace, warn};
+use std::{
+    io::{self, Read},
+    process::{Command, ExitStatus, Stdio},
+    thread,
+};
+
+use super::MailDestination;
+
+/// Used if the configuration does not specify a path
+const DEFAULT_PATH: &str = "/usr/sbin/sendmail";
+/// Maximum number of bytes of sendmail's error output kept for the log
+const STDERR_MAX_LEN: u64 = 4 * 1024;
+/// Exit codes (sysexits.h) telling that the mail itself can not be delivered:
+/// EX_DATAERR, EX_NOUSER and EX_NOHOST. All other failures might be temporary.
+const PERMANENT_EXIT_CODES: [i32; 3] = [65, 67, 68];
+
+enum SendError {
+    /// Sendmail rejected the message. Retrying will not help.
+    Permanent(String),
+    Temporary(String),
+}
+
+pub struct SendmailDestination {
+    log_target: String,
+    config: SendmailDestinationConfig,
+    worker: Option<thread::JoinHandle<()>>,
+}
+impl SendmailDestination {
+    pub fn new(name: String, config: &SendmailDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Sendmail[{}]", name),
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    /// Without configured recipients, sendmail reads them from the To, Cc and Bcc headers (`-t`).
+    /// `-i` keeps a line with a single dot from ending the message early.
+    fn arguments(config: &SendmailDestinationConfig) -> Vec<String> {
+        let mut arguments = vec!["-i".to_owned()];
+        if let Some(sender) = &config.sender {
+            arguments.push("-f".to_owned());
+            arguments.push(sender.clone());
+        }
+        match &config.recipients {
+            Some(recipients) => {
+                arguments.push("--".to_owned());
+                arguments.extend(recipients.iter().cloned());
+            }
+            None => arguments.push("-t".to_owned()),
+        }
+        arguments
+    }
+
+    fn send(
+        config: &SendmailDestinationConfig,
+        data: &[u8],
+        log_target: &str,
+    ) -> Result<ExitStatus, SendError> {
+        let mut child = Command::new(config.path.as_deref().unwrap_or(DEFAULT_PATH))
+            .args(Self::arguments(config))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| SendError::Temporary(format!("Failed to start sendmail: {}", e)))?;
+        // piped from a second thread, so sendmail can not block on a full stderr meanwhile
+        let stdin = child.stdin.take();
+        let data = data.to_vec();
+        let writer =
+            thread::spawn(move || stdin.map(|mut stdin| io::copy(&mut &data[..], &mut stdin)));
+        let stderr = child.stderr.take().map(read_stderr).unwrap_or_default();
+        let pipe_result = writer.join().expect("Thread exited with errors");
+        let status = child
+            .wait()
+            .map_err(|e| SendError::Temporary(format!("Failed to wait for sendmail: {}", e)))?;
+        if status.success() {
+            if let Some(Err(e)) = pipe_result {
+                // sendmail accepted the mail anyway, so it must have read all it needed
+                debug!(target: log_target, "Error while piping the mail to sendmail: {}", e);
+            }
+            return Ok(status);
+        }
+        let msg = format!("Sendmail exited with {}: {}", status, stderr.trim());
+        match status.code() {
+            Some(code) if PERMANENT_EXIT_CODES.contains(&code) => Err(SendError::Permanent(msg)),
+            _ => Err(SendError::Temporary(msg)),
+        }
+    }
+}
+
+/// Read sendmail's error output, keeping at most `STDERR_MAX_LEN` bytes of it.
+fn read_stderr(mut stderr: impl Read) -> String {
+    let mut kept = Vec::new();
+    let _ = stderr.by_ref().take(STDERR_MAX_LEN).read_to_end(&mut kept);
+    let _ = io::copy(&mut stderr, &mut io::sink());
+    String::from_utf8_lossy(&kept).into_owned()
+}
+
+impl MailAgent for SendmailDestination {
+    fn join(&mut self) {
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("Thread exited with errors");
+    }
+}
+impl MailDestination for SendmailDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        self.worker = Some(thread::spawn(move || {
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                match SendmailDestination::send(&config, &mail.data, &log_target) {
+                    Ok(status) => {
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        channel.notify_sent(mail, format!("Sendmail exited with {}", status));
+                    }
+                    Err(SendError::Permanent(err)) => {
+                        warn!(target: &log_target, "Sendmail does not accept mail {}, will not try again:\n{}", mail, err);
+                        channel.notify_rejected(mail, err);
+                    }
+                    Err(SendError::Temporary(err)) => {
+                        error!(target: &log_target, "Error while sending mail {}:\n{}", mail, err);
+                        channel.notify_failed_send(mail);
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use test_case::test_case;
+
+    fn config(path: &str, recipients: Option<Vec<&str>>) -> SendmailDestinationConfig {
+        SendmailDestinationConfig {
+            path: Some(path.to_owned()),
+            sender: None,
+            recipients: recipients.map(|r| r.into_iter().map(str::to_owned).collect()),
+        }
+    }
+
+    #[test]
+    fn test_arguments() {
+        let mut with_recipients = config("sendmail", Some(vec!["a@example.org", "-b"]));
+        with_recipients.sender = Some("idlemail@example.org".to_owned());
+        assert_eq!(
+            SendmailDestination::arguments(&with_recipients),
+            [
+                "-i",
+                "-f",
+                "idlemail@example.org",
+                "--",
+                "a@example.org",
+                "-b"
+            ]
+        );
+        assert_eq!(
+            SendmailDestination::arguments(&config("sendmail", None)),
+            ["-i", "-t"]
+        );
+    }
+
+    #[test_case(0 => "sent")]
+    #[test_case(67 => "permanent" ; "unknown user")]
+    #[test_case(75 => "temporary" ; "temporary failure")]
+    fn test_exit_status(code: i32) -> &'static str {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sendmail");
+        let received = dir.path().join("received");
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\ncat > '{}'\necho 'no such user' >&2\nexit {}\n",
+                received.display(),
+                code
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let result = SendmailDestination::send(
+            &config(&path.to_string_lossy(), None),
+            b"Subject: test\r\n\r\nbody\r\n",
+            "Sendmail[test]",
+        );
+        assert_eq!(
+            fs::read(&received).unwrap(),
+            b"Subject: test\r\n\r\nbody\r\n"
+        );
+        match result {
+            Ok(_) => "sent",
+            Err(SendError::Permanent(msg)) => {
+                assert!(msg.ends_with("no such user"));
+                "permanent"
+            }
+            Err(SendError::Temporary(_)) => "temporary",
+        }
+    }
+}
@@ -1,14 +1,30 @@
 use crate::{
-    config::{AuthMethod, SmtpDestinationConfig},
-    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    config::{
+        AuthMethod, Encryption, ForwardMode, SmtpDestinationConfig, SummaryConfig, TlsVersion,
+    },
+    dkim::DkimSigner,
+    error::ErrorReport,
+    hub::{rfc822_part, DestinationMessage, HubDestinationChannel, Mail, MailAgent},
+    mime,
 };
 use lettre::{
-    address::Envelope, transport::smtp::authentication as auth, Address, SmtpTransport, Transport,
+    address::Envelope,
+    message::{header::ContentType, Mailbox, MultiPart, SinglePart},
+    transport::smtp::{
+        authentication as auth,
+        client::{self, SmtpConnection, Tls, TlsParameters},
+        extension::{ClientId, Extension},
+    },
+    Address, Message, SmtpTransport, Transport,
 };
-use log::{error, info, trace, warn};
-use std::thread;
+use log::{debug, error, info, trace, warn};
+use std::{thread, time::Duration};
 
-use super::MailDestination;
+use super::{recipient_lookup::RecipientLookup, truncate, MailDestination};
+
+/// Minimum delay before a greylisted mail is retried. Greylisting servers reject retries
+/// that come too early (commonly within 1-5 minutes) again.
+const GREYLIST_RETRY_DELAY: Duration = Duration::from_secs(300);
 
 pub struct SmtpDestination {
     log_target: String,
@@ -23,6 +39,136 @@ impl SmtpDestination {
             worker: None,
         }
     }
+
+    /// TLS parameters for the server, honoring the configured minimum TLS version.
+    fn tls_parameters(
+        config: &SmtpDestinationConfig,
+    ) -> Result<TlsParameters, lettre::transport::smtp::Error> {
+        let mut builder = TlsParameters::builder(config.server.clone());
+        if let Some(min) = config.min_tls_version {
+            builder = builder.set_min_tls_version(match min {
+                TlsVersion::Tls10 => client::TlsVersion::Tlsv10,
+                TlsVersion::Tls11 => client::TlsVersion::Tlsv11,
+                TlsVersion::Tls12 => client::TlsVersion::Tlsv12,
+            });
+        }
+        builder.build()
+    }
+
+    /// Connect to the server once, to find out whether it advertises 8BITMIME.
+    fn probe_8bitmime(
+        config: &SmtpDestinationConfig,
+    ) -> Result<bool, lettre::transport::smtp::Error> {
+        let tls_parameters = match config.encryption {
+            Encryption::Ssl => Some(Self::tls_parameters(config)?),
+            Encryption::None | Encryption::Starttls { .. } => None,
+        };
+        let mut connection = SmtpConnection::connect(
+            (config.server.as_str(), config.port),
+            Some(Duration::from_secs(60)),
+            &ClientId::default(),
+            tls_parameters.as_ref(),
+            None,
+        )?;
+        let supported = connection
+            .server_info()
+            .supports_feature(Extension::EightBitMime);
+        let _ = connection.quit();
+        Ok(supported)
+    }
+
+    /// The envelope recipients of the mail: the recipients its original recipients resolve to
+    /// with the `recipient_lookup`, or the configured `recipient`, if none of them is known.
+    fn lookup_recipients(
+        lookup: &RecipientLookup,
+        mail: &Mail,
+        recipient: &Address,
+        log_target: &str,
+    ) -> Result<Vec<Address>, String> {
+        let original = mail.recipients();
+        let recipients: Vec<Address> = lookup
+            .resolve(&original)?
+            .into_iter()
+            .filter_map(|resolved| match resolved.parse() {
+                Ok(address) => Some(address),
+                Err(err) => {
+                    warn!(target: log_target, "Ignoring invalid recipient {} looked up for mail {}: {}", resolved, mail, err);
+                    None
+                }
+            })
+            .collect();
+        if recipients.is_empty() {
+            debug!(target: log_target, "No recipients found for {}, sending mail {} to {}", original.join(", "), mail, recipient);
+            return Ok(vec![recipient.clone()]);
+        }
+        debug!(target: log_target, "Resolved {} to {}", original.join(", "), recipients.iter().map(Address::to_string).collect::<Vec<_>>().join(", "));
+        Ok(recipients)
+    }
+
+    /// Wrap the given mail as message/rfc822 attachment into a new message to `recipient`.
+    fn wrap_as_attachment(
+        data: &[u8],
+        recipient: &Address,
+    ) -> Result<Vec<u8>, lettre::error::Error> {
+        let original = mime::Entity::parse(data);
+        let recipient = Mailbox::new(None, recipient.clone());
+        let from = original
+            .header("From")
+            .and_then(|from| from.parse().ok())
+            .unwrap_or_else(|| recipient.clone());
+        let subject = original.header("Subject").unwrap_or_default();
+        let attachment = rfc822_part(data, "forwarded.eml");
+        let message = Message::builder()
+            .from(from)
+            .to(recipient)
+            .subject(format!("Fwd: {}", subject))
+            .multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain("Forwarded message attached.".to_owned()))
+                    .singlepart(attachment),
+            )?;
+        Ok(message.formatted())
+    }
+
+    /// Replace the given mail by a plain text message to `recipient`, consisting of the
+    /// (optional) sender, the subject and the start of the text content.
+    fn summarize(
+        data: &[u8],
+        recipient: &Address,
+        summary: &SummaryConfig,
+    ) -> Result<Vec<u8>, lettre::error::Error> {
+        let original = mime::Entity::parse(data);
+        let recipient = Mailbox::new(None, recipient.clone());
+        let header = |name| {
+            original
+                .header(name)
+                .map(|value| mime::decode_header_words(&value))
+                .unwrap_or_default()
+        };
+        let from_header = header("From");
+        let from = from_header.parse().unwrap_or_else(|_| recipient.clone());
+
+        let mut lines = Vec::new();
+        if summary.include_from && !from_header.is_empty() {
+            lines.push(from_header);
+        }
+        let subject = header("Subject");
+        if !subject.is_empty() {
+            lines.push(subject);
+        }
+        // line breaks and indentation only waste characters on a pager
+        let text = original.text_content().unwrap_or_default();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            lines.push(text);
+        }
+        let message = Message::builder()
+            .from(from)
+            .to(recipient)
+            .header(ContentType::TEXT_PLAIN)
+            .body(truncate(&lines.join("\n"), summary.max_chars))?;
+        Ok(message.formatted())
+    }
 }
 impl MailAgent for SmtpDestination {
     fn join(&mut self) {
@@ -47,27 +193,52 @@ impl MailDestination for SmtpDestination {
                 return;
             }
         };
+        let dkim = match self
+            .config
+            .dkim
+            .as_ref()
+            .map(|dkim| DkimSigner::new(&dkim.domain, &dkim.selector, &dkim.private_key))
+        {
+            Some(Err(err)) => {
+                error!(target: &log_target, "Failed to set up DKIM signing:\n{}", ErrorReport(&err));
+                return;
+            }
+            dkim => dkim.transpose().unwrap(),
+        };
         trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
         let config = self.config.clone();
 
         self.worker = Some(thread::spawn(move || {
             let mut connection_builder = match config.encryption {
-                crate::config::Encryption::None => SmtpTransport::builder_dangerous(&config.server),
-                crate::config::Encryption::Ssl => {
-                    SmtpTransport::relay(&config.server).expect("Failed to initialize smtp client")
+                Encryption::None => SmtpTransport::builder_dangerous(&config.server),
+                Encryption::Ssl => {
+                    SmtpTransport::builder_dangerous(&config.server).tls(Tls::Wrapper(
+                        SmtpDestination::tls_parameters(&config)
+                            .expect("Failed to initialize smtp client"),
+                    ))
                 }
-                crate::config::Encryption::Starttls => {
-                    SmtpTransport::starttls_relay(&config.server)
-                        .expect("Failed to initialize smtp client")
+                Encryption::Starttls { require_tls } => {
+                    let tls_parameters = SmtpDestination::tls_parameters(&config)
+                        .expect("Failed to initialize smtp client");
+                    // Required fails right after EHLO, before credentials are sent, if the
+                    // server (or someone in between) does not offer STARTTLS
+                    let tls = if require_tls.unwrap_or(true) {
+                        Tls::Required(tls_parameters)
+                    } else {
+                        warn!(target: &log_target, "STARTTLS is not required, mails and credentials are sent unencrypted if the server does not offer it");
+                        Tls::Opportunistic(tls_parameters)
+                    };
+                    SmtpTransport::builder_dangerous(&config.server).tls(tls)
                 }
             };
 
             connection_builder = connection_builder.port(config.port);
 
             // configure authentication
-            if let Some(auth) = config.auth {
+            if let Some(auth) = config.auth.clone() {
                 match auth {
-                    AuthMethod::None => {}
+                    // preauth and xoauth2 are rejected by the config validation
+                    AuthMethod::None | AuthMethod::Preauth | AuthMethod::XOAuth2 { .. } => {}
                     AuthMethod::Plain { user, password } => {
                         connection_builder = connection_builder
                             .credentials(auth::Credentials::new(user, password))
@@ -82,17 +253,103 @@ impl MailDestination for SmtpDestination {
             }
 
             let mailer = connection_builder.build();
+            let mut supports_8bitmime = None;
+            let lookup = config.recipient_lookup.clone().map(RecipientLookup::new);
 
             while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                let recipients = match &lookup {
+                    Some(lookup) => match SmtpDestination::lookup_recipients(
+                        lookup,
+                        &mail,
+                        &recipient,
+                        &log_target,
+                    ) {
+                        Ok(recipients) => recipients,
+                        Err(err) => {
+                            error!(target: &log_target, "Error while looking up the recipients of mail {}:\n{}", mail, err);
+                            channel.notify_failed_send(mail);
+                            continue;
+                        }
+                    },
+                    None => vec![recipient.clone()],
+                };
+                let mut data = mail.data.clone();
+                if config.force_7bit && !data.is_ascii() {
+                    let supported = match supports_8bitmime {
+                        Some(supported) => supported,
+                        None => match SmtpDestination::probe_8bitmime(&config) {
+                            Ok(supported) => *supports_8bitmime.insert(supported),
+                            Err(err) => {
+                                // re-encoding is harmless, so better safe than sorry. Probe again next time.
+                                warn!(target: &log_target, "Failed to check server for 8BITMIME support:\n{}", ErrorReport(&err.into()));
+                                false
+                            }
+                        },
+                    };
+                    if !supported {
+                        debug!(target: &log_target, "Server does not support 8BITMIME, re-encoding mail to 7bit");
+                        let mut message = mime::Entity::parse(&data);
+                        message.make_7bit();
+                        data = message.to_bytes().into();
+                    }
+                }
+
+                if config.forward_mode == ForwardMode::Attachment {
+                    match SmtpDestination::wrap_as_attachment(&data, &recipients[0]) {
+                        Ok(wrapped) => data = wrapped.into(),
+                        Err(err) => {
+                            warn!(target: &log_target, "Failed to wrap mail {} as attachment, will not try again:\n{}", mail, err);
+                            channel.notify_rejected(mail, err.to_string());
+                            continue;
+                        }
+                    }
+                }
+
+                if let (ForwardMode::Summary, Some(summary)) =
+                    (config.forward_mode, &config.summary)
+                {
+                    match SmtpDestination::summarize(&data, &recipients[0], summary) {
+                        Ok(summarized) => data = summarized.into(),
+                        Err(err) => {
+                            warn!(target: &log_target, "Failed to summarize mail {}, will not try again:\n{}", mail, err);
+                            channel.notify_rejected(mail, err.to_string());
+                            continue;
+                        }
+                    }
+                }
+
+                if let Some(dkim) = &dkim {
+                    match dkim.sign(&data) {
+                        Ok(signed) => data = signed.into(),
+                        Err(err) => {
+                            warn!(target: &log_target, "Failed to DKIM-sign mail {}, will not try again:\n{}", mail, ErrorReport(&err));
+                            channel.notify_rejected(mail, err.to_string());
+                            continue;
+                        }
+                    }
+                }
+
                 // Send raw mail using constructed envelope
-                let evenlope = Envelope::new(None, vec![recipient.clone()]).unwrap();
-                match mailer.send_raw(&evenlope, &mail.data) {
-                    Ok(_) => info!(target: &log_target, "Successfully sent mail"),
+                let evenlope = Envelope::new(None, recipients).unwrap();
+                match mailer.send_raw(&evenlope, &data) {
+                    Ok(response) => {
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        let message: Vec<&str> = response.message().collect();
+                        channel.notify_sent(
+                            mail,
+                            format!("{} {}", response.code(), message.join(" ")),
+                        );
+                    }
                     Err(err) => {
                         if err.is_permanent() {
-                            warn!(target: &log_target, "The destination server does not accept this email, will not try again:\n{}", err);
+                            let reason = err.to_string();
+                            warn!(target: &log_target, "The destination server does not accept mail {}, will not try again:\n{}", mail, ErrorReport(&err.into()));
+                            channel.notify_rejected(mail, reason);
+                        } else if is_greylisting(&err) {
+                            info!(target: &log_target, "Mail {} was greylisted, retrying in at least {}s: {}", mail, GREYLIST_RETRY_DELAY.as_secs(), err);
+                            channel.notify_deferred(mail, GREYLIST_RETRY_DELAY);
                         } else {
-                            error!(target: &log_target, "Error while sending mail:\n{}", err);
+                            error!(target: &log_target, "Error while sending mail {}:\n{}", mail, ErrorReport(&err.into()));
                             channel.notify_failed_send(mail);
                         }
                     }
@@ -102,3 +359,21 @@ impl MailDestination for SmtpDestination {
         }));
     }
 }
+
+/// Whether the server temporarily rejected the mail because of greylisting. There is no
+/// dedicated reply code for it, but servers mention it in the reply text.
+fn is_greylisting(err: &lettre::transport::smtp::Error) -> bool {
+    if !err.is_transient() {
+        return false;
+    }
+    let message = err.to_string().to_lowercase();
+    [
+        "greylist",
+        "graylist",
+        "grey-list",
+        "gray-list",
+        "try again later",
+    ]
+    .iter()
+    .any(|hint| message.contains(hint))
+}
@@ -1,15 +1,20 @@
 use crate::{
     config::TestDestinationConfig,
-    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    hub::{DestinationMessage, HubDestinationChannel, Mail, MailAgent},
 };
 use log::{info, trace};
-use std::thread;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
 
 use super::MailDestination;
 
 pub struct TestDestination {
     log_target: String,
     config: TestDestinationConfig,
+    /// Every mail the destination simulated a successful delivery for, in order
+    delivered: Arc<Mutex<Vec<Mail>>>,
     worker: Option<thread::JoinHandle<()>>,
 }
 impl TestDestination {
@@ -17,9 +22,16 @@ impl TestDestination {
         Self {
             log_target: format!("TestDst[{}]", name),
             config: config.clone(),
+            delivered: Arc::default(),
             worker: None,
         }
     }
+
+    /// Buffer recording the delivered mails, to assert on them in tests.
+    #[cfg(test)]
+    pub fn delivered(&self) -> Arc<Mutex<Vec<Mail>>> {
+        self.delivered.clone()
+    }
 }
 impl MailAgent for TestDestination {
     fn join(&mut self) {
@@ -37,15 +49,18 @@ impl MailDestination for TestDestination {
 
         let log_target = self.log_target.clone();
         let config = self.config.clone();
+        let delivered = self.delivered.clone();
         self.worker = Some(thread::spawn(move || {
             let mut fails_remaining = config.fail_n_first;
             while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
                 if fails_remaining > 0 {
-                    info!(target: &log_target, "Got Mail: Simulating send failure.");
+                    info!(target: &log_target, "Got Mail {}: Simulating send failure.", mail);
                     fails_remaining -= 1;
                     channel.notify_failed_send(mail);
                 } else {
-                    info!(target: &log_target, "Got Mail: Simulating success");
+                    info!(target: &log_target, "Got Mail {}: Simulating success", mail);
+                    delivered.lock().unwrap().push(mail.clone());
+                    channel.notify_sent(mail, "Simulated success".to_owned());
                 }
             }
             info!(target: &log_target, "Stopping");
@@ -0,0 +1,558 @@
+use crate::{
+    config::XmppDestinationConfig,
+    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    mime,
+};
+use base64::Engine;
+use log::{debug, error, info, trace, warn};
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    net::TcpStream,
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+use xml::{
+    escape::{escape_str_attribute, escape_str_pcdata},
+    reader::{EventReader, XmlEvent},
+};
+
+use super::{truncate, MailDestination};
+
+/// Used if the configuration does not specify a port
+const DEFAULT_PORT: u16 = 5222;
+/// Resource of the session, i.e. the client name shown to other clients of the account
+const RESOURCE: &str = "idlemail";
+/// Maximum number of characters of the mail's text in the chat message
+const TEXT_MAX_LEN: usize = 1000;
+/// Time the server has to answer, before the connection is considered dead
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+const NS_STREAM: &str = "http://etherx.jabber.org/streams";
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_SESSION: &str = "urn:ietf:params:xml:ns:xmpp-session";
+const NS_PING: &str = "urn:xmpp:ping";
+
+enum SendError {
+    /// The server rejected the message. Retrying will not help.
+    Permanent(String),
+    Temporary(String),
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Tls(native_tls::TlsStream<TcpStream>),
+}
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The connection, shared between the XML reader and the writes of the session.
+#[derive(Clone)]
+struct SharedConnection(Rc<RefCell<Connection>>);
+impl Read for SharedConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+impl Write for SharedConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// An element received from the server (e.g. a stanza), with its children.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    namespace: Option<String>,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+impl Element {
+    fn is(&self, name: &str, namespace: &str) -> bool {
+        self.name == name && self.namespace.as_deref() == Some(namespace)
+    }
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+    fn child(&self, name: &str, namespace: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(name, namespace))
+    }
+    /// Name of the first child, e.g. the condition of an error
+    fn first_child_name(&self) -> &str {
+        self.children
+            .first()
+            .map(|child| child.name.as_str())
+            .unwrap_or("unknown")
+    }
+}
+
+/// Read the next complete element below the root of the stream.
+fn read_element<R: Read>(reader: &mut EventReader<R>) -> Result<Element, String> {
+    let mut open: Vec<Element> = Vec::new();
+    loop {
+        let event = reader
+            .next()
+            .map_err(|e| format!("Failed to read from server: {}", e))?;
+        match event {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => open.push(Element {
+                name: name.local_name,
+                namespace: name.namespace,
+                attributes: attributes
+                    .into_iter()
+                    .map(|attribute| (attribute.name.local_name, attribute.value))
+                    .collect(),
+                ..Default::default()
+            }),
+            XmlEvent::EndElement { .. } => match open.pop() {
+                Some(element) => match open.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                },
+                None => return Err("Server closed the stream".to_owned()),
+            },
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = open.last_mut() {
+                    element.text.push_str(&text);
+                }
+            }
+            XmlEvent::EndDocument => return Err("Server closed the connection".to_owned()),
+            _ => {}
+        }
+    }
+}
+
+/// Text without the characters that are not allowed in XML (e.g. control characters), which
+/// would make the server close the stream.
+fn xml_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect()
+}
+
+/// A logged in session with the server.
+struct XmppSession {
+    connection: SharedConnection,
+    reader: EventReader<SharedConnection>,
+    next_id: u64,
+}
+impl XmppSession {
+    /// Connect, secure the connection with STARTTLS, log in and bind a resource.
+    fn connect(config: &XmppDestinationConfig) -> Result<Self, String> {
+        let (local, domain) = config.account();
+        let server = config.server.as_deref().unwrap_or(domain);
+        let port = config.port.unwrap_or(DEFAULT_PORT);
+        let tcp = TcpStream::connect((server, port))
+            .map_err(|e| format!("Failed to connect to {}:{}: {}", server, port, e))?;
+        tcp.set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let connection = SharedConnection(Rc::new(RefCell::new(Connection::Plain(tcp))));
+
+        // mails and the password are never sent unencrypted
+        let (mut session, features) = Self::open_stream(connection, domain)?;
+        if features.child("starttls", NS_TLS).is_none() {
+            return Err("Server does not offer STARTTLS".to_owned());
+        }
+        session.write(&format!("<starttls xmlns='{}'/>", NS_TLS))?;
+        if !session.read_element()?.is("proceed", NS_TLS) {
+            return Err("Server refused STARTTLS".to_owned());
+        }
+        let XmppSession {
+            connection, reader, ..
+        } = session;
+        drop(reader);
+        let Ok(connection) = Rc::try_unwrap(connection.0) else {
+            unreachable!("The reader of the stream is dropped");
+        };
+        let Connection::Plain(tcp) = connection.into_inner() else {
+            unreachable!("STARTTLS is only negotiated once");
+        };
+        // the certificate is issued for the domain of the account, not the host
+        let tls = native_tls::TlsConnector::new()
+            .map_err(|e| format!("Failed to initialize TLS: {}", e))?
+            .connect(domain, tcp)
+            .map_err(|e| format!("TLS handshake failed: {}", e))?;
+        let connection = SharedConnection(Rc::new(RefCell::new(Connection::Tls(tls))));
+
+        let (mut session, features) = Self::open_stream(connection.clone(), domain)?;
+        let plain_offered = features
+            .child("mechanisms", NS_SASL)
+            .is_some_and(|mechanisms| {
+                mechanisms
+                    .children
+                    .iter()
+                    .any(|mechanism| mechanism.text.trim() == "PLAIN")
+            });
+        if !plain_offered {
+            return Err("Server does not offer authentication with PLAIN".to_owned());
+        }
+        let credentials = format!("\0{}\0{}", local, config.password);
+        session.write(&format!(
+            "<auth xmlns='{}' mechanism='PLAIN'>{}</auth>",
+            NS_SASL,
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        ))?;
+        let response = session.read_element()?;
+        if !response.is("success", NS_SASL) {
+            return Err(format!(
+                "Authentication failed: {}",
+                response.first_child_name()
+            ));
+        }
+
+        let (mut session, features) = Self::open_stream(connection, domain)?;
+        let id = session.id();
+        let bound = session.request(
+            &format!(
+                "<iq type='set' id='{}'><bind xmlns='{}'><resource>{}</resource></bind></iq>",
+                id, NS_BIND, RESOURCE
+            ),
+            &id,
+        )?;
+        if bound.attribute("type") != Some("result") {
+            return Err(format!(
+                "Failed to bind a resource: {}",
+                error_condition(&bound)
+            ));
+        }
+        // only required by old servers (RFC 3921), newer ones mark it optional or omit it
+        if let Some(session_feature) = features.child("session", NS_SESSION) {
+            if session_feature
+                .children
+                .iter()
+                .all(|child| child.name != "optional")
+            {
+                let id = session.id();
+                let established = session.request(
+                    &format!(
+                        "<iq type='set' id='{}'><session xmlns='{}'/></iq>",
+                        id, NS_SESSION
+                    ),
+                    &id,
+                )?;
+                if established.attribute("type") != Some("result") {
+                    return Err(format!(
+                        "Failed to establish a session: {}",
+                        error_condition(&established)
+                    ));
+                }
+            }
+        }
+        // A negative priority keeps chats with the account from being routed to idlemail,
+        // which does not read them.
+        session.write("<presence><priority>-1</priority></presence>")?;
+        Ok(session)
+    }
+
+    /// Open a new stream on the connection, and read the features the server offers on it.
+    fn open_stream(connection: SharedConnection, domain: &str) -> Result<(Self, Element), String> {
+        let mut session = Self {
+            reader: EventReader::new(connection.clone()),
+            connection,
+            next_id: 1,
+        };
+        session.write(&format!(
+            "<?xml version='1.0'?><stream:stream to='{}' version='1.0' xmlns='jabber:client' xmlns:stream='{}'>",
+            escape_str_attribute(domain),
+            NS_STREAM
+        ))?;
+        loop {
+            let event = session
+                .reader
+                .next()
+                .map_err(|e| format!("Failed to read from server: {}", e))?;
+            match event {
+                XmlEvent::StartElement { name, .. } if name.local_name == "stream" => break,
+                XmlEvent::StartElement { name, .. } => {
+                    return Err(format!(
+                        "Server started the stream with {}",
+                        name.local_name
+                    ))
+                }
+                XmlEvent::EndDocument => return Err("Server closed the connection".to_owned()),
+                _ => {}
+            }
+        }
+        let features = session.read_element()?;
+        if !features.is("features", NS_STREAM) {
+            return Err(format!("Expected stream features, got {}", features.name));
+        }
+        Ok((session, features))
+    }
+
+    fn id(&mut self) -> String {
+        self.next_id += 1;
+        format!("idlemail{}", self.next_id)
+    }
+
+    fn write(&mut self, data: &str) -> Result<(), String> {
+        self.connection
+            .write_all(data.as_bytes())
+            .and_then(|_| self.connection.flush())
+            .map_err(|e| format!("Failed to write to server: {}", e))
+    }
+
+    /// The next element below the stream. Stream errors end the session.
+    fn read_element(&mut self) -> Result<Element, String> {
+        let element = read_element(&mut self.reader)?;
+        if element.is("error", NS_STREAM) {
+            return Err(format!(
+                "Server closed the stream: {}",
+                element.first_child_name()
+            ));
+        }
+        Ok(element)
+    }
+
+    /// Send the iq `stanza` and wait for the response with the same `id`. Other stanzas
+    /// received meanwhile (e.g. presence of contacts) are ignored.
+    fn request(&mut self, stanza: &str, id: &str) -> Result<Element, String> {
+        self.write(stanza)?;
+        loop {
+            let element = self.read_element()?;
+            if element.name == "iq" && element.attribute("id") == Some(id) {
+                return Ok(element);
+            }
+        }
+    }
+
+    /// Send a chat message to `recipient`. To know that the server processed it, a ping is
+    /// sent after it, which the server answers after the message.
+    fn send_message(&mut self, recipient: &str, body: &str) -> Result<(), SendError> {
+        let message_id = self.id();
+        let ping_id = self.id();
+        self.write(&format!(
+            "<message to='{}' type='chat' id='{}'><body>{}</body></message><iq type='get' id='{}'><ping xmlns='{}'/></iq>",
+            escape_str_attribute(recipient),
+            message_id,
+            escape_str_pcdata(&xml_text(body)),
+            ping_id,
+            NS_PING
+        ))
+        .map_err(SendError::Temporary)?;
+        loop {
+            let element = self.read_element().map_err(SendError::Temporary)?;
+            if element.name == "message"
+                && element.attribute("id") == Some(message_id.as_str())
+                && element.attribute("type") == Some("error")
+            {
+                return Err(SendError::Permanent(format!(
+                    "Server rejected the message: {}",
+                    error_condition(&element)
+                )));
+            }
+            // answered with an error, if the server does not support pings
+            if element.name == "iq" && element.attribute("id") == Some(ping_id.as_str()) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn close(mut self) {
+        let _ = self.write("</stream:stream>");
+    }
+}
+
+/// The condition of an error stanza, e.g. `item-not-found`.
+fn error_condition(stanza: &Element) -> &str {
+    stanza
+        .children
+        .iter()
+        .find(|child| child.name == "error")
+        .map(Element::first_child_name)
+        .unwrap_or("unknown")
+}
+
+pub struct XmppDestination {
+    log_target: String,
+    config: XmppDestinationConfig,
+    worker: Option<thread::JoinHandle<()>>,
+}
+impl XmppDestination {
+    pub fn new(name: String, config: &XmppDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Xmpp[{}]", name),
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    /// The chat message for the mail: its subject and sender, followed by the beginning of
+    /// its text.
+    fn build_message(data: &[u8]) -> String {
+        let message = mime::Entity::parse(data);
+        let header = |name| {
+            message
+                .header(name)
+                .map(|value| mime::decode_header_words(&value))
+                .unwrap_or_default()
+        };
+        let subject = match header("Subject") {
+            subject if subject.trim().is_empty() => "(no subject)".to_owned(),
+            subject => subject,
+        };
+        let mut body = subject.trim().to_owned();
+        let from = header("From");
+        if !from.trim().is_empty() {
+            body.push_str("\nFrom: ");
+            body.push_str(from.trim());
+        }
+        let text = message.text_content().unwrap_or_default();
+        if !text.trim().is_empty() {
+            body.push_str("\n\n");
+            body.push_str(&truncate(text.trim(), TEXT_MAX_LEN));
+        }
+        body
+    }
+
+    /// Send the message over the session, which is established first if there is none. A
+    /// session that was idle might have been closed by the server meanwhile, so sending is
+    /// tried once more on a new one.
+    fn send(
+        session: &mut Option<XmppSession>,
+        config: &XmppDestinationConfig,
+        body: &str,
+        log_target: &str,
+    ) -> Result<(), SendError> {
+        for attempt in 0..2 {
+            let established = session.is_some();
+            let current = match session {
+                Some(current) => current,
+                None => {
+                    debug!(target: log_target, "Connecting");
+                    session.insert(XmppSession::connect(config).map_err(SendError::Temporary)?)
+                }
+            };
+            match current.send_message(&config.recipient, body) {
+                Ok(()) => return Ok(()),
+                Err(SendError::Temporary(err)) if established && attempt == 0 => {
+                    debug!(target: log_target, "Session lost, reconnecting: {}", err);
+                    *session = None;
+                }
+                Err(err) => {
+                    if let SendError::Temporary(_) = err {
+                        *session = None;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        unreachable!("The second attempt always returns");
+    }
+}
+impl MailAgent for XmppDestination {
+    fn join(&mut self) {
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("Thread exited with errors");
+    }
+}
+impl MailDestination for XmppDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        self.worker = Some(thread::spawn(move || {
+            // kept open between mails
+            let mut session = None;
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+                let body = XmppDestination::build_message(&mail.data);
+                match XmppDestination::send(&mut session, &config, &body, &log_target) {
+                    Ok(()) => {
+                        info!(target: &log_target, "Successfully sent mail {}", mail);
+                        channel.notify_sent(mail, format!("Sent to {}", config.recipient));
+                    }
+                    Err(SendError::Permanent(err)) => {
+                        warn!(target: &log_target, "Server does not accept mail {}, will not try again:\n{}", mail, err);
+                        channel.notify_rejected(mail, err);
+                    }
+                    Err(SendError::Temporary(err)) => {
+                        error!(target: &log_target, "Error while sending mail {}:\n{}", mail, err);
+                        channel.notify_failed_send(mail);
+                    }
+                }
+            }
+            if let Some(session) = session {
+                session.close();
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_element() {
+        let stream =
+            "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>\
+            <message from='a@example.org' type='error' id='m1'><error type='cancel'>\
+            <item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></message>\
+            <iq type='result' id='p1'/>";
+        let mut reader = EventReader::new(stream.as_bytes());
+        assert!(matches!(reader.next(), Ok(XmlEvent::StartDocument { .. })));
+        assert!(matches!(reader.next(), Ok(XmlEvent::StartElement { .. })));
+        let message = read_element(&mut reader).unwrap();
+        assert!(message.is("message", "jabber:client"));
+        assert_eq!(message.attribute("id"), Some("m1"));
+        assert_eq!(error_condition(&message), "item-not-found");
+        let iq = read_element(&mut reader).unwrap();
+        assert_eq!(
+            (iq.name.as_str(), iq.attribute("type")),
+            ("iq", Some("result"))
+        );
+        assert!(read_element(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_build_message() {
+        let data = b"Subject: =?utf-8?q?Gr=C3=BC=C3=9Fe?=\r\nFrom: Alice <alice@example.org>\r\n\r\nHello\x07 <world>\r\n";
+        let body = XmppDestination::build_message(data);
+        assert_eq!(
+            body,
+            "Gr\u{fc}\u{df}e\nFrom: Alice <alice@example.org>\n\nHello\x07 <world>"
+        );
+        assert_eq!(
+            escape_str_pcdata(&xml_text(&body)),
+            "Gr\u{fc}\u{df}e\nFrom: Alice &lt;alice@example.org&gt;\n\nHello &lt;world&gt;"
+        );
+    }
+}
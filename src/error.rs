@@ -0,0 +1,30 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Environment variable that enables verbose errors, independent of the configuration.
+pub const VERBOSE_ERRORS_ENV: &str = "IDLEMAIL_VERBOSE_ERRORS";
+
+static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Log the complete chain of causes and a backtrace for every error, instead of
+/// only its outermost context. Has to be called before any errors are created.
+pub fn enable_verbose_errors() {
+    VERBOSE_ERRORS.store(true, Ordering::Relaxed);
+    if std::env::var_os("RUST_LIB_BACKTRACE").is_none() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "1");
+    }
+}
+
+/// Formats an error for the log, according to the configured verbosity.
+pub struct ErrorReport<'a>(pub &'a anyhow::Error);
+impl fmt::Display for ErrorReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if VERBOSE_ERRORS.load(Ordering::Relaxed) {
+            write!(f, "{:?}", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
@@ -1,39 +1,297 @@
 use super::config::{ConfigContainer, DestinationConfig, SourceConfig};
+#[cfg(unix)]
+use crate::destinations::fifo::FifoDestination;
 use crate::{
-    config::RetryAgentConfig,
+    config::{
+        AutoSubmittedHandling, CircuitBreakerConfig, DestinationCommonConfig, FailurePolicy,
+        MappingTarget, ParseErrorPolicy, RateLimitExcess, ReplyToExisting, RetryAgentConfig,
+        SourceCommonConfig, Transformation,
+    },
     destinations::{
-        exec::ExecDestination, smtp::SmtpDestination, testdst::TestDestination, MailDestination,
+        archive::ArchiveDestination, discord::DiscordDestination, exec::ExecDestination,
+        sendmail::SendmailDestination, smtp::SmtpDestination, testdst::TestDestination,
+        xmpp::XmppDestination, MailDestination,
     },
+    mime,
     retryagents::{filesystem::FilesystemRetryAgent, memory::MemoryRetryAgent, MailRetryAgent},
     sources::{
         imap_idle::ImapIdleSource, imap_poll::ImapPollSource, testsrc::TestSource, MailSource,
+        SharedConnections,
     },
 };
 use async_std::{channel as async_mpsc, future::timeout as await_timeout, task};
-use log::{info, warn};
+use lettre::message::{
+    header::{ContentDisposition, ContentTransferEncoding, ContentType},
+    Body, SinglePart,
+};
+use log::{debug, error, info, log_enabled, warn, Level};
 use mpsc::RecvError;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    fmt, fs,
     hash::{Hash, Hasher},
-    sync::mpsc,
-    time::Duration,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
 };
 
+/// Source name of the mails announcing that idlemail started or stops, see
+/// `lifecycle_destination`. Destinations do not filter them by their allowed sources.
+pub const LIFECYCLE_SOURCE: &str = "idlemail";
+/// Source of the dead letters delivered once more with `MailHub::replay_dead_letters`
+const DEAD_LETTER_SOURCE: &str = "dead-letter";
+/// Subjects are cut to this many characters in log lines
+const IDENT_SUBJECT_MAX_LEN: usize = 80;
+/// Bytes at the start of a mail's text searched by body patterns of the mappings.
+/// Regular expressions run in linear time, but huge mails would still stall the hub.
+const BODY_SCAN_MAX_LEN: usize = 256 * 1024;
+/// Headers naming the addresses a mail was sent to, see `Mail::recipients`
+const RECIPIENT_HEADERS: [&str; 4] = ["Delivered-To", "X-Original-To", "To", "Cc"];
+/// Headers requesting a read receipt from the recipient's mail client
+const RECEIPT_HEADERS: [&str; 3] = [
+    "Disposition-Notification-To",
+    "Disposition-Notification-Options",
+    "Return-Receipt-To",
+];
+
+/// A mail on its way through the hub.
+/// The raw message is shared between all clones, so distributing a mail to multiple
+/// destinations (or queueing it for retry) does not copy the message.
 #[derive(Clone, Debug)]
 pub struct Mail {
     pub from_src: String,
-    pub data: Vec<u8>,
+    /// `/`-delimited path of the folder the mail was retrieved from, if the source knows folders
+    pub folder: Option<String>,
+    pub data: Arc<[u8]>,
     pub hash: String,
+    /// Value of the Message-ID header, if the mail has one
+    pub message_id: Option<String>,
+    /// Message-ID and Subject, to identify the mail in logs. See `Display`.
+    pub ident: String,
 }
 impl Mail {
-    pub fn from_rfc822(srcname: String, body: Vec<u8>) -> Self {
+    pub fn from_rfc822<B: Into<Arc<[u8]>>>(srcname: String, body: B) -> Self {
+        let body = body.into();
         let mut hasher = DefaultHasher::new();
         body.hash(&mut hasher);
+        let hash = hasher.finish().to_string();
+        let message = mime::Entity::parse_headers(&body);
+        let message_id = message
+            .header("Message-ID")
+            .map(|message_id| message_id.trim().to_owned())
+            .filter(|message_id| !message_id.is_empty());
         Self {
             from_src: srcname,
+            folder: None,
+            ident: Self::parse_ident(&message, message_id.as_deref(), &hash),
+            message_id,
             data: body,
-            hash: hasher.finish().to_string(),
+            hash,
+        }
+    }
+
+    fn parse_ident(message: &mime::Entity, message_id: Option<&str>, hash: &str) -> String {
+        let message_id = match message_id {
+            Some(message_id) => message_id.to_owned(),
+            None => format!("(no Message-ID, hash {})", hash),
+        };
+        match message.header("Subject") {
+            Some(subject) => {
+                let subject = mime::decode_header_words(&subject);
+                let subject = subject.trim();
+                if subject.chars().count() > IDENT_SUBJECT_MAX_LEN {
+                    let subject: String = subject.chars().take(IDENT_SUBJECT_MAX_LEN).collect();
+                    format!("{} \"{}…\"", message_id, subject)
+                } else {
+                    format!("{} \"{}\"", message_id, subject)
+                }
+            }
+            None => message_id,
+        }
+    }
+
+    /// Copy of this mail with the given header prepended to the raw message.
+    pub fn with_header(&self, name: &str, value: &str) -> Self {
+        let newline = if self.data.windows(2).any(|w| w == b"\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let mut data = format!("{}: {}{}", name, value, newline).into_bytes();
+        data.extend_from_slice(&self.data);
+        self.with_data(data)
+    }
+
+    /// Copy of this mail with a Message-ID derived from its content, if it has none.
+    /// The same mail gets the same Message-ID when it is fetched again.
+    pub fn with_generated_message_id(&self, domain: &str) -> Self {
+        if self.message_id.is_some() {
+            return self.clone();
+        }
+        let digest = Sha256::digest(&self.data);
+        let local: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        self.with_header("Message-ID", &format!("<{}.idlemail@{}>", local, domain))
+    }
+
+    /// Whether the mail was sent automatically, e.g. by a vacation responder or as a bounce,
+    /// according to its `Auto-Submitted` header (RFC 3834).
+    pub fn is_auto_submitted(&self) -> bool {
+        let Some(value) = mime::Entity::parse_headers(&self.data).header("Auto-Submitted") else {
+            return false;
+        };
+        // e.g. `auto-replied; owner-email="me@example.org"`, optionally with comments
+        let keyword = value.split([';', '(']).next().unwrap_or_default().trim();
+        !keyword.is_empty() && !keyword.eq_ignore_ascii_case("no")
+    }
+
+    /// Identifier of the mailing list the mail was sent to, from its `List-Id` header
+    /// (RFC 2919), e.g. `rust-users.lists.example.org`.
+    pub fn list_id(&self) -> Option<String> {
+        let value = mime::Entity::parse_headers(&self.data).header("List-Id")?;
+        // the identifier is enclosed in angle brackets, after an optional description
+        let list_id = match (value.rfind('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value.as_str(),
+        };
+        let list_id = list_id.trim();
+        (!list_id.is_empty()).then(|| list_id.to_owned())
+    }
+
+    /// Addresses the mail was sent to, lowercased. Besides `To` and `Cc`, the headers added by
+    /// the receiving server are included, since a catch-all mailbox receives mails for
+    /// addresses that are only named in `Bcc`.
+    pub fn recipients(&self) -> Vec<String> {
+        let message = mime::Entity::parse_headers(&self.data);
+        let mut recipients: Vec<String> = RECIPIENT_HEADERS
+            .iter()
+            .flat_map(|name| message.headers(name))
+            .flat_map(|value| mime::parse_addresses(&value))
+            .collect();
+        let mut seen = HashSet::new();
+        recipients.retain(|recipient| seen.insert(recipient.clone()));
+        recipients
+    }
+
+    /// The decoded text of the mail (see `mime::Entity::text_content`), cut to at most
+    /// `max_len` bytes.
+    pub fn body_text(&self, max_len: usize) -> Option<String> {
+        let mut text = mime::Entity::parse(&self.data).text_content()?;
+        if text.len() > max_len {
+            let end = (0..=max_len)
+                .rev()
+                .find(|i| text.is_char_boundary(*i))
+                .unwrap_or_default();
+            text.truncate(end);
+        }
+        Some(text)
+    }
+
+    /// Copy of this mail without headers requesting read receipts.
+    pub fn without_receipt_requests(&self) -> Self {
+        let mut message = mime::Entity::parse(&self.data);
+        let mut removed = false;
+        for name in RECEIPT_HEADERS {
+            removed |= message.remove_header(name);
+        }
+        if removed {
+            self.with_data(message.to_bytes())
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Copy of this mail with its Reply-To set to `reply_to`, in which `{from}` is replaced
+    /// with the mail's From header. Without From, or if the mail has a Reply-To that is to be
+    /// preserved, the mail is left unchanged.
+    pub fn with_reply_to(&self, reply_to: &str, existing: ReplyToExisting) -> Self {
+        let mut message = mime::Entity::parse(&self.data);
+        if existing == ReplyToExisting::Preserve && message.header("Reply-To").is_some() {
+            return self.clone();
         }
+        let reply_to = if reply_to.contains("{from}") {
+            match message.header("From") {
+                Some(from) => reply_to.replace("{from}", from.trim()),
+                None => return self.clone(),
+            }
+        } else {
+            reply_to.to_owned()
+        };
+        message.set_header("Reply-To", &reply_to);
+        self.with_data(message.to_bytes())
+    }
+
+    /// Copy of this mail with `footer` appended to its text, see `mime::Entity::add_footer`.
+    /// `{source}` and `{folder}` in the footer are replaced with where the mail came from.
+    pub fn with_footer(&self, footer: &str) -> Self {
+        let footer = footer
+            .replace("{source}", &self.from_src)
+            .replace("{folder}", self.folder.as_deref().unwrap_or_default());
+        let mut message = mime::Entity::parse(&self.data);
+        if message.add_footer(&footer) {
+            self.with_data(message.to_bytes())
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Copy of this mail with the transformation applied. Yields multiple mails, if the
+    /// transformation splits the mail.
+    pub fn transformed(&self, transformation: &Transformation) -> Vec<Self> {
+        let transformed = match transformation {
+            Transformation::AddHeader { name, value } => {
+                self.with_header(name, &mime::encode_header_words(value))
+            }
+            Transformation::StripAttachments => {
+                let mut message = mime::Entity::parse(&self.data);
+                if message.strip_attachments() {
+                    self.with_data(message.to_bytes())
+                } else {
+                    self.clone()
+                }
+            }
+            Transformation::SubjectPrefix { prefix } => {
+                let mut message = mime::Entity::parse(&self.data);
+                let subject = message.header("Subject").unwrap_or_default();
+                let subject = format!("{}{}", mime::encode_header_words(prefix), subject);
+                message.set_header("Subject", &subject);
+                self.with_data(message.to_bytes())
+            }
+            Transformation::ExplodeDigest => {
+                return match mime::Entity::parse(&self.data).digest_messages() {
+                    Some(messages) => messages
+                        .into_iter()
+                        .map(|message| self.with_data(message))
+                        .collect(),
+                    None => vec![self.clone()],
+                };
+            }
+        };
+        vec![transformed]
+    }
+
+    fn with_data(&self, data: Vec<u8>) -> Self {
+        Self {
+            folder: self.folder.clone(),
+            ..Self::from_rfc822(self.from_src.clone(), data)
+        }
+    }
+
+    pub fn with_folder(mut self, folder: String) -> Self {
+        self.folder = Some(folder);
+        self
+    }
+
+    /// Reader over the raw message, for destinations that stream the mail in chunks.
+    pub fn reader(&self) -> impl Read + '_ {
+        &self.data[..]
+    }
+}
+
+impl fmt::Display for Mail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.ident)
     }
 }
 
@@ -49,57 +307,158 @@ pub enum HubMessage {
     SendingMailFailed {
         dstname: String,
         mail: Mail,
+        /// Set if the destination must not be retried before this delay passed
+        min_delay: Option<Duration>,
+    },
+    /// The destination delivered the mail. `response` describes the result, e.g. a status code
+    MailSent {
+        dstname: String,
+        mail: Mail,
+        response: String,
+    },
+    /// The destination refused the mail permanently, so it is not retried
+    MailRejected {
+        dstname: String,
+        mail: Mail,
+        reason: String,
     },
     Shutdown,
     /// Message sent by the RetryAgent to confirm successfull suspension
     RetryAgentSuspended,
+    /// Final message of the RetryAgent, about the mails it still had queued when it stopped
+    RetryAgentStopped {
+        /// Mails kept in persistent storage, for the next run
+        persisted: usize,
+        /// Mails that were lost, because they could not be stored
+        lost: usize,
+    },
 }
+/// Limits how many connections the sources keep open at the same time, across the whole hub.
+/// Sources with many mailboxes at one provider would otherwise exceed its connection limit.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    /// Every queued item is a taken permit
+    permits: Option<(async_mpsc::Sender<()>, async_mpsc::Receiver<()>)>,
+}
+impl ConnectionLimit {
+    pub fn new(max_connections: Option<usize>) -> Self {
+        Self {
+            permits: max_connections.map(async_mpsc::bounded),
+        }
+    }
+
+    /// Wait until a connection may be established. The returned permit is held as long as
+    /// the connection is open, another one may be established as soon as it is dropped.
+    pub async fn acquire(&self) -> ConnectionPermit {
+        match &self.permits {
+            Some((sender, receiver)) => {
+                sender.send(()).await.expect("Permits are never closed");
+                ConnectionPermit(Some(receiver.clone()))
+            }
+            None => ConnectionPermit(None),
+        }
+    }
+
+    /// Like `acquire`, but returns `None` instead of waiting if no connection may be
+    /// established right now.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        match &self.permits {
+            Some((sender, receiver)) => {
+                sender.try_send(()).ok()?;
+                Some(ConnectionPermit(Some(receiver.clone())))
+            }
+            None => Some(ConnectionPermit(None)),
+        }
+    }
+}
+#[derive(Debug)]
+pub struct ConnectionPermit(Option<async_mpsc::Receiver<()>>);
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(receiver) = &self.0 {
+            let _ = receiver.try_recv();
+        }
+    }
+}
+
 pub struct HubChannel {
     sender: mpsc::Sender<HubMessage>,
     recv: mpsc::Receiver<HubMessage>,
     destinations: HashMap<String, mpsc::Sender<DestinationMessage>>,
     sources: HashMap<String, async_mpsc::Sender<SourceMessage>>,
-    retryagent_sender: Option<mpsc::Sender<RetryAgentMessage>>,
-    retryagent_recv: Option<mpsc::Receiver<RetryAgentMessage>>,
+    /// Running RetryAgents, by their name in `retryagents` (`None` for `retryagent`)
+    retryagents: HashMap<Option<String>, mpsc::Sender<RetryAgentMessage>>,
+    /// Named RetryAgent of every destination that has one. The others use `retryagent`.
+    destination_retryagents: HashMap<String, String>,
+    connection_limit: ConnectionLimit,
+    shared_connections: SharedConnections,
 }
 impl HubChannel {
-    pub fn new() -> Self {
+    pub fn new(
+        connection_limit: ConnectionLimit,
+        destination_retryagents: HashMap<String, String>,
+    ) -> Self {
         let (main_sender, main_recv) = mpsc::channel();
-        let (retryagent_sender, retryagent_recv) = mpsc::channel();
         Self {
             sender: main_sender,
             recv: main_recv,
             destinations: HashMap::new(),
             sources: HashMap::new(),
-            retryagent_sender: Some(retryagent_sender),
-            retryagent_recv: Some(retryagent_recv),
+            retryagents: HashMap::new(),
+            destination_retryagents,
+            connection_limit,
+            shared_connections: SharedConnections::default(),
         }
     }
 
     pub fn next(&self) -> HubMessage {
         self.recv.recv().unwrap()
     }
+    pub fn next_timeout(&self, timeout: Duration) -> Option<HubMessage> {
+        self.recv.recv_timeout(timeout).ok()
+    }
     pub fn try_next(&self) -> Option<HubMessage> {
         self.recv.try_recv().ok()
     }
 
-    pub fn queue_mail_for_sending(&self, dstname: &str, mail: Mail) -> Result<(), ()> {
-        let dst_comm = self.destinations.get(dstname).ok_or(())?;
+    /// Returns the mail, if the destination is not running (anymore).
+    pub fn queue_mail_for_sending(&self, dstname: &str, mail: Mail) -> Result<(), Box<Mail>> {
+        let dst_comm = match self.destinations.get(dstname) {
+            Some(dst_comm) => dst_comm,
+            None => return Err(Box::new(mail)),
+        };
         dst_comm
             .send(DestinationMessage::Mail { mail })
-            .map_err(|_| ())
+            .map_err(|mpsc::SendError(DestinationMessage::Mail { mail })| Box::new(mail))
     }
 
-    pub fn queue_mail_for_retry(&self, dstname: String, mail: Mail) {
-        if self
-            .retryagent_sender
-            .as_ref()
-            .unwrap()
-            .send(RetryAgentMessage::QueueMail { dstname, mail })
+    /// Returns `false` if the mail could not be queued, and is thus lost.
+    pub fn queue_mail_for_retry(
+        &self,
+        dstname: String,
+        mail: Mail,
+        min_delay: Option<Duration>,
+    ) -> bool {
+        let retryagent = self.destination_retryagents.get(&dstname).cloned();
+        let sender = match self.retryagents.get(&retryagent) {
+            Some(sender) => sender,
+            None => {
+                error!(target: "HubChannel", "No RetryAgent running, mail {} for destination {} is lost", mail, dstname);
+                return false;
+            }
+        };
+        if sender
+            .send(RetryAgentMessage::QueueMail {
+                dstname,
+                mail,
+                min_delay,
+            })
             .is_err()
         {
-            warn!(target: "HubChannel", "Failed to queue mail for retransmission. Either no RetryAgent configured, or a bug.");
+            warn!(target: "HubChannel", "Failed to queue mail for retransmission. The RetryAgent stopped early, this is a bug.");
+            return false;
         }
+        true
     }
 
     pub fn shutdown_sources(&mut self) {
@@ -110,17 +469,15 @@ impl HubChannel {
         info!(target: "HubChannel", "Signaling shutdown to destinations");
         self.destinations.clear();
     }
-    pub fn suspend_retryagent(&mut self) {
-        info!(target: "HubChannel", "Suspending retryagent");
-        let _ = self
-            .retryagent_sender
-            .as_ref()
-            .unwrap()
-            .send(RetryAgentMessage::Suspend);
+    pub fn suspend_retryagents(&mut self) {
+        info!(target: "HubChannel", "Suspending retryagents");
+        for sender in self.retryagents.values() {
+            let _ = sender.send(RetryAgentMessage::Suspend);
+        }
     }
-    pub fn shutdown_retryagent(&mut self) {
-        info!(target: "HubChannel", "Signaling shutdown to retryagent");
-        drop(self.retryagent_sender.take());
+    pub fn shutdown_retryagents(&mut self) {
+        info!(target: "HubChannel", "Signaling shutdown to retryagents");
+        self.retryagents.clear();
     }
 
     pub fn get_stop_channel(&self) -> HubStopSender {
@@ -144,12 +501,16 @@ impl HubChannel {
             name,
             sender: self.sender.clone(),
             recv: src_recv,
+            connection_limit: self.connection_limit.clone(),
+            shared_connections: self.shared_connections.clone(),
         }
     }
-    pub fn get_retryagent_channel(&mut self) -> HubRetryAgentChannel {
+    pub fn get_retryagent_channel(&mut self, name: Option<String>) -> HubRetryAgentChannel {
+        let (retryagent_send, retryagent_recv) = mpsc::channel();
+        self.retryagents.insert(name, retryagent_send);
         HubRetryAgentChannel {
             sender: self.sender.clone(),
-            recv: self.retryagent_recv.take().unwrap(),
+            recv: retryagent_recv,
         }
     }
 }
@@ -176,11 +537,75 @@ impl HubDestinationChannel {
         self.recv.recv()
     }
 
+    /// For reporting the outcome of deliveries from other threads than the one receiving mails.
+    pub fn notifier(&self) -> DestinationNotifier {
+        DestinationNotifier {
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+
+    pub fn notify_failed_send(&self, mail: Mail) {
+        self.notifier().notify_failed_send(mail);
+    }
+
+    /// Like `notify_failed_send`, but the mail must not be retried before `min_delay` passed,
+    /// e.g. because the server asked to come back later.
+    pub fn notify_deferred(&self, mail: Mail, min_delay: Duration) {
+        self.notifier().notify_deferred(mail, min_delay);
+    }
+
+    pub fn notify_sent(&self, mail: Mail, response: String) {
+        self.notifier().notify_sent(mail, response);
+    }
+
+    pub fn notify_rejected(&self, mail: Mail, reason: String) {
+        self.notifier().notify_rejected(mail, reason);
+    }
+}
+
+#[derive(Clone)]
+pub struct DestinationNotifier {
+    name: String,
+    sender: mpsc::Sender<HubMessage>,
+}
+impl DestinationNotifier {
     pub fn notify_failed_send(&self, mail: Mail) {
         self.sender
             .send(HubMessage::SendingMailFailed {
                 dstname: self.name.clone(),
                 mail,
+                min_delay: None,
+            })
+            .unwrap();
+    }
+
+    pub fn notify_deferred(&self, mail: Mail, min_delay: Duration) {
+        self.sender
+            .send(HubMessage::SendingMailFailed {
+                dstname: self.name.clone(),
+                mail,
+                min_delay: Some(min_delay),
+            })
+            .unwrap();
+    }
+
+    pub fn notify_sent(&self, mail: Mail, response: String) {
+        self.sender
+            .send(HubMessage::MailSent {
+                dstname: self.name.clone(),
+                mail,
+                response,
+            })
+            .unwrap();
+    }
+
+    pub fn notify_rejected(&self, mail: Mail, reason: String) {
+        self.sender
+            .send(HubMessage::MailRejected {
+                dstname: self.name.clone(),
+                mail,
+                reason,
             })
             .unwrap();
     }
@@ -191,8 +616,16 @@ pub struct HubSourceChannel {
     pub(crate) name: String,
     pub(crate) sender: mpsc::Sender<HubMessage>,
     pub(crate) recv: async_mpsc::Receiver<SourceMessage>,
+    pub(crate) connection_limit: ConnectionLimit,
+    pub(crate) shared_connections: SharedConnections,
 }
 impl HubSourceChannel {
+    pub fn connection_limit(&self) -> ConnectionLimit {
+        self.connection_limit.clone()
+    }
+    pub fn shared_connections(&self) -> SharedConnections {
+        self.shared_connections.clone()
+    }
     pub async fn next(&self) -> Option<SourceMessage> {
         self.recv.recv().await.ok()
     }
@@ -218,6 +651,8 @@ pub enum RetryAgentMessage {
     QueueMail {
         dstname: String,
         mail: Mail,
+        /// Retry no earlier than this, even if the RetryAgent's delay is shorter
+        min_delay: Option<Duration>,
     },
     /// Sending this message to a running RetryAgent suspends its re-submission attempts.
     /// This means, that the RetryAgent will still receive and handle incomming messages
@@ -246,30 +681,245 @@ impl HubRetryAgentChannel {
     pub fn confirm_suspension(&self) {
         self.sender.send(HubMessage::RetryAgentSuspended).unwrap();
     }
+    /// Report the state of the queue, right before the RetryAgent stops.
+    pub fn report_shutdown(&self, persisted: usize, lost: usize) {
+        let _ = self
+            .sender
+            .send(HubMessage::RetryAgentStopped { persisted, lost });
+    }
 }
 
 pub trait MailAgent {
     fn join(&mut self);
 }
 
+enum Admission {
+    Deliver,
+    /// First delivery after the cooldown, which decides whether the circuit closes again
+    Probe,
+    /// The circuit is open, the mail goes to the RetryAgent without a delivery attempt
+    Hold,
+}
+
+/// Stops delivery attempts to a destination that keeps failing, see `CircuitBreakerConfig`.
+struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    consecutive_failures: u32,
+    /// Set while the circuit is open
+    open_until: Option<Instant>,
+    /// Set while the probe after the cooldown is in flight
+    probing: bool,
+}
+impl CircuitBreaker {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            consecutive_failures: 0,
+            open_until: None,
+            probing: false,
+        }
+    }
+
+    fn admit(&mut self) -> Admission {
+        match self.open_until {
+            None => Admission::Deliver,
+            Some(_) if self.probing => Admission::Hold,
+            Some(open_until) if Instant::now() >= open_until => {
+                self.probing = true;
+                Admission::Probe
+            }
+            Some(_) => Admission::Hold,
+        }
+    }
+
+    /// Returns whether the circuit (re)opened because of this failure.
+    fn record_failure(&mut self) -> bool {
+        self.consecutive_failures += 1;
+        let threshold_reached =
+            self.open_until.is_none() && self.consecutive_failures >= self.config.failure_threshold;
+        if !threshold_reached && !self.probing {
+            return false;
+        }
+        self.open_until = Some(Instant::now() + Duration::from_secs(self.config.cooldown));
+        self.probing = false;
+        true
+    }
+
+    /// Returns whether the circuit was open before.
+    fn record_success(&mut self) -> bool {
+        let was_open = self.open_until.is_some();
+        self.consecutive_failures = 0;
+        self.open_until = None;
+        self.probing = false;
+        was_open
+    }
+}
+
+/// What happened to the mails during one run, logged at shutdown.
+#[derive(Default)]
+struct RunStatistics {
+    received: usize,
+    delivered: usize,
+    rejected: usize,
+    failed_attempts: usize,
+    /// Mails for which queueing a retry failed
+    lost: usize,
+    /// Failed mails stored in the `dead_letter_dir`
+    dead_letters: usize,
+    /// Failed mails discarded, as configured with `on_delivery_failure`
+    dropped: usize,
+    /// New mails discarded, because their source exceeded its `rate_limit`
+    rate_limited: usize,
+    /// Reported by the RetryAgents when they stopped, summed up
+    retry_queue: Option<(usize, usize)>,
+    /// Mails received and delivered, by source and folder, for sources with `folder_statistics`
+    folders: HashMap<(String, String), (usize, usize)>,
+}
+impl RunStatistics {
+    fn log_report(&self, has_retryagent: bool) {
+        let mut report = format!(
+            "Shutdown report:\n  mails received: {}\n  deliveries:     {} succeeded, {} rejected, {} failed attempts",
+            self.received, self.delivered, self.rejected, self.failed_attempts
+        );
+        let mut lost = self.lost;
+        match self.retry_queue {
+            Some((persisted, retry_lost)) => {
+                report += &format!(
+                    "\n  retry queue:    {} persisted for the next run, {} lost",
+                    persisted, retry_lost
+                );
+                lost += retry_lost;
+            }
+            None if has_retryagent => {
+                report += "\n  retry queue:    unknown, RetryAgent did not report"
+            }
+            None => report += "\n  retry queue:    no RetryAgent configured",
+        }
+        if self.dead_letters > 0 {
+            report += &format!("\n  dead letters:   {}", self.dead_letters);
+        }
+        if self.dropped > 0 {
+            report += &format!("\n  mails dropped:  {}", self.dropped);
+        }
+        if self.rate_limited > 0 {
+            report += &format!("\n  over rate limit: {}", self.rate_limited);
+        }
+        report += &format!("\n  mails lost:     {}", lost);
+        if !self.folders.is_empty() {
+            let mut folders: Vec<_> = self.folders.iter().collect();
+            // the most active folders first
+            folders.sort_by(|(a_name, a), (b_name, b)| b.0.cmp(&a.0).then(a_name.cmp(b_name)));
+            report += "\n  folders:";
+            for ((srcname, folder), (received, delivered)) in folders {
+                report += &format!(
+                    "\n    {} {}: {} received, {} deliveries",
+                    srcname, folder, received, delivered
+                );
+            }
+        }
+        if lost > 0 {
+            warn!(target: "MailHub", "{}", report);
+        } else {
+            info!(target: "MailHub", "{}", report);
+        }
+    }
+}
+
+/// Destination name as used in the file names of dead letters
+fn dead_letter_prefix(dstname: &str) -> String {
+    dstname
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Store a mail for `dstname` as `.eml` file in `dir`, and return its path.
+fn write_dead_letter(dir: &Path, dstname: &str, mail: &Mail) -> std::io::Result<PathBuf> {
+    let path = dir.join(format!("{}-{}.eml", dead_letter_prefix(dstname), mail.hash));
+    fs::write(&path, &mail.data)?;
+    Ok(path)
+}
+
+/// Destination a dead letter was stored for, from its file name `<destination>-<hash>.eml`
+fn dead_letter_destination<'a>(
+    file_name: &str,
+    dstnames: impl Iterator<Item = &'a String>,
+) -> Option<&'a String> {
+    let stem = file_name.strip_suffix(".eml")?;
+    let mut matching = dstnames.filter(|dstname| {
+        stem.strip_prefix(&dead_letter_prefix(dstname))
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|hash| !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_digit()))
+    });
+    match (matching.next(), matching.next()) {
+        (Some(dstname), None) => Some(dstname),
+        // names that only differ in replaced characters can not be told apart
+        _ => None,
+    }
+}
+
 pub struct MailHub {
     destination_agents: HashMap<String, Box<dyn MailDestination>>,
     source_agents: HashMap<String, Box<dyn MailSource>>,
-    retryagent: Option<Box<dyn MailRetryAgent>>,
-    mappings: HashMap<String, Vec<String>>,
+    /// By their name in `retryagents`, `None` for `retryagent`
+    retryagents: HashMap<Option<String>, Box<dyn MailRetryAgent>>,
+    mappings: HashMap<String, Vec<MappingTarget>>,
+    destination_options: HashMap<String, DestinationCommonConfig>,
+    source_options: HashMap<String, SourceCommonConfig>,
     hubchannel: HubChannel,
+    statistics: RunStatistics,
+    circuit_breakers: HashMap<String, CircuitBreaker>,
+    /// Deliveries held back during the quiet hours of their source, or by its `rate_limit`,
+    /// with the time they are released
+    held: Vec<(Instant, String, Mail)>,
+    /// Times the recent mails of sources with a `rate_limit` were (or will be) forwarded
+    rate_windows: HashMap<String, VecDeque<Instant>>,
+    /// Mails collected for destinations with `debounce`, by destination and source, with
+    /// the end of the window
+    debounced: HashMap<(String, String), (Instant, Vec<Mail>)>,
+    /// Destination notified when idlemail starts and stops
+    lifecycle_destination: Option<String>,
+    /// Domain of the Message-IDs added to mails without one
+    message_id_domain: Option<String>,
+    on_delivery_failure: FailurePolicy,
+    dead_letter_dir: Option<PathBuf>,
+    /// Mails delivered by the `test` destinations
+    #[cfg(test)]
+    test_deliveries: HashMap<String, Arc<std::sync::Mutex<Vec<Mail>>>>,
 }
 impl MailHub {
-    pub fn from_config(config: &ConfigContainer) -> Self {
+    pub fn from_config(config: &ConfigContainer) -> anyhow::Result<Self> {
         let mut destination_agents = HashMap::new();
+        let mut destination_options = HashMap::new();
         let mut source_agents = HashMap::new();
-        let hubchannel = HubChannel::new();
+        let mut source_options = HashMap::new();
+        let mut circuit_breakers = HashMap::new();
+        let destination_retryagents = config
+            .destinations
+            .iter()
+            .filter_map(|(dstname, dst)| Some((dstname.clone(), dst.common.retryagent.clone()?)))
+            .collect();
+        let hubchannel = HubChannel::new(
+            ConnectionLimit::new(config.max_connections),
+            destination_retryagents,
+        );
+        #[cfg(test)]
+        let mut test_deliveries = HashMap::new();
 
         // Create destinations
-        for (dstname, dstcfg) in &config.destinations {
-            let destination_agent: Box<dyn MailDestination> = match dstcfg {
+        for (dstname, dstentry) in &config.destinations {
+            let destination_agent: Box<dyn MailDestination> = match &dstentry.config {
                 DestinationConfig::Test(config) => {
-                    Box::new(TestDestination::new(dstname.clone(), config))
+                    let destination = TestDestination::new(dstname.clone(), config);
+                    #[cfg(test)]
+                    test_deliveries.insert(dstname.clone(), destination.delivered());
+                    Box::new(destination)
                 }
                 DestinationConfig::Smtp(config) => {
                     Box::new(SmtpDestination::new(dstname.clone(), config))
@@ -277,13 +927,33 @@ impl MailHub {
                 DestinationConfig::Exec(config) => {
                     Box::new(ExecDestination::new(dstname.clone(), config))
                 }
+                DestinationConfig::Discord(config) => {
+                    Box::new(DiscordDestination::new(dstname.clone(), config))
+                }
+                DestinationConfig::Sendmail(config) => {
+                    Box::new(SendmailDestination::new(dstname.clone(), config))
+                }
+                #[cfg(unix)]
+                DestinationConfig::Fifo(config) => {
+                    Box::new(FifoDestination::new(dstname.clone(), config))
+                }
+                DestinationConfig::Archive(config) => {
+                    Box::new(ArchiveDestination::new(dstname.clone(), config))
+                }
+                DestinationConfig::Xmpp(config) => {
+                    Box::new(XmppDestination::new(dstname.clone(), config))
+                }
             };
             destination_agents.insert(dstname.clone(), destination_agent);
+            destination_options.insert(dstname.clone(), dstentry.common.clone());
+            if let Some(config) = &dstentry.common.circuit_breaker {
+                circuit_breakers.insert(dstname.clone(), CircuitBreaker::new(config.clone()));
+            }
         }
 
         // Create sources
-        for (srcname, srccfg) in &config.sources {
-            let source_agent: Box<dyn MailSource> = match srccfg {
+        for (srcname, srcentry) in &config.sources {
+            let source_agent: Box<dyn MailSource> = match &srcentry.config {
                 SourceConfig::Test(config) => Box::new(TestSource::new(srcname.clone(), config)),
                 SourceConfig::ImapPoll(config) => {
                     Box::new(ImapPollSource::new(srcname.clone(), config))
@@ -293,26 +963,151 @@ impl MailHub {
                 }
             };
             source_agents.insert(srcname.clone(), source_agent);
+            source_options.insert(srcname.clone(), srcentry.common.clone());
         }
 
-        let retryagent = config.retryagent.as_ref().map(|c| {
-            let retryagent: Box<dyn MailRetryAgent> = match c {
-                RetryAgentConfig::Memory(config) => Box::new(MemoryRetryAgent::new(config)),
-                RetryAgentConfig::Filesystem(config) => Box::new(FilesystemRetryAgent::new(config)),
-            };
-            retryagent
-        });
+        let named_retryagents = config.retryagents.iter().flatten();
+        let retryagents = config
+            .retryagent
+            .iter()
+            .map(|c| (None, c))
+            .chain(named_retryagents.map(|(name, c)| (Some(name.clone()), c)))
+            .map(|(name, c)| {
+                let retryagent: Box<dyn MailRetryAgent> = match c {
+                    RetryAgentConfig::Memory(config) => Box::new(MemoryRetryAgent::new(config)),
+                    RetryAgentConfig::Filesystem(config) => {
+                        Box::new(FilesystemRetryAgent::new(config)?)
+                    }
+                };
+                Ok((name, retryagent))
+            })
+            .collect::<anyhow::Result<_>>()?;
 
-        Self {
+        Ok(Self {
             destination_agents,
             source_agents,
-            retryagent,
+            retryagents,
             mappings: config.mappings.clone(),
+            destination_options,
+            source_options,
             hubchannel,
+            statistics: RunStatistics::default(),
+            circuit_breakers,
+            held: Vec::new(),
+            rate_windows: HashMap::new(),
+            debounced: HashMap::new(),
+            lifecycle_destination: config.lifecycle_destination.clone(),
+            message_id_domain: config.message_id_domain.clone(),
+            on_delivery_failure: config.on_delivery_failure.unwrap_or_default(),
+            dead_letter_dir: config.dead_letter_dir.as_ref().map(PathBuf::from),
+            #[cfg(test)]
+            test_deliveries,
+        })
+    }
+
+    fn dispatch(&mut self, dstname: &str, mail: Mail) {
+        let mut mail = mail;
+        if let Some(options) = self.destination_options.get(dstname) {
+            if mail.from_src != LIFECYCLE_SOURCE && !options.accepts_source(&mail.from_src) {
+                warn!(target: "MailHub", "Destination {} does not accept mail {} from source {}. Rejecting.", dstname, mail, mail.from_src);
+                return;
+            }
+            if let Some(active_hours) = &options.active_hours {
+                if !active_hours.is_active() {
+                    let overflow = active_hours.overflow.clone();
+                    info!(target: "MailHub", "Destination {} is outside of its active hours, redirecting mail {} => {}", dstname, mail, overflow);
+                    self.dispatch(&overflow, mail);
+                    return;
+                }
+            }
+            if options.strip_receipts {
+                mail = mail.without_receipt_requests();
+            }
+            if let Some(reply_to) = &options.reply_to {
+                mail = mail.with_reply_to(reply_to, options.reply_to_existing.unwrap_or_default());
+            }
         }
+        if let Some(breaker) = self.circuit_breakers.get_mut(dstname) {
+            match breaker.admit() {
+                Admission::Deliver => {}
+                Admission::Probe => {
+                    info!(target: "MailHub", "Cooldown of destination {} over, probing with mail {}", dstname, mail);
+                }
+                Admission::Hold => {
+                    debug!(target: "MailHub", "Circuit of destination {} is open, queueing mail {} for retry", dstname, mail);
+                    if !self
+                        .hubchannel
+                        .queue_mail_for_retry(dstname.to_owned(), mail, None)
+                    {
+                        self.statistics.lost += 1;
+                    }
+                    return;
+                }
+            }
+        }
+        // Every destination has its own worker and queue, so a slow one does not hold up the
+        // others. One that stopped (e.g. failed to initialize) must not take the others down.
+        if let Err(mail) = self.hubchannel.queue_mail_for_sending(dstname, mail) {
+            error!(target: "MailHub", "Destination {} is not running, mail {} can not be delivered", dstname, mail);
+            self.handle_failed_delivery(dstname.to_owned(), *mail, None);
+        }
+    }
+
+    /// Retry, store or drop a mail that could not be delivered, as configured with
+    /// `on_delivery_failure`. This applies to every failed attempt, including failed retries.
+    fn handle_failed_delivery(&mut self, dstname: String, mail: Mail, min_delay: Option<Duration>) {
+        match self.on_delivery_failure {
+            FailurePolicy::Retry => {
+                info!(target: "MailHub", "Queueing failed mail {} for retransmission", mail);
+                if !self
+                    .hubchannel
+                    .queue_mail_for_retry(dstname, mail, min_delay)
+                {
+                    self.statistics.lost += 1;
+                }
+            }
+            FailurePolicy::DeadLetter => {
+                let dir = self
+                    .dead_letter_dir
+                    .as_deref()
+                    .expect("Validated with config");
+                match write_dead_letter(dir, &dstname, &mail) {
+                    Ok(path) => {
+                        warn!(target: "MailHub", "Stored failed mail {} for destination {} as {}", mail, dstname, path.display());
+                        self.statistics.dead_letters += 1;
+                    }
+                    Err(e) => {
+                        error!(target: "MailHub", "Failed to store failed mail {} for destination {}: {}", mail, dstname, e);
+                        self.statistics.lost += 1;
+                    }
+                }
+            }
+            FailurePolicy::Drop => {
+                warn!(target: "MailHub", "Dropping failed mail {} for destination {}", mail, dstname);
+                self.statistics.dropped += 1;
+            }
+        }
+    }
+
+    fn record_destination_response(&mut self, dstname: &str) {
+        if let Some(breaker) = self.circuit_breakers.get_mut(dstname) {
+            if breaker.record_success() {
+                info!(target: "MailHub", "Destination {} is working again, resuming deliveries", dstname);
+            }
+        }
+    }
+
+    /// Counters of the folder the mail came from, if its source has `folder_statistics`
+    fn folder_statistics(&mut self, mail: &Mail) -> Option<&mut (usize, usize)> {
+        let options = self.source_options.get(&mail.from_src)?;
+        if !options.folder_statistics {
+            return None;
+        }
+        let key = (mail.from_src.clone(), mail.folder.clone()?);
+        Some(self.statistics.folders.entry(key).or_default())
     }
 
-    fn handle_message(&self, msg: HubMessage) -> bool {
+    fn handle_message(&mut self, msg: HubMessage) -> bool {
         match msg {
             HubMessage::Shutdown => {
                 return true;
@@ -320,41 +1115,373 @@ impl MailHub {
             HubMessage::RetryAgentSuspended => {
                 return true;
             }
+            HubMessage::RetryAgentStopped { persisted, lost } => {
+                let retry_queue = self.statistics.retry_queue.get_or_insert((0, 0));
+                retry_queue.0 += persisted;
+                retry_queue.1 += lost;
+            }
             HubMessage::NewMail { srcname, mail } => {
-                info!(target: "MailHub", "Mail from source {}", srcname);
-                if let Some(dstlist) = self.mappings.get(&srcname) {
-                    for dstname in dstlist {
-                        info!(target: "MailHub", "Distributing Mail {} => {}", srcname, dstname);
-                        self.hubchannel
-                            .queue_mail_for_sending(dstname, mail.clone())
-                            .expect("Failed to distribute mail");
+                info!(target: "MailHub", "Mail {} from source {}", mail, srcname);
+                self.statistics.received += 1;
+                if let Some(folder) = self.folder_statistics(&mail) {
+                    folder.0 += 1;
+                }
+                if log_enabled!(Level::Debug) {
+                    // the mail is forwarded unchanged, but its metadata might be incomplete
+                    for problem in mime::Entity::parse(&mail.data).problems() {
+                        debug!(target: "MailHub", "Malformed MIME in mail {}: {}", mail, problem);
+                    }
+                }
+                let mail = match &self.message_id_domain {
+                    Some(domain) if mail.message_id.is_none() => {
+                        let mail = mail.with_generated_message_id(domain);
+                        debug!(target: "MailHub", "Generated Message-ID for mail {}", mail);
+                        mail
+                    }
+                    _ => mail,
+                };
+                let mail = match self.source_options.get(&srcname) {
+                    Some(options) if options.tag_source => {
+                        mail.with_header("X-Idlemail-Source", &srcname)
+                    }
+                    _ => mail,
+                };
+                // parsed only if the source treats auto-submitted mails differently
+                let auto_submitted = self
+                    .source_options
+                    .get(&srcname)
+                    .and_then(|options| options.auto_submitted.clone())
+                    .filter(|handling| *handling != AutoSubmittedHandling::Forward)
+                    .filter(|_| mail.is_auto_submitted());
+                if let Some(AutoSubmittedHandling::Skip) = auto_submitted {
+                    info!(target: "MailHub", "Not forwarding auto-submitted mail {} from source {}", mail, srcname);
+                    return false;
+                }
+                if let Some(targets) = self.mappings.get(&srcname) {
+                    let list_id = mail.list_id();
+                    // only extracted if needed, since it requires decoding the mail
+                    let body = targets
+                        .iter()
+                        .any(|target| target.body_pattern().is_some())
+                        .then(|| mail.body_text(BODY_SCAN_MAX_LEN))
+                        .flatten();
+                    let attachments = if targets
+                        .iter()
+                        .any(|target| target.attachment_pattern().is_some())
+                    {
+                        mime::Entity::parse(&mail.data).attachments()
+                    } else {
+                        Vec::new()
+                    };
+                    let recipients = if targets
+                        .iter()
+                        .any(|target| target.recipient_pattern().is_some())
+                    {
+                        mail.recipients()
+                    } else {
+                        Vec::new()
+                    };
+                    let mut dstlist: Vec<(&String, &[Transformation])> = Vec::new();
+                    if let Some(AutoSubmittedHandling::Destinations(dstnames)) = &auto_submitted {
+                        // instead of the mappings
+                        info!(target: "MailHub", "Routing auto-submitted mail {} from source {} to its own destinations", mail, srcname);
+                        dstlist.extend(dstnames.iter().map(|dstname| (dstname, &[][..])));
+                    }
+                    for target in targets.iter().filter(|_| auto_submitted.is_none()) {
+                        if target.matches(
+                            mail.folder.as_deref(),
+                            list_id.as_deref(),
+                            body.as_deref(),
+                            &attachments,
+                            &recipients,
+                        ) {
+                            let pipeline = target.pipeline();
+                            dstlist.extend(target.destinations().iter().map(|d| (d, pipeline)));
+                        }
+                    }
+                    // a destination might be matched by multiple folder patterns, or be listed
+                    // twice by mistake (see `ConfigContainer::duplicate_destinations`)
+                    let mut distributed = HashSet::new();
+                    dstlist.retain(|target| distributed.insert(*target));
+                    if dstlist.is_empty() {
+                        warn!(target: "MailHub", "No destination mapped for mail {} from source {} in folder {}", mail, srcname, mail.folder.as_deref().unwrap_or("-"));
+                    }
+                    let mut deliveries = Vec::with_capacity(dstlist.len());
+                    // only parsed if a destination transforming the mail cares
+                    let mut problems = None;
+                    for (dstname, pipeline) in dstlist {
+                        let options = self.destination_options.get(dstname);
+                        // added here, as retries of the delivery already carry the footer
+                        let footer = options.and_then(|options| options.footer.as_deref());
+                        if pipeline.is_empty() && footer.is_none() {
+                            info!(target: "MailHub", "Distributing Mail {} {} => {}", mail, srcname, dstname);
+                            deliveries.push((dstname.clone(), mail.clone()));
+                            continue;
+                        }
+                        let on_parse_error = options
+                            .and_then(|options| options.on_parse_error.as_ref())
+                            .filter(|policy| **policy != ParseErrorPolicy::Transform);
+                        if let Some(on_parse_error) = on_parse_error {
+                            let problems = problems
+                                .get_or_insert_with(|| mime::Entity::parse(&mail.data).problems());
+                            if let Some(problem) = problems.first() {
+                                match on_parse_error {
+                                    ParseErrorPolicy::Drop => {
+                                        warn!(target: "MailHub", "Malformed MIME in mail {} ({}), not distributing it to {}", mail, problem, dstname);
+                                    }
+                                    ParseErrorPolicy::Destination(error_dstname) => {
+                                        warn!(target: "MailHub", "Malformed MIME in mail {} ({}), distributing it unchanged {} => {} instead of {}", mail, problem, srcname, error_dstname, dstname);
+                                        deliveries.push((error_dstname.clone(), mail.clone()));
+                                    }
+                                    ParseErrorPolicy::Pass | ParseErrorPolicy::Transform => {
+                                        warn!(target: "MailHub", "Malformed MIME in mail {} ({}), distributing it unchanged {} => {}", mail, problem, srcname, dstname);
+                                        deliveries.push((dstname.clone(), mail.clone()));
+                                    }
+                                }
+                                continue;
+                            }
+                        }
+                        let transformed = pipeline.iter().fold(vec![mail.clone()], |mails, t| {
+                            mails.iter().flat_map(|mail| mail.transformed(t)).collect()
+                        });
+                        for mut transformed in transformed {
+                            if let Some(footer) = footer {
+                                transformed = transformed.with_footer(footer);
+                            }
+                            info!(target: "MailHub", "Distributing Mail {} {} => {} (transformed to {})", mail, srcname, dstname, transformed);
+                            deliveries.push((dstname.clone(), transformed));
+                        }
+                    }
+                    // only mails that are forwarded count towards the rate limit
+                    let forwarded = deliveries.iter().any(|(dstname, _)| {
+                        self.destination_options
+                            .get(dstname)
+                            .is_none_or(|options| options.accepts_source(&srcname))
+                    });
+                    let slot = if forwarded {
+                        self.rate_limit_slot(&srcname)
+                    } else {
+                        Some(Instant::now())
+                    };
+                    let Some(slot) = slot else {
+                        warn!(target: "MailHub", "Source {} exceeded its rate limit, dropping mail {}", srcname, mail);
+                        self.statistics.rate_limited += 1;
+                        return false;
+                    };
+                    let quiet_hours = self
+                        .source_options
+                        .get(&srcname)
+                        .and_then(|options| options.quiet_hours.as_ref())
+                        .and_then(|quiet_hours| quiet_hours.remaining());
+                    let now = Instant::now();
+                    let mut release = slot;
+                    if let Some(remaining) = quiet_hours {
+                        if !deliveries.is_empty() {
+                            info!(target: "MailHub", "Quiet hours of source {}, holding mail {} for {}min", srcname, mail, remaining.as_secs().div_ceil(60));
+                        }
+                        release = release.max(now + remaining);
+                    } else if slot > now && !deliveries.is_empty() {
+                        info!(target: "MailHub", "Source {} exceeded its rate limit, holding mail {} for {}s", srcname, mail, (slot - now).as_secs_f64().ceil());
+                    }
+                    if release > now {
+                        self.held.extend(
+                            deliveries
+                                .into_iter()
+                                .map(|(dstname, mail)| (release, dstname, mail)),
+                        );
+                        return false;
+                    }
+                    for (dstname, mail) in deliveries {
+                        self.deliver(&dstname, mail);
                     }
                 }
             }
-            HubMessage::SendingMailFailed { dstname, mail } => {
-                info!(target: "MailHub", "Queueing failed mail for retransmission");
-                self.hubchannel.queue_mail_for_retry(dstname, mail);
+            HubMessage::SendingMailFailed {
+                dstname,
+                mail,
+                min_delay,
+            } => {
+                self.statistics.failed_attempts += 1;
+                if let Some(breaker) = self.circuit_breakers.get_mut(&dstname) {
+                    if breaker.record_failure() {
+                        warn!(target: "MailHub", "Destination {} failed {} times in a row. Not attempting deliveries for {}s", dstname, breaker.consecutive_failures, breaker.config.cooldown);
+                    }
+                }
+                self.handle_failed_delivery(dstname, mail, min_delay);
+            }
+            HubMessage::MailSent {
+                dstname,
+                mail,
+                response,
+            } => {
+                debug!(target: "MailHub", "Mail {} delivered to {}: {}", mail, dstname, response);
+                self.statistics.delivered += 1;
+                if let Some(folder) = self.folder_statistics(&mail) {
+                    folder.1 += 1;
+                }
+                self.record_destination_response(&dstname);
+            }
+            HubMessage::MailRejected {
+                dstname,
+                mail,
+                reason,
+            } => {
+                debug!(target: "MailHub", "Mail {} rejected by {}: {}", mail, dstname, reason);
+                self.statistics.rejected += 1;
+                // the destination is reachable, it just does not want this mail
+                self.record_destination_response(&dstname);
             }
             HubMessage::RetryMail { dstname, mail } => {
-                info!(target: "MailHub", "Distributing Mail [retry] => {}", dstname);
-                self.hubchannel
-                    .queue_mail_for_sending(&dstname, mail)
-                    .expect("Failed to distribute mail");
+                info!(target: "MailHub", "Distributing Mail {} [retry] => {}", mail, dstname);
+                self.dispatch(&dstname, mail);
             }
         }
         false
     }
 
-    pub fn run(&mut self) {
-        info!(target: "MailHub", "Starting.");
+    /// Dispatch the held mails whose quiet hours are over, or that the rate limit of their
+    /// source allows now.
+    fn release_held_mails(&mut self) {
+        let now = Instant::now();
+        let (released, held) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|(release, _, _)| *release <= now);
+        self.held = held;
+        for (_, dstname, mail) in released {
+            info!(target: "MailHub", "Distributing held mail {} => {}", mail, dstname);
+            self.deliver(&dstname, mail);
+        }
+    }
+
+    /// When the next mail of `srcname` is forwarded under the source's `rate_limit`: now,
+    /// later if the excess is delayed, or `None` if it is dropped.
+    fn rate_limit_slot(&mut self, srcname: &str) -> Option<Instant> {
+        let now = Instant::now();
+        let Some(rate_limit) = self
+            .source_options
+            .get(srcname)
+            .and_then(|options| options.rate_limit.as_ref())
+        else {
+            return Some(now);
+        };
+        let period = Duration::from_secs(rate_limit.period);
+        let window = self.rate_windows.entry(srcname.to_owned()).or_default();
+        while window.front().is_some_and(|slot| *slot + period <= now) {
+            window.pop_front();
+        }
+        let slot = if window.len() < rate_limit.max_mails {
+            now
+        } else {
+            match rate_limit.excess.unwrap_or_default() {
+                RateLimitExcess::Drop => return None,
+                // as soon as the mail `max_mails` before this one left the window
+                RateLimitExcess::Delay => window[window.len() - rate_limit.max_mails] + period,
+            }
+        };
+        window.push_back(slot);
+        Some(slot)
+    }
+
+    /// Dispatch a new mail, or collect it first, if the destination has `debounce`.
+    fn deliver(&mut self, dstname: &str, mail: Mail) {
+        let debounce = self
+            .destination_options
+            .get(dstname)
+            .and_then(|options| options.debounce);
+        match debounce {
+            Some(debounce) => {
+                let key = (dstname.to_owned(), mail.from_src.clone());
+                let (end, mails) = self.debounced.entry(key).or_insert_with(|| {
+                    (Instant::now() + Duration::from_secs(debounce), Vec::new())
+                });
+                debug!(target: "MailHub", "Collecting mail {} for destination {} for {}s", mail, dstname, end.saturating_duration_since(Instant::now()).as_secs());
+                mails.push(mail);
+            }
+            None => self.dispatch(dstname, mail),
+        }
+    }
+
+    /// Dispatch the mails collected for `debounce` whose window is over (all with `force`),
+    /// combined into one mail for each destination and source.
+    fn release_debounced_mails(&mut self, force: bool) {
+        let now = Instant::now();
+        let (released, debounced) = std::mem::take(&mut self.debounced)
+            .into_iter()
+            .partition(|(_, (end, _))| force || *end <= now);
+        self.debounced = debounced;
+        for ((dstname, srcname), (_, mails)) in released {
+            let mail = match combine_mails(&srcname, &mails) {
+                Ok(mail) => mail,
+                Err(e) => {
+                    warn!(target: "MailHub", "{}, delivering the {} mails one by one", e, mails.len());
+                    for mail in mails {
+                        self.dispatch(&dstname, mail);
+                    }
+                    continue;
+                }
+            };
+            info!(target: "MailHub", "Distributing {} collected mails {} => {} as {}", mails.len(), srcname, dstname, mail);
+            self.dispatch(&dstname, mail);
+        }
+    }
+
+    /// Hand the mails still held at shutdown to the RetryAgent, to be delivered once the
+    /// quiet hours are over, or the rate limit allows.
+    fn persist_held_mails(&mut self) {
+        let now = Instant::now();
+        for (release, dstname, mail) in std::mem::take(&mut self.held) {
+            if !self.hubchannel.queue_mail_for_retry(
+                dstname,
+                mail,
+                Some(release.saturating_duration_since(now)),
+            ) {
+                self.statistics.lost += 1;
+            }
+        }
+    }
+
+    /// Send a mail with `subject` and `text` to the `lifecycle_destination`, if there is one.
+    fn notify_lifecycle(&mut self, subject: &str, text: &str) {
+        let dstname = match &self.lifecycle_destination {
+            Some(dstname) => dstname.clone(),
+            None => return,
+        };
+        match notification_message(subject, text) {
+            Ok(data) => {
+                let mail = Mail::from_rfc822(LIFECYCLE_SOURCE.to_owned(), data);
+                info!(target: "MailHub", "Sending lifecycle notification {} => {}", mail, dstname);
+                self.dispatch(&dstname, mail);
+            }
+            Err(e) => warn!(target: "MailHub", "{}", e),
+        }
+    }
+
+    fn start_destinations(&mut self) {
         for (dst_name, dst) in &mut self.destination_agents {
             info!(target: "MailHub", "Starting destination: {}", dst_name);
             let comm = self.hubchannel.get_destination_channel(dst_name.clone());
             dst.start(comm);
         }
-        if let Some(ref mut retryagent) = self.retryagent {
-            info!(target: "MailHub", "Starting retryagent");
-            let comm = self.hubchannel.get_retryagent_channel();
+    }
+
+    /// Let the destinations finish the mails they have queued, and wait for them to stop.
+    fn stop_destinations(&mut self) {
+        self.hubchannel.shutdown_destinations();
+        for (dst_name, dst) in &mut self.destination_agents {
+            dst.join();
+            info!(target: "MailHub", "Destination: {} stopped", dst_name);
+        }
+    }
+
+    pub fn run(&mut self) {
+        info!(target: "MailHub", "Starting.");
+        self.start_destinations();
+        for (name, retryagent) in &mut self.retryagents {
+            match name {
+                Some(name) => info!(target: "MailHub", "Starting retryagent: {}", name),
+                None => info!(target: "MailHub", "Starting retryagent"),
+            }
+            let comm = self.hubchannel.get_retryagent_channel(name.clone());
             retryagent.start(comm);
         }
         for (src_name, src) in &mut self.source_agents {
@@ -363,9 +1490,33 @@ impl MailHub {
             src.start(comm);
         }
 
+        self.notify_lifecycle(
+            "idlemail started",
+            &format!(
+                "idlemail {} started, with {} sources and {} destinations.\r\n",
+                env!("CARGO_PKG_VERSION"),
+                self.source_agents.len(),
+                self.destination_agents.len()
+            ),
+        );
+
         info!(target: "MailHub", "Starting distribution loop");
         loop {
-            let msg = self.hubchannel.next();
+            self.release_held_mails();
+            self.release_debounced_mails(false);
+            let held_releases = self.held.iter().map(|(release, _, _)| *release);
+            let debounce_ends = self.debounced.values().map(|(end, _)| *end);
+            let next_release = held_releases.chain(debounce_ends).min();
+            let msg = match next_release {
+                Some(release) => match self
+                    .hubchannel
+                    .next_timeout(release.saturating_duration_since(Instant::now()))
+                {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                None => self.hubchannel.next(),
+            };
             if self.handle_message(msg) {
                 break;
             }
@@ -383,26 +1534,42 @@ impl MailHub {
             info!(target: "MailHub", "Source: {} stopped", src_name);
         }
 
-        // Then, we suspend the retry-agent, so it does still take incomming mails to-be
-        // retried, but it does not actually schedule them (send them to the hub).
-        self.hubchannel.suspend_retryagent();
-
-        // Wait for retryagent to confirm suspension and handle all messages until then
+        // Then, we suspend the retry-agents, so they do still take incomming mails to-be
+        // retried, but they do not actually schedule them (send them to the hub).
+        // Wait for every retryagent to confirm suspension and handle all messages until then
         // (there might still be some resubmissions sent to destinations here)
-        loop {
-            let msg = self.hubchannel.next();
-            if self.handle_message(msg) {
-                break;
+        if !self.retryagents.is_empty() {
+            self.hubchannel.suspend_retryagents();
+            let mut suspended = 0;
+            while suspended < self.retryagents.len() {
+                let msg = self.hubchannel.next();
+                if self.handle_message(msg) {
+                    suspended += 1;
+                }
+            }
+        } else {
+            while let Some(msg) = self.hubchannel.try_next() {
+                self.handle_message(msg);
             }
         }
+        // Mails of the stopped sources are all handled now. The collected ones are delivered
+        // early, the ones held back for quiet hours are kept by the (suspended) retryagent.
+        self.release_debounced_mails(true);
+        self.persist_held_mails();
+
+        // Queued before the destinations are shut down, so it is still sent (or queued for
+        // retry) like the mails they have queued already
+        self.notify_lifecycle(
+            "idlemail stopping",
+            &format!(
+                "idlemail is stopping. Since it started, it received {} mails, and delivered {}.\r\n",
+                self.statistics.received, self.statistics.delivered
+            ),
+        );
 
         // The destinations can now finish the mails they have queued (which might schedule
         // new mails in the retryagents), but no new mails are queued into destinations to send.
-        self.hubchannel.shutdown_destinations();
-        for (dst_name, dst) in &mut self.destination_agents {
-            dst.join();
-            info!(target: "MailHub", "Destination: {} stopped", dst_name);
-        }
+        self.stop_destinations();
 
         // Handle all resubmission HubMessages that have accumulated before we tell the retryagent to shut down
         // If the retryagent is a persistent one, it can store the new mails to be retried, it won't
@@ -411,15 +1578,514 @@ impl MailHub {
             self.handle_message(msg);
         }
 
-        // Last, the retryagent is shutdown.
-        self.hubchannel.shutdown_retryagent();
-        if let Some(retryagent) = &mut self.retryagent {
+        // Last, the retryagents are shutdown.
+        self.hubchannel.shutdown_retryagents();
+        for (name, retryagent) in &mut self.retryagents {
             retryagent.join();
-            info!(target: "MailHub", "Retryagent stopped");
+            match name {
+                Some(name) => info!(target: "MailHub", "Retryagent {} stopped", name),
+                None => info!(target: "MailHub", "Retryagent stopped"),
+            }
+        }
+        // the RetryAgents report their queue right before they stop
+        while let Some(msg) = self.hubchannel.try_next() {
+            self.handle_message(msg);
         }
+        self.statistics.log_report(!self.retryagents.is_empty());
     }
 
     pub fn get_stop_sender(&self) -> HubStopSender {
         self.hubchannel.get_stop_channel()
     }
+
+    /// Send a generated test mail through the destination `dstname`, without starting
+    /// anything else. Returns the destination's response, or why sending failed.
+    pub fn test_destination(&mut self, dstname: &str) -> Result<String, String> {
+        let destination = self
+            .destination_agents
+            .get_mut(dstname)
+            .ok_or_else(|| format!("Unknown destination: {}", dstname))?;
+        let mail = Mail::from_rfc822(
+            "idlemail-test".to_owned(),
+            notification_message(
+                "Idlemail test message",
+                "This is a test message sent by idlemail --test-destination.\r\n\
+                 If you can read this, the destination is configured correctly.\r\n",
+            )?,
+        );
+        info!(target: "MailHub", "Sending test mail {} => {}", mail, dstname);
+
+        let comm = self.hubchannel.get_destination_channel(dstname.to_owned());
+        destination.start(comm);
+        self.hubchannel
+            .queue_mail_for_sending(dstname, mail)
+            .expect("Failed to distribute mail");
+        // the destination stops after handling the queued mail
+        self.hubchannel.shutdown_destinations();
+        destination.join();
+
+        while let Some(msg) = self.hubchannel.try_next() {
+            match msg {
+                HubMessage::MailSent { response, .. } => return Ok(response),
+                HubMessage::MailRejected { reason, .. } => {
+                    return Err(format!("Destination rejected the mail: {}", reason))
+                }
+                HubMessage::SendingMailFailed { .. } => {
+                    return Err(
+                        "Sending failed, the mail would have been queued for retry".to_owned()
+                    )
+                }
+                _ => {}
+            }
+        }
+        Err("Destination did not report a result".to_owned())
+    }
+
+    /// Deliver the dead letters in `path` (a file or a directory of them) once more, each to
+    /// the destination it failed for, without starting anything else. The files of delivered
+    /// mails are removed, the others are kept. Returns how many were delivered and how many not.
+    pub fn replay_dead_letters(&mut self, path: &Path) -> Result<(usize, usize), String> {
+        let files = if path.is_dir() {
+            let mut files = fs::read_dir(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| file.extension().is_some_and(|ext| ext == "eml"))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        } else {
+            vec![path.to_owned()]
+        };
+
+        let mut pending: HashMap<(String, String), PathBuf> = HashMap::new();
+        let mut failed = 0;
+        for file in files {
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            let dstname = match dead_letter_destination(&file_name, self.destination_agents.keys())
+            {
+                Some(dstname) => dstname.clone(),
+                None => {
+                    error!(target: "MailHub", "No destination found for dead letter {}", file.display());
+                    failed += 1;
+                    continue;
+                }
+            };
+            let data =
+                fs::read(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+            let mail = Mail::from_rfc822(DEAD_LETTER_SOURCE.to_owned(), data);
+            if !self.hubchannel.destinations.contains_key(&dstname) {
+                let comm = self.hubchannel.get_destination_channel(dstname.clone());
+                self.destination_agents
+                    .get_mut(&dstname)
+                    .expect("Found by name")
+                    .start(comm);
+            }
+            info!(target: "MailHub", "Replaying dead letter {} => {}", mail, dstname);
+            pending.insert((dstname.clone(), mail.hash.clone()), file);
+            self.hubchannel
+                .queue_mail_for_sending(&dstname, mail)
+                .expect("Failed to distribute mail");
+        }
+        let started: Vec<String> = self.hubchannel.destinations.keys().cloned().collect();
+        // the destinations stop after handling the queued mails
+        self.hubchannel.shutdown_destinations();
+        for dstname in started {
+            self.destination_agents
+                .get_mut(&dstname)
+                .expect("Started by name")
+                .join();
+        }
+
+        let mut delivered = 0;
+        while let Some(msg) = self.hubchannel.try_next() {
+            let (dstname, mail) = match msg {
+                HubMessage::MailSent { dstname, mail, .. } => {
+                    if let Some(file) = pending.remove(&(dstname, mail.hash.clone())) {
+                        if let Err(e) = fs::remove_file(&file) {
+                            warn!(target: "MailHub", "Failed to remove replayed dead letter {}: {}", file.display(), e);
+                        }
+                        delivered += 1;
+                    }
+                    continue;
+                }
+                HubMessage::MailRejected { dstname, mail, .. } => (dstname, mail),
+                HubMessage::SendingMailFailed { dstname, mail, .. } => (dstname, mail),
+                _ => continue,
+            };
+            if let Some(file) = pending.remove(&(dstname.clone(), mail.hash.clone())) {
+                warn!(target: "MailHub", "Replaying mail {} to {} failed, keeping {}", mail, dstname, file.display());
+                failed += 1;
+            }
+        }
+        Ok((delivered, failed + pending.len()))
+    }
+}
+
+/// Mail of `srcname` enclosing the given mails as attachments, after a list of their senders
+/// and subjects. A single mail is returned unchanged.
+fn combine_mails(srcname: &str, mails: &[Mail]) -> Result<Mail, String> {
+    if let [mail] = mails {
+        return Ok(mail.clone());
+    }
+    let mut text = format!("{} mails from {}:\r\n\r\n", mails.len(), srcname);
+    for mail in mails {
+        let message = mime::Entity::parse(&mail.data);
+        let header = |name| {
+            message
+                .header(name)
+                .map(|value| mime::decode_header_words(&value))
+                .unwrap_or_default()
+        };
+        text += &format!("- {}: {}\r\n", header("From"), header("Subject"));
+    }
+    let mut multipart = lettre::message::MultiPart::mixed().singlepart(SinglePart::plain(text));
+    for (i, mail) in mails.iter().enumerate() {
+        multipart = multipart.singlepart(rfc822_part(&mail.data, &format!("{}.eml", i + 1)));
+    }
+    let address: lettre::message::Mailbox = "Idlemail <idlemail@localhost>"
+        .parse()
+        .map_err(|e| format!("Invalid notification mail address: {}", e))?;
+    let message = lettre::Message::builder()
+        .from(address.clone())
+        .to(address)
+        .subject(format!("{} mails from {}", mails.len(), srcname))
+        .message_id(None)
+        .multipart(multipart)
+        .map_err(|e| format!("Failed to combine collected mails: {}", e))?;
+    let mut combined = Mail::from_rfc822(srcname.to_owned(), message.formatted());
+    // all from the same folder, or none
+    combined.folder = mails[0].folder.clone();
+    if mails.iter().any(|mail| mail.folder != combined.folder) {
+        combined.folder = None;
+    }
+    Ok(combined)
+}
+
+/// Attachment part enclosing the raw message `data` unchanged.
+pub fn rfc822_part(data: &[u8], filename: &str) -> SinglePart {
+    // message/rfc822 parts must not be base64 / quoted-printable encoded (RFC 2046)
+    let body = match String::from_utf8(data.to_vec()) {
+        Ok(text) if text.is_ascii() => {
+            Body::new_with_encoding(text, ContentTransferEncoding::SevenBit)
+        }
+        Ok(text) => Body::new_with_encoding(text, ContentTransferEncoding::EightBit),
+        Err(err) => Err(err.into_bytes()),
+    }
+    .or_else(|data| Body::new_with_encoding(data, ContentTransferEncoding::Binary))
+    .expect("binary encoding accepts everything");
+    SinglePart::builder()
+        .header(ContentType::parse("message/rfc822").unwrap())
+        .header(ContentDisposition::attachment(filename))
+        .body(body)
+}
+
+/// Raw message generated by idlemail itself, e.g. for `MailHub::test_destination`
+fn notification_message(subject: &str, text: &str) -> Result<Vec<u8>, String> {
+    let address: lettre::message::Mailbox = "Idlemail <idlemail@localhost>"
+        .parse()
+        .map_err(|e| format!("Invalid notification mail address: {}", e))?;
+    let message = lettre::Message::builder()
+        .from(address.clone())
+        .to(address)
+        .subject(subject)
+        .message_id(None)
+        .body(text.to_owned())
+        .map_err(|e| format!("Failed to build notification mail: {}", e))?;
+    Ok(message.formatted())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    /// A hub for the configuration `config`, as it would be read from a configuration file
+    fn hub_from_json(config: serde_json::Value) -> MailHub {
+        let config: ConfigContainer = serde_json::from_value(config).unwrap();
+        MailHub::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut hub = hub_from_json(serde_json::json!({
+            "sources": {
+                "account": { "type": "test", "delay": 0, "interval": 60 },
+                "other": { "type": "test", "delay": 0, "interval": 60 }
+            },
+            "destinations": {
+                "all": { "type": "test", "fail_n_first": 0 },
+                "inbox": { "type": "test", "fail_n_first": 0, "allowed_sources": ["account"] },
+                "prefixed": { "type": "test", "fail_n_first": 0 }
+            },
+            "mappings": {
+                "account": [
+                    "all",
+                    { "folder": "INBOX", "destinations": ["inbox"] },
+                    {
+                        "folder": "INBOX",
+                        "pipeline": [{ "type": "subject_prefix", "prefix": "[inbox] " }],
+                        "destination": "prefixed"
+                    }
+                ],
+                "other": ["all", "inbox"]
+            }
+        }));
+
+        hub.start_destinations();
+        for (srcname, folder) in [
+            ("account", "INBOX"),
+            ("account", "Archive"),
+            ("other", "INBOX"),
+        ] {
+            let data = format!("Subject: {} {}\r\n\r\nbody\r\n", srcname, folder);
+            let mail = Mail::from_rfc822(srcname.to_owned(), data.into_bytes())
+                .with_folder(folder.to_owned());
+            hub.handle_message(HubMessage::NewMail {
+                srcname: srcname.to_owned(),
+                mail,
+            });
+        }
+        hub.stop_destinations();
+
+        let subjects = |dstname: &str| -> Vec<String> {
+            let delivered = hub.test_deliveries[dstname].lock().unwrap();
+            delivered
+                .iter()
+                .filter_map(|mail| mime::Entity::parse(&mail.data).header("Subject"))
+                .collect()
+        };
+        assert_eq!(
+            subjects("all"),
+            ["account INBOX", "account Archive", "other INBOX"]
+        );
+        // mails from other are not accepted
+        assert_eq!(subjects("inbox"), ["account INBOX"]);
+        assert_eq!(subjects("prefixed"), ["[inbox] account INBOX"]);
+    }
+
+    #[test]
+    fn test_generated_message_id() {
+        let mail = Mail::from_rfc822("src".to_owned(), &b"Subject: alert\r\n\r\nbody\r\n"[..]);
+        let generated = mail.with_generated_message_id("example.org");
+        let message_id = generated.message_id.clone().unwrap();
+        assert!(message_id.starts_with('<'));
+        assert!(message_id.ends_with(".idlemail@example.org>"));
+        // stable for the same content, so a mail fetched twice is still a duplicate
+        assert_eq!(
+            mail.with_generated_message_id("example.org").message_id,
+            generated.message_id
+        );
+        // an existing Message-ID is kept
+        assert_eq!(
+            generated.with_generated_message_id("example.org").data,
+            generated.data
+        );
+    }
+
+    #[test]
+    fn test_dead_letter_destination() {
+        let dstnames: Vec<String> = ["a", "a-b", "x y", "x_y", "mail@example.org"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let destination = |file_name: &str| {
+            dead_letter_destination(file_name, dstnames.iter()).map(String::as_str)
+        };
+        assert_eq!(destination("a-123.eml"), Some("a"));
+        assert_eq!(destination("a-b-123.eml"), Some("a-b"));
+        assert_eq!(
+            destination("mail_example_org-5.eml"),
+            Some("mail@example.org")
+        );
+        // both are stored as x_y
+        assert_eq!(destination("x_y-123.eml"), None);
+        assert_eq!(destination("a-123.txt"), None);
+        assert_eq!(destination("c-123.eml"), None);
+    }
+
+    #[test_case("Auto-Submitted: auto-replied" => true)]
+    #[test_case("Auto-Submitted: Auto-Generated (bounce)" => true)]
+    #[test_case("Auto-Submitted: auto-notified; owner-email=\"me@example.org\"" => true)]
+    #[test_case("Auto-Submitted: no" => false)]
+    #[test_case("Subject: Out of office" => false ; "without header")]
+    fn test_auto_submitted(header: &str) -> bool {
+        let data = format!("{}\r\nFrom: alice@example.org\r\n\r\nbody\r\n", header);
+        Mail::from_rfc822("src".to_owned(), data.as_bytes()).is_auto_submitted()
+    }
+
+    #[test]
+    fn test_reply_to() {
+        let reply_to = |data: &str, existing| {
+            let mail = Mail::from_rfc822("src".to_owned(), data.as_bytes());
+            let mail = mail.with_reply_to("{from}", existing);
+            mime::Entity::parse(&mail.data).header("Reply-To")
+        };
+        let from = "From: Alice <alice@example.org>\r\n\r\nbody\r\n";
+        assert_eq!(
+            reply_to(from, ReplyToExisting::Preserve).as_deref(),
+            Some("Alice <alice@example.org>")
+        );
+        let with_reply_to = "Reply-To: list@example.org\r\nFrom: alice@example.org\r\n\r\nbody\r\n";
+        assert_eq!(
+            reply_to(with_reply_to, ReplyToExisting::Preserve).as_deref(),
+            Some("list@example.org")
+        );
+        assert_eq!(
+            reply_to(with_reply_to, ReplyToExisting::Overwrite).as_deref(),
+            Some("alice@example.org")
+        );
+        assert_eq!(
+            reply_to(
+                "Subject: no sender\r\n\r\nbody\r\n",
+                ReplyToExisting::Overwrite
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_on_parse_error() {
+        let footer = |on_parse_error: serde_json::Value| {
+            serde_json::json!({
+                "type": "test", "fail_n_first": 0,
+                "footer": "Forwarded by idlemail", "on_parse_error": on_parse_error
+            })
+        };
+        let mut hub = hub_from_json(serde_json::json!({
+            "sources": { "account": { "type": "test", "delay": 0, "interval": 60 } },
+            "destinations": {
+                "transform": footer(serde_json::json!("transform")),
+                "pass": footer(serde_json::json!("pass")),
+                "drop": footer(serde_json::json!("drop")),
+                "routed": footer(serde_json::json!({ "destination": "errors" })),
+                "errors": { "type": "test", "fail_n_first": 0 }
+            },
+            "mappings": { "account": ["transform", "pass", "drop", "routed"] }
+        }));
+
+        hub.start_destinations();
+        for data in [
+            "Subject: valid\r\n\r\nbody\r\n",
+            "Subject: broken\r\nContent-Transfer-Encoding: x-unknown\r\n\r\nbody\r\n",
+        ] {
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "account".to_owned(),
+                mail: Mail::from_rfc822("account".to_owned(), data.as_bytes()),
+            });
+        }
+        hub.stop_destinations();
+
+        // subjects of the delivered mails, marked if they carry the footer
+        let delivered = |dstname: &str| -> Vec<String> {
+            let delivered = hub.test_deliveries[dstname].lock().unwrap();
+            delivered
+                .iter()
+                .map(|mail| {
+                    let message = mime::Entity::parse(&mail.data);
+                    let subject = message.header("Subject").unwrap_or_default();
+                    match String::from_utf8_lossy(&message.body).contains("idlemail") {
+                        true => format!("{} +footer", subject),
+                        false => subject,
+                    }
+                })
+                .collect()
+        };
+        assert_eq!(delivered("transform"), ["valid +footer", "broken +footer"]);
+        assert_eq!(delivered("pass"), ["valid +footer", "broken"]);
+        assert_eq!(delivered("drop"), ["valid +footer"]);
+        assert_eq!(delivered("routed"), ["valid +footer"]);
+        assert_eq!(delivered("errors"), ["broken"]);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut hub = hub_from_json(serde_json::json!({
+            "sources": {
+                "dropping": {
+                    "type": "test", "delay": 0, "interval": 60,
+                    "rate_limit": { "max_mails": 2, "period": 3600 }
+                },
+                "delaying": {
+                    "type": "test", "delay": 0, "interval": 60,
+                    "rate_limit": { "max_mails": 2, "period": 3600, "excess": "delay" }
+                },
+                "unlimited": { "type": "test", "delay": 0, "interval": 60 },
+                "filtered": {
+                    "type": "test", "delay": 0, "interval": 60,
+                    "rate_limit": { "max_mails": 1, "period": 3600 }
+                }
+            },
+            "destinations": { "all": { "type": "test", "fail_n_first": 0 } },
+            "mappings": {
+                "dropping": ["all"], "delaying": ["all"], "unlimited": ["all"],
+                "filtered": [{ "list_id": "news.example.org", "destinations": ["all"] }]
+            }
+        }));
+
+        hub.start_destinations();
+        for srcname in ["dropping", "delaying", "unlimited"] {
+            for i in 0..3 {
+                let data = format!("Subject: {} {}\r\n\r\nbody\r\n", srcname, i);
+                hub.handle_message(HubMessage::NewMail {
+                    srcname: srcname.to_owned(),
+                    mail: Mail::from_rfc822(srcname.to_owned(), data.into_bytes()),
+                });
+            }
+        }
+        // mails that are not forwarded do not count towards the limit
+        for data in [
+            "Subject: not mapped\r\n\r\nbody\r\n",
+            "List-Id: <news.example.org>\r\nSubject: mapped\r\n\r\nbody\r\n",
+        ] {
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "filtered".to_owned(),
+                mail: Mail::from_rfc822("filtered".to_owned(), data.as_bytes()),
+            });
+        }
+        hub.stop_destinations();
+
+        assert_eq!(hub.test_deliveries["all"].lock().unwrap().len(), 8);
+        assert_eq!(hub.statistics.rate_limited, 1);
+        assert_eq!(hub.held.len(), 1);
+        let (release, _, held) = &hub.held[0];
+        assert!(held.to_string().ends_with("\"delaying 2\""));
+        assert!(*release > Instant::now() + Duration::from_secs(3500));
+    }
+
+    #[test]
+    fn test_debounce() {
+        let mut hub = hub_from_json(serde_json::json!({
+            "sources": { "alerts": { "type": "test", "delay": 0, "interval": 60 } },
+            "destinations": { "chat": { "type": "test", "fail_n_first": 0, "debounce": 60 } },
+            "mappings": { "alerts": ["chat"] }
+        }));
+
+        hub.start_destinations();
+        for subject in ["disk full", "disk still full"] {
+            let data = format!(
+                "From: monitor@example.org\r\nSubject: {}\r\n\r\nbody\r\n",
+                subject
+            );
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "alerts".to_owned(),
+                mail: Mail::from_rfc822("alerts".to_owned(), data.into_bytes()),
+            });
+        }
+        // the window is not over yet
+        hub.release_debounced_mails(false);
+        assert_eq!(hub.debounced.len(), 1);
+        hub.release_debounced_mails(true);
+        hub.stop_destinations();
+
+        let delivered = hub.test_deliveries["chat"].lock().unwrap();
+        assert_eq!(delivered.len(), 1);
+        let message = mime::Entity::parse(&delivered[0].data);
+        assert_eq!(
+            message.header("Subject").as_deref(),
+            Some("2 mails from alerts")
+        );
+        let text = message.text_content().unwrap();
+        assert!(text.contains("- monitor@example.org: disk full\r\n"));
+        assert!(text.contains("- monitor@example.org: disk still full\r\n"));
+    }
 }
@@ -1,10 +1,13 @@
 mod config;
 mod destinations;
+mod dkim;
+mod error;
 mod hub;
+mod mime;
 mod retryagents;
 mod sources;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use signal::{trap::Trap, Signal};
 use std::time::{Duration, Instant};
 use magic::Cookie;
@@ -17,17 +20,49 @@ use std::process::exit;
 #[command(
     help_template = "{about-section}\n{author-with-newline} \n{usage-heading}  {usage} \n\n{all-args} {tab}"
 )]
-
 struct Cli {
-    /// Path to config file
-    #[arg(short = 'c', long, value_name = "config",required=true,)]
-    config: Option<String>,
+    /// Path to config file. Can be given multiple times, later files extend / override earlier ones
+    #[arg(
+        short = 'c',
+        long,
+        value_name = "config",
+        required_unless_present_any = ["migrate_retry_queue", "generate_config"]
+    )]
+    config: Vec<String>,
+
+    /// Send a test mail through the given destination, report the result, then exit
+    #[arg(long, value_name = "destination")]
+    test_destination: Option<String>,
+
+    /// Deliver the dead letters in the given file or directory once more, then exit
+    #[arg(long, value_name = "path", conflicts_with = "test_destination")]
+    replay_deadletter: Option<String>,
+
+    /// Export the mails of a folder of an IMAP source within a UID range (e.g. 1:100) as .eml
+    /// files into a directory, without changing them on the server, then exit
+    #[arg(
+        long,
+        num_args = 4,
+        value_names = ["source", "folder", "uids", "directory"],
+        conflicts_with_all = ["test_destination", "replay_deadletter"]
+    )]
+    export: Option<Vec<String>>,
+
+    /// Move all mails queued by a filesystem RetryAgent from one folder into another, then exit
+    #[arg(long, num_args = 2, value_names = ["from", "to"], conflicts_with = "config")]
+    migrate_retry_queue: Option<Vec<String>>,
+
+    /// Print an example configuration with every type of source and destination, then exit
+    #[arg(long, conflicts_with_all = ["config", "migrate_retry_queue", "test_destination", "replay_deadletter", "export"])]
+    generate_config: bool,
 }
-fn init_logging() {
+fn init_logging(default_filter: Option<&str>) {
     let mut log_builder = pretty_env_logger::formatted_builder();
 
     if let Ok(level) = std::env::var("RUST_LOG") {
         log_builder.parse_filters(&level);
+    } else if let Some(filter) = default_filter {
+        log_builder.parse_filters(filter);
     }
     if let Ok(write_style) = std::env::var("RUST_LOG_STYLE") {
         log_builder.parse_write_style(&write_style);
@@ -37,32 +72,113 @@ fn init_logging() {
 }
 
 fn main() {
-    init_logging();
-
     // commandline parsing
     let cli = Cli::parse();
-    let config_file = cli.config.unwrap();
+    if cli.generate_config {
+        print!("{}", config::TEMPLATE);
+        exit(0);
+    }
+    // when testing a destination, replaying or exporting, show what it does by default
+    let one_shot =
+        cli.test_destination.is_some() || cli.replay_deadletter.is_some() || cli.export.is_some();
+    init_logging(one_shot.then_some("info"));
+
+    if let Some(paths) = cli.migrate_retry_queue {
+        match retryagents::filesystem::migrate_queue(&paths[0], &paths[1]) {
+            Ok(cnt) => {
+                info!(target: "Idlemail", "Migrated {} queued mails", cnt);
+                exit(0);
+            }
+            Err(e) => {
+                error!(target: "Idlemail", "Failed to migrate retry queue:\n{}", error::ErrorReport(&e));
+                exit(1);
+            }
+        }
+    }
+    let config_files = cli.config;
 
-    // check if config file is a json file
+    // check if config files are json files
     let flags = Flags::MIME_TYPE;
     let cookie = Cookie::open(flags);
     let database = &Default::default();
     let cookie = cookie.expect("Error loading database").load(database);
-    let m_type = cookie.expect("Error reading file").file(&config_file);
-    if m_type.unwrap() != "application/json" {
-        error!(target: "Idlemail", "This is no json file");
-        exit(1);
+    let cookie = cookie.expect("Error reading file");
+    for config_file in &config_files {
+        let m_type = cookie.file(config_file);
+        if m_type.unwrap() != "application/json" {
+            error!(target: "Idlemail", "This is no json file: {}", config_file);
+            exit(1);
+        }
     }
     
     info!(target: "Idlemail", "Parsing configuration file");
-    let config = match config::ConfigContainer::from_file(&config_file) {
+    let config = match config::ConfigContainer::from_files(&config_files) {
         Ok(config) => config,
         Err(err) => {
-            error!(target: "Idlemail", "Failed to parse configuration file: {}\n{}", &config_file, err);
+            error!(target: "Idlemail", "Failed to parse configuration file: {}\n{}", config_files.join(", "), err);
             panic!();
         }
     };
-    let mut mailhub = hub::MailHub::from_config(&config);
+    for warning in config.port_warnings() {
+        warn!(target: "Idlemail", "{}", warning);
+    }
+    for warning in config.duplicate_destinations() {
+        warn!(target: "Idlemail", "{}", warning);
+    }
+    if config.verbose_errors || std::env::var_os(error::VERBOSE_ERRORS_ENV).is_some() {
+        error::enable_verbose_errors();
+    }
+
+    if let Some(args) = cli.export {
+        let directory = std::path::Path::new(&args[3]);
+        match sources::export::export(&config, &args[0], &args[1], &args[2], directory) {
+            Ok(cnt) => {
+                info!(target: "Idlemail", "Exported {} mails to {}", cnt, directory.display());
+                exit(0);
+            }
+            Err(e) => {
+                error!(target: "Idlemail", "Failed to export mails:\n{}", error::ErrorReport(&e));
+                exit(1);
+            }
+        }
+    }
+    let mut mailhub = match hub::MailHub::from_config(&config) {
+        Ok(mailhub) => mailhub,
+        Err(e) => {
+            error!(target: "Idlemail", "Failed to set up the mail hub:\n{}", error::ErrorReport(&e));
+            exit(1);
+        }
+    };
+
+    if let Some(dstname) = cli.test_destination {
+        match mailhub.test_destination(&dstname) {
+            Ok(response) => {
+                info!(target: "Idlemail", "Test mail sent successfully: {}", response);
+                exit(0);
+            }
+            Err(err) => {
+                error!(target: "Idlemail", "Failed to send test mail:\n{}", err);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = cli.replay_deadletter {
+        match mailhub.replay_dead_letters(std::path::Path::new(&path)) {
+            Ok((delivered, 0)) => {
+                info!(target: "Idlemail", "Replayed {} dead letters", delivered);
+                exit(0);
+            }
+            Ok((delivered, failed)) => {
+                error!(target: "Idlemail", "Replayed {} dead letters, {} failed and are kept", delivered, failed);
+                exit(1);
+            }
+            Err(err) => {
+                error!(target: "Idlemail", "Failed to replay dead letters:\n{}", err);
+                exit(1);
+            }
+        }
+    }
 
     #[cfg(target_os = "linux")]
     {
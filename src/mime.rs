@@ -0,0 +1,1130 @@
+//! Minimal MIME (RFC 2045 / 2046 / 2047) handling for transformations on forwarded mail.
+//!
+//! Parsing is deliberately lenient: unchanged headers and parts are kept byte-for-byte,
+//! so re-serializing a parsed message only alters what a transformation touched.
+
+use crate::config::glob_match;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+const CRLF: &[u8] = b"\r\n";
+/// Maximum length of a line in a quoted-printable / base64 encoded body (RFC 2045).
+const MAX_ENCODED_LINE_LEN: usize = 76;
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub name: String,
+    /// Everything after the colon, including folding whitespace but without the final line break.
+    raw_value: Vec<u8>,
+}
+impl Header {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            raw_value: format!(" {}", value).into_bytes(),
+        }
+    }
+
+    /// The unfolded header value, with surrounding whitespace removed.
+    pub fn value(&self) -> String {
+        let value = String::from_utf8_lossy(&self.raw_value);
+        let unfolded: String = value.split(['\r', '\n']).collect();
+        unfolded.trim().to_owned()
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.name.as_bytes());
+        out.push(b':');
+        out.extend_from_slice(&self.raw_value);
+        out.extend_from_slice(CRLF);
+    }
+}
+
+/// A header value like `Content-Type`, split into its main value and its parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentType {
+    /// Lowercase `type/subtype`
+    pub mime_type: String,
+    /// Parameters with lowercase names and unquoted values
+    pub params: Vec<(String, String)>,
+}
+impl ContentType {
+    pub fn parse(value: &str) -> Self {
+        let mut segments = split_unquoted(value, ';').into_iter();
+        let mime_type = segments.next().unwrap_or_default().trim().to_lowercase();
+        let params = segments
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .map(|v| v.replace("\\\"", "\"").replace("\\\\", "\\"))
+                    .unwrap_or_else(|| value.to_owned());
+                Some((name.trim().to_lowercase(), value))
+            })
+            .collect();
+        Self { mime_type, params }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Format as header value, quoting parameter values where needed.
+    pub fn to_header_value(&self) -> String {
+        let mut value = self.mime_type.clone();
+        for (name, param) in &self.params {
+            let is_token = !param.is_empty()
+                && param
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c));
+            if is_token {
+                value.push_str(&format!("; {}={}", name, param));
+            } else {
+                let quoted = param.replace('\\', "\\\\").replace('"', "\\\"");
+                value.push_str(&format!("; {}=\"{}\"", name, quoted));
+            }
+        }
+        value
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.mime_type.starts_with("multipart/")
+    }
+    pub fn is_text(&self) -> bool {
+        self.mime_type.starts_with("text/")
+    }
+}
+impl Default for ContentType {
+    fn default() -> Self {
+        Self::parse("text/plain; charset=us-ascii")
+    }
+}
+
+/// Split a header value at `separator`, ignoring separators within quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut segments = vec![String::new()];
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            '\\' if in_quotes && !escaped => escaped = true,
+            '"' if !escaped => in_quotes = !in_quotes,
+            c if c == separator && !in_quotes => {
+                segments.push(String::new());
+                continue;
+            }
+            _ => escaped = false,
+        }
+        segments.last_mut().unwrap().push(c);
+    }
+    segments
+}
+
+/// Iterator over the lines of a byte buffer, yielding each line including its line break.
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split_inclusive(|b| *b == b'\n')
+}
+
+fn trim_line_break(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// A MIME entity: either a complete message, or one part of a multipart body.
+#[derive(Clone, Debug, Default)]
+pub struct Entity {
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+impl Entity {
+    pub fn parse(data: &[u8]) -> Self {
+        let (headers, offset) = Self::parse_header_section(data);
+        Self {
+            headers,
+            body: data[offset..].to_vec(),
+        }
+    }
+
+    /// Only the headers of `data`, with an empty body. Cheaper than `parse` for large mails,
+    /// when only headers are needed.
+    pub fn parse_headers(data: &[u8]) -> Self {
+        Self {
+            headers: Self::parse_header_section(data).0,
+            body: Vec::new(),
+        }
+    }
+
+    /// The headers at the start of `data`, and the offset of the body behind them
+    fn parse_header_section(data: &[u8]) -> (Vec<Header>, usize) {
+        let mut headers: Vec<Header> = Vec::new();
+        let mut offset = 0;
+        for line in lines(data) {
+            let content = trim_line_break(line);
+            if content.is_empty() {
+                offset += line.len();
+                break;
+            }
+            if content[0] == b' ' || content[0] == b'\t' {
+                // folded continuation of the previous header
+                if let Some(header) = headers.last_mut() {
+                    header.raw_value.extend_from_slice(CRLF);
+                    header.raw_value.extend_from_slice(content);
+                    offset += line.len();
+                    continue;
+                }
+            }
+            match content.iter().position(|b| *b == b':') {
+                Some(colon) => headers.push(Header {
+                    name: String::from_utf8_lossy(&content[..colon]).trim().to_owned(),
+                    raw_value: content[colon + 1..].to_vec(),
+                }),
+                // not a header line, so the header section ended without an empty line
+                None => break,
+            }
+            offset += line.len();
+        }
+        (headers, offset)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 1024);
+        self.headers.iter().for_each(|h| h.write_to(&mut out));
+        out.extend_from_slice(CRLF);
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    /// Value of the first header with the given name
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(Header::value)
+    }
+
+    /// Values of all headers with the given name, in order
+    pub fn headers(&self, name: &str) -> Vec<String> {
+        self.headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .map(Header::value)
+            .collect()
+    }
+
+    /// Replace the first header with the given name, or append it if there is none.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let header = Header::new(name, value);
+        match self
+            .headers
+            .iter_mut()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+        {
+            Some(existing) => *existing = header,
+            None => self.headers.push(header),
+        }
+    }
+
+    /// Remove all headers with the given name. Returns whether there was any.
+    pub fn remove_header(&mut self, name: &str) -> bool {
+        let count = self.headers.len();
+        self.headers.retain(|h| !h.name.eq_ignore_ascii_case(name));
+        self.headers.len() != count
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.header("Content-Type")
+            .map(|v| ContentType::parse(&v))
+            .unwrap_or_default()
+    }
+
+    /// Lowercase Content-Transfer-Encoding, defaulting to `7bit`
+    pub fn transfer_encoding(&self) -> String {
+        self.header("Content-Transfer-Encoding")
+            .map(|v| v.to_lowercase())
+            .unwrap_or_else(|| "7bit".to_owned())
+    }
+
+    /// The parts of a multipart entity, or `None` if this entity is not a (valid) multipart.
+    pub fn multipart(&self) -> Option<Multipart> {
+        let content_type = self.content_type();
+        if !content_type.is_multipart() {
+            return None;
+        }
+        Multipart::parse(&self.body, content_type.param("boundary")?)
+    }
+
+    /// The body, with its Content-Transfer-Encoding removed.
+    pub fn decoded_body(&self) -> Vec<u8> {
+        match self.transfer_encoding().as_str() {
+            "base64" => {
+                let encoded: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                BASE64.decode(encoded).unwrap_or_else(|_| self.body.clone())
+            }
+            "quoted-printable" => {
+                quoted_printable::decode(&self.body, quoted_printable::ParseMode::Robust)
+                    .unwrap_or_else(|_| self.body.clone())
+            }
+            _ => self.body.clone(),
+        }
+    }
+
+    /// The decoded text of the first `text/plain` part, or of the first other text part
+    /// if there is none. Charsets are converted to UTF-8.
+    pub fn text_content(&self) -> Option<String> {
+        self.text_content_with_fallback(None)
+    }
+
+    /// Like `text_content`, but text without charset, or with an unknown one, is decoded
+    /// as `fallback_charset` (if that is known).
+    pub fn text_content_with_fallback(&self, fallback_charset: Option<&str>) -> Option<String> {
+        self.find_text_part("text/plain", fallback_charset)
+            .or_else(|| self.find_text_part("text/", fallback_charset))
+    }
+
+    fn find_text_part(
+        &self,
+        mime_type_prefix: &str,
+        fallback_charset: Option<&str>,
+    ) -> Option<String> {
+        if let Some(multipart) = self.multipart() {
+            return multipart
+                .parts
+                .iter()
+                .find_map(|part| part.find_text_part(mime_type_prefix, fallback_charset));
+        }
+        let content_type = self.content_type();
+        if !content_type.mime_type.starts_with(mime_type_prefix) || !content_type.is_text() {
+            return None;
+        }
+        if self.is_attachment() {
+            return None;
+        }
+        let charset = match (content_type.param("charset"), fallback_charset) {
+            (Some(charset), _) if is_known_charset(charset) => charset,
+            (_, Some(fallback)) if is_known_charset(fallback) => fallback,
+            (charset, _) => charset.unwrap_or("us-ascii"),
+        };
+        Some(decode_charset(&self.decoded_body(), charset))
+    }
+
+    /// Descriptions of everything malformed in this entity and its parts, which parsing
+    /// worked around. Empty for a well-formed entity.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.collect_problems("message", &mut problems);
+        problems
+    }
+
+    fn collect_problems(&self, location: &str, problems: &mut Vec<String>) {
+        for header in &self.headers {
+            if has_invalid_encoded_words(&header.value()) {
+                problems.push(format!(
+                    "{}: {} header contains an encoded-word that can not be decoded: {}",
+                    location,
+                    header.name,
+                    header.value()
+                ));
+            }
+        }
+
+        let content_type = self.content_type();
+        if content_type.is_multipart() {
+            match content_type.param("boundary") {
+                None => problems.push(format!(
+                    "{}: {} without boundary parameter, treated as single part",
+                    location, content_type.mime_type
+                )),
+                Some(boundary) => match Multipart::parse(&self.body, boundary) {
+                    None => problems.push(format!(
+                        "{}: boundary \"{}\" not found in the body, treated as single part",
+                        location, boundary
+                    )),
+                    Some(multipart) => {
+                        if !multipart.closed {
+                            problems.push(format!(
+                                "{}: closing boundary \"{}\" missing",
+                                location, boundary
+                            ));
+                        }
+                        for (i, part) in multipart.parts.iter().enumerate() {
+                            let part_location = match location {
+                                "message" => format!("part {}", i + 1),
+                                _ => format!("{}.{}", location, i + 1),
+                            };
+                            part.collect_problems(&part_location, problems);
+                        }
+                    }
+                },
+            }
+        }
+
+        match self.transfer_encoding().as_str() {
+            "base64" => {
+                let encoded: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                if BASE64.decode(encoded).is_err() {
+                    problems.push(format!(
+                        "{}: body is not valid base64, kept undecoded",
+                        location
+                    ));
+                }
+            }
+            "quoted-printable" => {
+                if quoted_printable::decode(&self.body, quoted_printable::ParseMode::Strict)
+                    .is_err()
+                {
+                    problems.push(format!(
+                        "{}: body is not valid quoted-printable, decoded leniently",
+                        location
+                    ));
+                }
+            }
+            "7bit" | "8bit" | "binary" => {}
+            encoding => problems.push(format!(
+                "{}: unknown Content-Transfer-Encoding \"{}\", kept undecoded",
+                location, encoding
+            )),
+        }
+
+        if let Some(charset) = content_type.param("charset") {
+            if encoding_rs::Encoding::for_label(charset.as_bytes()).is_none() {
+                problems.push(format!(
+                    "{}: unknown charset \"{}\", decoded as UTF-8",
+                    location, charset
+                ));
+            }
+        }
+    }
+
+    /// The parts with an `attachment` Content-Disposition, also from nested multiparts.
+    pub fn attachments(&self) -> Vec<Attachment> {
+        let mut attachments = Vec::new();
+        self.collect_attachments(&mut attachments);
+        attachments
+    }
+
+    fn collect_attachments(&self, attachments: &mut Vec<Attachment>) {
+        if self.is_attachment() {
+            let content_type = self.content_type();
+            let disposition = self
+                .header("Content-Disposition")
+                .map(|value| ContentType::parse(&value));
+            let filename = disposition
+                .as_ref()
+                .and_then(|disposition| disposition.param("filename"))
+                .or_else(|| content_type.param("name"))
+                .map(decode_header_words);
+            attachments.push(Attachment {
+                mime_type: content_type.mime_type,
+                filename,
+                size: self.body.len(),
+            });
+        } else if let Some(multipart) = self.multipart() {
+            for part in &multipart.parts {
+                part.collect_attachments(attachments);
+            }
+        }
+    }
+
+    fn is_attachment(&self) -> bool {
+        self.header("Content-Disposition")
+            .map(|v| v.to_lowercase().starts_with("attachment"))
+            .unwrap_or(false)
+    }
+
+    /// Remove all parts with an `attachment` Content-Disposition, also from nested multiparts.
+    /// Returns whether anything was removed.
+    pub fn strip_attachments(&mut self) -> bool {
+        let mut multipart = match self.multipart() {
+            Some(multipart) => multipart,
+            None => return false,
+        };
+        let part_count = multipart.parts.len();
+        multipart.parts.retain(|part| !part.is_attachment());
+        let mut changed = multipart.parts.len() != part_count;
+        for part in multipart.parts.iter_mut() {
+            changed |= part.strip_attachments();
+        }
+        if changed {
+            self.body = multipart.to_bytes();
+        }
+        changed
+    }
+
+    /// The messages enclosed in the first multipart/digest of the message (which may also be
+    /// nested in another multipart, like in the digests of most list managers), or `None` if it
+    /// has no digest. Parts of the digest that are not a message/rfc822 are skipped.
+    pub fn digest_messages(&self) -> Option<Vec<Vec<u8>>> {
+        let multipart = self.multipart()?;
+        if self.content_type().mime_type != "multipart/digest" {
+            return multipart.parts.iter().find_map(Entity::digest_messages);
+        }
+        let messages: Vec<Vec<u8>> = multipart
+            .parts
+            .iter()
+            // in a digest, parts without Content-Type are messages (RFC 2046, 5.1.5)
+            .filter(|part| {
+                part.header("Content-Type").is_none()
+                    || part.content_type().mime_type == "message/rfc822"
+            })
+            .map(Entity::decoded_body)
+            .filter(|message| !message.is_empty())
+            .collect();
+        (!messages.is_empty()).then_some(messages)
+    }
+
+    /// Append `footer` to the text of the message: to each alternative of a
+    /// multipart/alternative, and to the first part (the main text) of other multiparts.
+    /// In HTML, it is inserted before `</body>`. Signed or encrypted multiparts, attachments
+    /// and other content are left unchanged. Returns whether anything was changed.
+    pub fn add_footer(&mut self, footer: &str) -> bool {
+        if self.is_attachment() {
+            return false;
+        }
+        let mut content_type = self.content_type();
+        if let Some(mut multipart) = self.multipart() {
+            let changed = match content_type.mime_type.as_str() {
+                // changing the content would break the signature
+                "multipart/signed" | "multipart/encrypted" => false,
+                "multipart/alternative" => multipart
+                    .parts
+                    .iter_mut()
+                    .fold(false, |changed, part| part.add_footer(footer) | changed),
+                _ => multipart.parts[0].add_footer(footer),
+            };
+            if changed {
+                self.body = multipart.to_bytes();
+            }
+            return changed;
+        }
+        let charset = content_type.param("charset").unwrap_or("us-ascii");
+        if !is_known_charset(charset) {
+            return false;
+        }
+        let text = decode_charset(&self.decoded_body(), charset);
+        let footer = footer.replace("\r\n", "\n");
+        let text = match content_type.mime_type.as_str() {
+            "text/plain" => {
+                let mut text = text.trim_end_matches(['\r', '\n']).to_owned();
+                text.push_str("\r\n\r\n");
+                text.push_str(&footer.replace('\n', "\r\n"));
+                text.push_str("\r\n");
+                text
+            }
+            "text/html" => {
+                let escaped = footer
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('\n', "<br>\r\n");
+                let footer = format!("<p>{}</p>\r\n", escaped);
+                let mut text = text;
+                match text.to_ascii_lowercase().rfind("</body>") {
+                    Some(end) => text.insert_str(end, &footer),
+                    None => text.push_str(&footer),
+                }
+                text
+            }
+            _ => return false,
+        };
+        // the text is UTF-8 now, which the charset has to tell, unless it is all ASCII
+        if !text.is_ascii() && !charset.eq_ignore_ascii_case("utf-8") {
+            content_type.params.retain(|(name, _)| name != "charset");
+            content_type
+                .params
+                .push(("charset".to_owned(), "utf-8".to_owned()));
+            self.set_header("Content-Type", &content_type.to_header_value());
+        }
+        let transfer_encoding = match self.transfer_encoding().as_str() {
+            encoding @ ("base64" | "quoted-printable" | "8bit" | "binary") => encoding.to_owned(),
+            _ if text.is_ascii() => "7bit".to_owned(),
+            _ => "quoted-printable".to_owned(),
+        };
+        if transfer_encoding == "7bit" && self.header("Content-Transfer-Encoding").is_none() {
+            self.body = text.into_bytes();
+        } else {
+            self.set_decoded_body(text.as_bytes(), &transfer_encoding);
+        }
+        true
+    }
+
+    /// Replace the body with the given (decoded) content, using the given transfer encoding.
+    pub fn set_decoded_body(&mut self, content: &[u8], transfer_encoding: &str) {
+        self.body = match transfer_encoding {
+            "base64" => encode_base64_lines(content),
+            "quoted-printable" => encode_quoted_printable(content),
+            _ => content.to_vec(),
+        };
+        self.set_header("Content-Transfer-Encoding", transfer_encoding);
+    }
+
+    /// Re-encode all 8bit content of this entity, so the result is pure 7bit ASCII.
+    /// Returns whether anything had to be changed.
+    pub fn make_7bit(&mut self) -> bool {
+        let mut changed = false;
+        for header in self.headers.iter_mut() {
+            if !header.raw_value.is_ascii() {
+                let value = header.value();
+                *header = Header::new(&header.name, &encode_header_words(&value));
+                changed = true;
+            }
+        }
+
+        if let Some(mut multipart) = self.multipart() {
+            for part in multipart.parts.iter_mut() {
+                changed |= part.make_7bit();
+            }
+            if changed {
+                self.body = multipart.to_bytes();
+            }
+        } else if self.content_type().mime_type == "message/rfc822" {
+            // message/rfc822 may not be encoded (RFC 2046), so the enclosed message is converted
+            let mut message = Entity::parse(&self.decoded_body());
+            if message.make_7bit() || !self.body.is_ascii() {
+                self.body = message.to_bytes();
+                self.set_header("Content-Transfer-Encoding", "7bit");
+                changed = true;
+            }
+        } else if !self.body.is_ascii() {
+            let content = self.decoded_body();
+            if self.content_type().is_text() {
+                self.set_decoded_body(&content, "quoted-printable");
+            } else {
+                self.set_decoded_body(&content, "base64");
+            }
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// The body of a multipart entity, split at its boundary.
+#[derive(Clone, Debug)]
+pub struct Multipart {
+    pub boundary: String,
+    pub preamble: Vec<u8>,
+    pub parts: Vec<Entity>,
+    pub epilogue: Vec<u8>,
+    /// Whether the body ended with the closing delimiter
+    pub closed: bool,
+}
+impl Multipart {
+    pub fn parse(body: &[u8], boundary: &str) -> Option<Self> {
+        let delimiter = format!("--{}", boundary);
+        let mut preamble = Vec::new();
+        let mut parts = Vec::new();
+        let mut current: Option<Vec<u8>> = None;
+        let mut closed = false;
+        let mut offset = 0;
+        for line in lines(body) {
+            offset += line.len();
+            let content = trim_line_break(line);
+            let is_delimiter = content.starts_with(delimiter.as_bytes())
+                && content[delimiter.len()..]
+                    .iter()
+                    .all(|b| matches!(b, b'-' | b' ' | b'\t'));
+            if is_delimiter {
+                if let Some(mut part) = current.take() {
+                    // the line break before the delimiter belongs to the delimiter
+                    let trimmed_len = trim_line_break(&part).len();
+                    part.truncate(trimmed_len);
+                    parts.push(Entity::parse(&part));
+                }
+                if content[delimiter.len()..].starts_with(b"--") {
+                    closed = true;
+                    break;
+                }
+                current = Some(Vec::new());
+                continue;
+            }
+            match current.as_mut() {
+                Some(part) => part.extend_from_slice(line),
+                None => preamble.extend_from_slice(line),
+            }
+        }
+        if let Some(part) = current {
+            // unterminated multipart, keep what we have
+            parts.push(Entity::parse(&part));
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(Self {
+            boundary: boundary.to_owned(),
+            preamble,
+            parts,
+            epilogue: if closed {
+                body[offset..].to_vec()
+            } else {
+                Vec::new()
+            },
+            closed,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.preamble.clone();
+        for part in &self.parts {
+            out.extend_from_slice(format!("--{}\r\n", self.boundary).as_bytes());
+            out.extend_from_slice(&part.to_bytes());
+            out.extend_from_slice(CRLF);
+        }
+        out.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        out.extend_from_slice(&self.epilogue);
+        out
+    }
+}
+
+fn encode_base64_lines(content: &[u8]) -> Vec<u8> {
+    let encoded = BASE64.encode(content);
+    let mut out = Vec::with_capacity(encoded.len() + encoded.len() / MAX_ENCODED_LINE_LEN * 2);
+    for line in encoded.as_bytes().chunks(MAX_ENCODED_LINE_LEN) {
+        out.extend_from_slice(line);
+        out.extend_from_slice(CRLF);
+    }
+    out
+}
+
+fn encode_quoted_printable(content: &[u8]) -> Vec<u8> {
+    // encode line by line, so the original line structure of the text is preserved
+    let mut out = Vec::with_capacity(content.len() * 3 / 2);
+    for line in lines(content) {
+        let text = trim_line_break(line);
+        out.extend_from_slice(&quoted_printable::encode(text));
+        if text.len() != line.len() {
+            out.extend_from_slice(CRLF);
+        }
+    }
+    out
+}
+
+/// A part of a mail with an `attachment` Content-Disposition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// Lowercase, e.g. `application/pdf`
+    pub mime_type: String,
+    pub filename: Option<String>,
+    /// Size of the (transfer-encoded) content in bytes
+    pub size: usize,
+}
+impl Attachment {
+    /// Whether the filename or the MIME type matches the pattern, in which `*` matches any
+    /// sequence. Compared case-insensitively.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.to_lowercase();
+        glob_match(&pattern, &self.mime_type)
+            || self
+                .filename
+                .as_ref()
+                .is_some_and(|filename| glob_match(&pattern, &filename.to_lowercase()))
+    }
+}
+
+/// Encode every whitespace-separated word of a header value that is not plain ASCII
+/// as an RFC 2047 encoded-word.
+pub fn encode_header_words(value: &str) -> String {
+    value
+        .split(' ')
+        .map(|word| {
+            if word.is_ascii() {
+                word.to_owned()
+            } else {
+                format!("=?utf-8?B?{}?=", BASE64.encode(word.as_bytes()))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether text in the given charset can be converted to UTF-8
+pub fn is_known_charset(charset: &str) -> bool {
+    encoding_rs::Encoding::for_label(charset.as_bytes()).is_some()
+}
+
+/// Convert text in the given charset to a String. Unknown charsets are treated as UTF-8.
+fn decode_charset(data: &[u8], charset: &str) -> String {
+    let encoding =
+        encoding_rs::Encoding::for_label(charset.as_bytes()).unwrap_or(encoding_rs::UTF_8);
+    encoding.decode_without_bom_handling(data).0.into_owned()
+}
+
+/// Decode the RFC 2047 encoded-word at the start of `word` (just after its `=?`).
+/// Returns the decoded text, and the length of the consumed encoded-word including `?=`.
+fn decode_encoded_word(word: &str) -> Option<(String, usize)> {
+    let (charset, rest) = word.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let text = &rest[..rest.find("?=")?];
+    // position of the closing `?=`, counted from the start of `word`
+    let end = word.len() - rest.len() + text.len();
+    // the encoded text is printable ASCII
+    if word[..end].contains(char::is_whitespace) || !text.is_ascii() {
+        return None;
+    }
+    let data = match encoding {
+        "B" | "b" => BASE64.decode(text).ok()?,
+        "Q" | "q" => {
+            let text = text.replace('_', " ");
+            quoted_printable::decode(text, quoted_printable::ParseMode::Robust).ok()?
+        }
+        _ => return None,
+    };
+    // RFC 2231 allows a language suffix: charset*lang
+    let charset = charset.split('*').next()?;
+    Some((decode_charset(&data, charset), end + 2))
+}
+
+/// Whether the header value contains something that starts like an encoded-word,
+/// but can not be decoded.
+fn has_invalid_encoded_words(value: &str) -> bool {
+    let mut rest = value;
+    while let Some(start) = rest.find("=?") {
+        match decode_encoded_word(&rest[start + 2..]) {
+            Some((_, consumed)) => rest = &rest[start + 2 + consumed..],
+            None => return true,
+        }
+    }
+    false
+}
+
+/// The addresses in a header value like `To`, e.g. `a@example.org` in
+/// `"Doe, Jane" <a@example.org>`, lowercased. Groups and comments are not supported.
+pub fn parse_addresses(value: &str) -> Vec<String> {
+    split_unquoted(value, ',')
+        .iter()
+        .filter_map(|mailbox| {
+            let address = match (mailbox.rfind('<'), mailbox.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+                _ => mailbox.as_str(),
+            };
+            let address = address.trim();
+            address.contains('@').then(|| address.to_lowercase())
+        })
+        .collect()
+}
+
+/// Decode all RFC 2047 encoded-words in a header value.
+/// Words that can not be decoded are kept as they are.
+pub fn decode_header_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_encoded_word = false;
+    while let Some(start) = rest.find("=?") {
+        let between = &rest[..start];
+        match decode_encoded_word(&rest[start + 2..]) {
+            Some((text, consumed)) => {
+                // whitespace between two adjacent encoded-words is not displayed
+                if !(after_encoded_word && between.trim().is_empty()) {
+                    out.push_str(between);
+                }
+                out.push_str(&text);
+                rest = &rest[start + 2 + consumed..];
+                after_encoded_word = true;
+            }
+            None => {
+                out.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                after_encoded_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART_MAIL: &str = "From: sender@example.org\r\n\
+        Subject: =?utf-8?B?VGVzdA==?=\r\n\
+        Content-Type: multipart/alternative;\r\n boundary=\"XYZ\"\r\n\
+        \r\n\
+        preamble\r\n\
+        --XYZ\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 8bit\r\n\
+        \r\n\
+        Grüße\r\n\
+        --XYZ\r\n\
+        Content-Type: text/html; charset=utf-8\r\n\
+        \r\n\
+        <b>plain ascii</b>\r\n\
+        --XYZ--\r\n";
+
+    #[test]
+    fn test_roundtrip_is_lossless() {
+        let entity = Entity::parse(MULTIPART_MAIL.as_bytes());
+        assert_eq!(entity.to_bytes(), MULTIPART_MAIL.as_bytes());
+        let multipart = entity.multipart().unwrap();
+        assert_eq!(multipart.boundary, "XYZ");
+        assert_eq!(multipart.parts.len(), 2);
+        assert_eq!(multipart.to_bytes(), entity.body);
+    }
+
+    #[test]
+    fn test_header_unfolding() {
+        let entity = Entity::parse(MULTIPART_MAIL.as_bytes());
+        let content_type = entity.content_type();
+        assert_eq!(content_type.mime_type, "multipart/alternative");
+        assert_eq!(content_type.param("boundary"), Some("XYZ"));
+
+        let headers = Entity::parse_headers(MULTIPART_MAIL.as_bytes());
+        assert_eq!(
+            headers.header("Content-Type"),
+            entity.header("Content-Type")
+        );
+        assert!(headers.body.is_empty());
+    }
+
+    #[test]
+    fn test_make_7bit() {
+        let mut entity = Entity::parse(MULTIPART_MAIL.as_bytes());
+        assert!(entity.make_7bit());
+        let converted = entity.to_bytes();
+        assert!(converted.is_ascii());
+
+        let converted = Entity::parse(&converted);
+        let parts = converted.multipart().unwrap().parts;
+        assert_eq!(parts[0].transfer_encoding(), "quoted-printable");
+        assert_eq!(parts[0].decoded_body(), "Grüße".as_bytes());
+        // untouched parts are kept as they were
+        assert_eq!(parts[1].headers.len(), 1);
+        assert_eq!(parts[1].body, b"<b>plain ascii</b>");
+    }
+
+    #[test]
+    fn test_make_7bit_keeps_ascii_mail() {
+        let mail = "Subject: ascii\r\n\r\nbody\r\n";
+        let mut entity = Entity::parse(mail.as_bytes());
+        assert!(!entity.make_7bit());
+        assert_eq!(entity.to_bytes(), mail.as_bytes());
+    }
+
+    #[test]
+    fn test_strip_attachments() {
+        let mail = "Subject: files\r\n\
+            Content-Type: multipart/mixed; boundary=\"OUT\"\r\n\
+            \r\n\
+            --OUT\r\n\
+            Content-Type: multipart/alternative; boundary=\"IN\"\r\n\
+            \r\n\
+            --IN\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            text\r\n\
+            --IN\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: attachment; filename=\"notes.txt\"\r\n\
+            \r\n\
+            nested attachment\r\n\
+            --IN--\r\n\
+            --OUT\r\n\
+            Content-Type: application/pdf\r\n\
+            Content-Disposition: ATTACHMENT; filename=\"doc.pdf\"\r\n\
+            \r\n\
+            JVBERi0=\r\n\
+            --OUT--\r\n";
+        let mut entity = Entity::parse(mail.as_bytes());
+        let attachments = entity.attachments();
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].filename.as_deref(), Some("notes.txt"));
+        assert!(attachments[1].matches("*.PDF"));
+        assert!(attachments[1].matches("application/*"));
+        assert!(!attachments[0].matches("*.pdf"));
+
+        assert!(entity.strip_attachments());
+        assert!(entity.attachments().is_empty());
+        let stripped = Entity::parse(&entity.to_bytes());
+        let parts = stripped.multipart().unwrap().parts;
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].multipart().unwrap().parts.len(), 1);
+        assert_eq!(stripped.text_content().as_deref(), Some("text"));
+
+        assert!(!entity.strip_attachments());
+    }
+
+    #[test]
+    fn test_digest_messages() {
+        let mail = "Subject: list digest\r\n\
+            Content-Type: multipart/mixed; boundary=\"OUT\"\r\n\
+            \r\n\
+            --OUT\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Today's topics\r\n\
+            --OUT\r\n\
+            Content-Type: multipart/digest; boundary=\"DIGEST\"\r\n\
+            \r\n\
+            --DIGEST\r\n\
+            \r\n\
+            Subject: first\r\n\
+            \r\n\
+            one\r\n\
+            --DIGEST\r\n\
+            Content-Type: message/rfc822\r\n\
+            \r\n\
+            Subject: second\r\n\
+            \r\n\
+            two\r\n\
+            --DIGEST\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            not a message\r\n\
+            --DIGEST--\r\n\
+            --OUT--\r\n";
+        let messages = Entity::parse(mail.as_bytes()).digest_messages().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"Subject: first\r\n\r\none");
+        assert_eq!(
+            Entity::parse(&messages[1]).header("Subject").as_deref(),
+            Some("second")
+        );
+
+        assert!(Entity::parse(MULTIPART_MAIL.as_bytes())
+            .digest_messages()
+            .is_none());
+    }
+
+    #[test]
+    fn test_add_footer() {
+        let mut entity = Entity::parse(MULTIPART_MAIL.as_bytes());
+        assert!(entity.add_footer("Forwarded from <work>\nby idlemail"));
+        let parts = entity.multipart().unwrap().parts;
+        assert_eq!(
+            parts[0].decoded_body(),
+            "Grüße\r\n\r\nForwarded from <work>\r\nby idlemail\r\n".as_bytes()
+        );
+        assert_eq!(
+            parts[1].decoded_body(),
+            b"<b>plain ascii</b><p>Forwarded from &lt;work&gt;<br>\r\nby idlemail</p>\r\n"
+        );
+
+        // only the main text of a mixed multipart, with the charset changed for the footer
+        let mail = "Content-Type: multipart/mixed; boundary=\"OUT\"\r\n\
+            \r\n\
+            --OUT\r\n\
+            Content-Type: text/html; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            PGh0bWw+PGJvZHk+/DwvYm9keT48L2h0bWw+\r\n\
+            --OUT\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            second text\r\n\
+            --OUT\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: attachment; filename=\"notes.txt\"\r\n\
+            \r\n\
+            notes\r\n\
+            --OUT--\r\n";
+        let mut entity = Entity::parse(mail.as_bytes());
+        assert!(entity.add_footer("Grüße"));
+        let parts = entity.multipart().unwrap().parts;
+        assert_eq!(parts[0].content_type().param("charset"), Some("utf-8"));
+        assert_eq!(parts[0].transfer_encoding(), "base64");
+        assert_eq!(
+            parts[0].decoded_body(),
+            "<html><body>ü<p>Grüße</p>\r\n</body></html>".as_bytes()
+        );
+        assert_eq!(parts[1].body, b"second text");
+        assert_eq!(parts[2].body, b"notes");
+
+        let mail = "Content-Type: multipart/signed; boundary=\"S\"\r\n\r\n\
+            --S\r\nContent-Type: text/plain\r\n\r\nsigned\r\n--S--\r\n";
+        let mut entity = Entity::parse(mail.as_bytes());
+        assert!(!entity.add_footer("footer"));
+        assert_eq!(entity.to_bytes(), mail.as_bytes());
+    }
+
+    #[test]
+    fn test_problems() {
+        assert!(Entity::parse(MULTIPART_MAIL.as_bytes())
+            .problems()
+            .is_empty());
+
+        let mail = "Subject: =?utf-8?X?broken?=\r\n\
+            Content-Type: multipart/mixed; boundary=\"XYZ\"\r\n\
+            \r\n\
+            --XYZ\r\n\
+            Content-Type: text/plain; charset=no-such-charset\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            not base64!\r\n";
+        let problems = Entity::parse(mail.as_bytes()).problems();
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("message: Subject header"));
+        assert!(problems[1].starts_with("message: closing boundary"));
+        assert!(problems[2].starts_with("part 1: body is not valid base64"));
+        assert!(problems[3].starts_with("part 1: unknown charset"));
+    }
+
+    #[test]
+    fn test_text_content_fallback_charset() {
+        let mail = b"Content-Type: text/plain\r\nContent-Transfer-Encoding: 8bit\r\n\r\n\xc1\r\n";
+        let message = Entity::parse(mail);
+        assert_eq!(message.text_content().as_deref(), Some("\u{c1}\r\n"));
+        assert_eq!(
+            message
+                .text_content_with_fallback(Some("koi8-r"))
+                .as_deref(),
+            Some("\u{430}\r\n")
+        );
+        // a declared charset takes precedence
+        let mail = b"Content-Type: text/plain; charset=iso-8859-1\r\n\r\n\xc1";
+        assert_eq!(
+            Entity::parse(mail)
+                .text_content_with_fallback(Some("koi8-r"))
+                .as_deref(),
+            Some("\u{c1}")
+        );
+    }
+
+    #[test]
+    fn test_decode_header_words() {
+        assert_eq!(decode_header_words("plain text"), "plain text");
+        assert_eq!(decode_header_words("=?utf-8?B?VGVzdA==?="), "Test");
+        assert_eq!(
+            decode_header_words("Re: =?ISO-8859-1?Q?Gr=FC=DFe?= =?utf-8?q?_aus_M=C3=BCnchen?="),
+            "Re: Grüße aus München"
+        );
+        assert_eq!(decode_header_words("=?broken"), "=?broken");
+        // RFC 2231 language suffix
+        assert_eq!(decode_header_words("=?utf-8*en?Q?abc?= tail"), "abc tail");
+        assert_eq!(
+            decode_header_words("=?utf-8*de?Q?Gr=C3=BC=C3=9Fe?= x"),
+            "Grüße x"
+        );
+        assert_eq!(
+            decode_header_words("=?utf-8*en?Q?é?= tail"),
+            "=?utf-8*en?Q?é?= tail"
+        );
+        assert!(!has_invalid_encoded_words(
+            "=?utf-8*de?Q?Gr=C3=BC=C3=9Fe?= =?utf-8?B?VGVzdA==?="
+        ));
+        assert!(has_invalid_encoded_words("=?utf-8*en?Q?é?="));
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses(
+                "\"Doe, Jane\" <Jane@Example.org>, alerts@example.org,\r\n <x@example.org>"
+            ),
+            ["jane@example.org", "alerts@example.org", "x@example.org"]
+        );
+        assert!(parse_addresses("undisclosed-recipients:;").is_empty());
+    }
+}
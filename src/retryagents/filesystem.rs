@@ -1,35 +1,35 @@
 use crate::{
     config::FilesystemRetryAgentConfig,
+    error::ErrorReport,
     hub::{Mail, MailAgent, RetryAgentMessage},
 };
-use anyhow::Result;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Result};
 use log::{debug, error, info, warn};
-use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::VecDeque,
     fs,
+    path::Path,
     sync::mpsc,
     thread,
     time::{Duration, SystemTime},
 };
 
-use super::MailRetryAgent;
+use super::{retry_delay, MailRetryAgent, QueuedRetryMailModel};
+
+const NONCE_LEN: usize = 12;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
-struct QueuedRetryMailModel {
-    pub due_time: SystemTime,
-    pub dstname: String,
-    pub mail_from_src: String,
-    pub mail_data: Vec<u8>,
-}
 impl From<&QueuedRetryMail> for QueuedRetryMailModel {
     fn from(retry_mail: &QueuedRetryMail) -> Self {
         Self {
             due_time: retry_mail.due_time,
             dstname: retry_mail.dstname.clone(),
             mail_from_src: retry_mail.mail.from_src.clone(),
-            mail_data: retry_mail.mail.data.clone(),
+            mail_data: retry_mail.mail.data.to_vec(),
+            nonce: None,
         }
     }
 }
@@ -44,45 +44,192 @@ struct QueuedRetryMail {
 pub struct FilesystemRetryAgent {
     log_target: String,
     config: FilesystemRetryAgentConfig,
+    cipher: Option<Aes256Gcm>,
     worker: Option<thread::JoinHandle<()>>,
 }
 impl FilesystemRetryAgent {
-    pub fn new(config: &FilesystemRetryAgentConfig) -> Self {
-        Self {
+    pub fn new(config: &FilesystemRetryAgentConfig) -> Result<Self> {
+        let cipher = match &config.encryption_key {
+            Some(key_source) => {
+                let key = key_source
+                    .load()
+                    .map_err(|e| anyhow!("Failed to load the encryption key: {}", e))?;
+                Some(Aes256Gcm::new_from_slice(&key)?)
+            }
+            None => None,
+        };
+        Ok(Self {
             log_target: "RetryAgent[Filesystem]".to_string(),
             config: config.clone(),
+            cipher,
             worker: None,
-        }
+        })
     }
 
     fn load_from_fs(&self) -> Result<Vec<QueuedRetryMail>> {
-        let mail_files: Vec<QueuedRetryMail> = fs::read_dir(&self.config.path)?
-			.filter_map(|file| {
-				let file_path = file.ok()?.path();
-				let file_path_str = file_path.to_str()?.to_owned();
-				if !file_path_str.ends_with(".json") {
-					return None;
-				}
-				let file_reader = match fs::File::open(file_path) {
-					Ok(file_reader) => file_reader,
-					Err(e) => {
-						error!(target: &self.log_target, "Failed to open retry-file: {}\n{}", file_path_str, e);
-						return None;
-					}
-				};
-				let retry_mail: QueuedRetryMailModel = serde_json::from_reader(file_reader).ok()?;
-				info!(target: &self.log_target, "Successfully parsed retry-file: {}", file_path_str);
-				Some(QueuedRetryMail {
-					due_time: retry_mail.due_time,
-					dstname: retry_mail.dstname,
-					mail: Mail::from_rfc822(retry_mail.mail_from_src, retry_mail.mail_data),
-					file_path: file_path_str
-				})
-			})
-			.collect();
-        Ok(mail_files)
+        load_from_fs(&self.log_target, &self.config.path, self.cipher.as_ref())
+    }
+}
+
+/// Read all retry-files in the folder `path`, without decrypting them.
+/// Returns the path of every file, together with its content.
+fn read_from_fs(log_target: &str, path: &str) -> Result<Vec<(String, QueuedRetryMailModel)>> {
+    let mail_files = fs::read_dir(path)?
+        .filter_map(|file| {
+            let file_path = file.ok()?.path();
+            let file_path_str = file_path.to_str()?.to_owned();
+            if !file_path_str.ends_with(".json") {
+                return None;
+            }
+            let file_reader = match fs::File::open(file_path) {
+                Ok(file_reader) => file_reader,
+                Err(e) => {
+                    error!(target: log_target, "Failed to open retry-file: {}\n{}", file_path_str, e);
+                    return None;
+                }
+            };
+            let retry_mail: QueuedRetryMailModel = serde_json::from_reader(file_reader).ok()?;
+            Some((file_path_str, retry_mail))
+        })
+        .collect();
+    Ok(mail_files)
+}
+
+fn load_from_fs(
+    log_target: &str,
+    path: &str,
+    cipher: Option<&Aes256Gcm>,
+) -> Result<Vec<QueuedRetryMail>> {
+    let mail_files = read_from_fs(log_target, path)?
+        .into_iter()
+        .filter_map(|(file_path, retry_mail)| {
+            let mail_data = match (&retry_mail.nonce, cipher) {
+                (None, _) => retry_mail.mail_data,
+                (Some(_), None) => {
+                    error!(
+                        target: log_target,
+                        "Retry-file is encrypted, but no encryption_key is configured: {}", file_path
+                    );
+                    return None;
+                }
+                (Some(nonce), Some(cipher)) => {
+                    match decrypt(cipher, nonce, &retry_mail) {
+                        Ok(mail_data) => mail_data,
+                        Err(e) => {
+                            error!(target: log_target, "Failed to decrypt retry-file: {}\n{}", file_path, e);
+                            return None;
+                        }
+                    }
+                }
+            };
+            info!(target: log_target, "Successfully parsed retry-file: {}", file_path);
+            Some(QueuedRetryMail {
+                due_time: retry_mail.due_time,
+                dstname: retry_mail.dstname,
+                mail: Mail::from_rfc822(retry_mail.mail_from_src, mail_data),
+                file_path,
+            })
+        })
+        .collect();
+    Ok(mail_files)
+}
+
+/// Data authenticated together with the encrypted mail, so a retry-file can not be
+/// redirected to another destination or source without failing to decrypt.
+fn associated_data(model: &QueuedRetryMailModel) -> Vec<u8> {
+    let mut aad = (model.dstname.len() as u64).to_le_bytes().to_vec();
+    aad.extend_from_slice(model.dstname.as_bytes());
+    aad.extend_from_slice(model.mail_from_src.as_bytes());
+    aad
+}
+
+fn encrypt(cipher: &Aes256Gcm, model: &mut QueuedRetryMailModel) -> Result<()> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: &model.mail_data,
+        aad: &associated_data(model),
+    };
+    model.mail_data = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| anyhow!("Failed to encrypt retry-mail"))?;
+    model.nonce = Some(nonce.to_vec());
+    Ok(())
+}
+
+fn decrypt(cipher: &Aes256Gcm, nonce: &[u8], model: &QueuedRetryMailModel) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_LEN {
+        return Err(anyhow!("Invalid nonce length: {}", nonce.len()));
+    }
+    let payload = Payload {
+        msg: &model.mail_data,
+        aad: &associated_data(model),
+    };
+    cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow!("Authentication failed. Wrong key, or the file was modified"))
+}
+
+/// Attempt to find a non-taken filename starting with `name` in the folder `path`,
+/// and store the retry-mail there. On success, the path of the created file is returned.
+fn store_to_fs(path: &str, name: &str, model: &QueuedRetryMailModel) -> Result<String> {
+    for i in 0..10 {
+        // try 10 append-indices against hash-collision
+        let file_name = format!("{}/{}-{}.json", path, name, i);
+        if let Ok(retry_file) = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_name)
+        {
+            if let Err(e) = serde_json::to_writer(retry_file, model) {
+                // a partial file would be loaded as a corrupt retry-mail on the next start
+                let _ = fs::remove_file(&file_name);
+                return Err(anyhow!(
+                    "Failed to write retry-mail file: {}\n{}",
+                    file_name,
+                    e
+                ));
+            }
+            return Ok(file_name);
+        }
+    }
+    Err(anyhow!(
+        "Failed to find a free filename for retry-mail {}",
+        name
+    ))
+}
+
+/// Copy all mails queued by a FilesystemRetryAgent in the folder `from` into the folder `to`.
+/// Mails are only removed from `from` after they were successfully stored in `to`.
+/// Encrypted mails are copied as they are, so no key is required.
+/// Returns the number of migrated mails.
+pub fn migrate_queue(from: &str, to: &str) -> Result<usize> {
+    let log_target = "RetryAgent[Filesystem]";
+    if !Path::new(to).is_dir() {
+        return Err(anyhow!("Target folder does not exist: {}", to));
+    }
+    if fs::canonicalize(from)? == fs::canonicalize(to)? {
+        return Err(anyhow!("Source and target folder are the same"));
+    }
+    let mut migrated = 0;
+    for (old_path, retry_mail) in read_from_fs(log_target, from)? {
+        // keep the name, without the append-index
+        let name = Path::new(&old_path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.rsplit_once('-'))
+            .map(|(name, _)| name.to_owned())
+            .unwrap_or_else(|| format!("migrated_to_{}", retry_mail.dstname));
+        let file_path = store_to_fs(to, &name, &retry_mail)?;
+        info!(
+            target: log_target,
+            "Migrated retry-mail {} to: {}", old_path, file_path
+        );
+        fs::remove_file(&old_path)?;
+        migrated += 1;
     }
+    Ok(migrated)
 }
+
 impl MailAgent for FilesystemRetryAgent {
     fn join(&mut self) {
         self.worker
@@ -96,6 +243,7 @@ impl MailRetryAgent for FilesystemRetryAgent {
     fn start(&mut self, channel: crate::hub::HubRetryAgentChannel) {
         let config = self.config.clone();
         let log_target = self.log_target.clone();
+        let cipher = self.cipher.clone();
         info!(
             target: &log_target,
             "Loading messages from folder: {}", config.path
@@ -105,7 +253,8 @@ impl MailRetryAgent for FilesystemRetryAgent {
             Err(e) => {
                 error!(
                     target: &log_target,
-                    "Failed to load retry-mails from filesystem:\n{}", e
+                    "Failed to load retry-mails from filesystem:\n{}",
+                    ErrorReport(&e)
                 );
                 Vec::new()
             }
@@ -117,57 +266,64 @@ impl MailRetryAgent for FilesystemRetryAgent {
             let mut queue: VecDeque<QueuedRetryMail> = VecDeque::from(restored_mails);
 
             let mut suspended = false;
+            // mails that could not be stored
+            let mut lost = 0;
 
             loop {
                 match channel.next_timeout(Duration::from_secs(1)) {
                     Err(mpsc::RecvTimeoutError::Timeout) => {}
-                    Err(mpsc::RecvTimeoutError::Disconnected) => break, // shutdown
-                    Ok(RetryAgentMessage::QueueMail { dstname, mail }) => {
-                        let retransmission_timepoint =
-                            SystemTime::now() + Duration::from_secs(config.delay);
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        // shutdown, everything still queued is stored in the folder
+                        channel.report_shutdown(queue.len(), lost);
+                        break;
+                    }
+                    Ok(RetryAgentMessage::QueueMail {
+                        dstname,
+                        mail,
+                        min_delay,
+                    }) => {
+                        let delay = retry_delay(config.delay, config.jitter, min_delay);
+                        let retransmission_timepoint = SystemTime::now() + delay;
                         info!(
                             target: &log_target,
-                            "Queueing mail {} for retransmission in {}s", mail.hash, config.delay
+                            "Queueing mail {} for retransmission in {}s", mail, delay.as_secs()
                         );
 
-                        // construct QueuedRetryMail structure, and attempt to find a non-taken filename
-                        // for it in our designated filesystem path.
+                        // construct QueuedRetryMail structure, and store it in our
+                        // designated filesystem path.
                         let mut retry_mail = QueuedRetryMail {
                             due_time: retransmission_timepoint,
                             dstname,
                             mail,
                             file_path: "".to_owned(),
                         };
-                        for i in 0..10 {
-                            // try 10 append-indices against hash-collision
-                            let file_name = format!(
-                                "{}/{}_to_{}-{}.json",
-                                config.path, &retry_mail.mail.hash, retry_mail.dstname, i
-                            );
-                            if let Ok(retry_file) = fs::File::create(&file_name) {
-                                retry_mail.file_path = file_name.clone();
-                                match serde_json::to_writer(
-                                    retry_file,
-                                    &QueuedRetryMailModel::from(&retry_mail),
-                                ) {
-                                    Ok(_) => {
-                                        debug!(
-                                            target: &log_target,
-                                            "Stored retry-mail in: {}", retry_mail.file_path
-                                        );
-                                        queue.push_back(retry_mail);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        error!(
-                                            target: &log_target,
-                                            "Failed to create retry-mail file: {}\n{}",
-                                            retry_mail.file_path,
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                }
+                        let mut model = QueuedRetryMailModel::from(&retry_mail);
+                        let name = format!("{}_to_{}", retry_mail.mail.hash, retry_mail.dstname);
+                        let stored = match &cipher {
+                            Some(cipher) => encrypt(cipher, &mut model),
+                            None => Ok(()),
+                        }
+                        .and_then(|_| store_to_fs(&config.path, &name, &model));
+                        match stored {
+                            Ok(file_path) => {
+                                debug!(
+                                    target: &log_target,
+                                    "Stored retry-mail in: {}", file_path
+                                );
+                                retry_mail.file_path = file_path;
+                                // keep the queue sorted, some mails need a longer delay
+                                let index = queue.partition_point(|queued| {
+                                    queued.due_time <= retry_mail.due_time
+                                });
+                                queue.insert(index, retry_mail);
+                            }
+                            Err(e) => {
+                                error!(
+                                    target: &log_target,
+                                    "Failed to create retry-mail file:\n{}",
+                                    ErrorReport(&e)
+                                );
+                                lost += 1;
                             }
                         }
                     }
@@ -186,7 +342,7 @@ impl MailRetryAgent for FilesystemRetryAgent {
                             let mail = queue.pop_front().unwrap();
                             info!(
                                 target: &log_target,
-                                "Mail {} due for retransmission. Queueing.", mail.mail.hash
+                                "Mail {} due for retransmission. Queueing.", mail.mail
                             );
                             channel.notify_retry_mail(mail.dstname, mail.mail);
                             if let Err(e) = fs::remove_file(&mail.file_path) {
@@ -201,7 +357,7 @@ impl MailRetryAgent for FilesystemRetryAgent {
                                 );
                             }
                         } else {
-                            // The mails are stored in the order in which they were queued.
+                            // The mails are sorted by due time.
                             // If the first isn't due, neither is every mail behind that.
                             break;
                         }
@@ -2,15 +2,140 @@ use crate::{
     config::MemoryRetryAgentConfig,
     hub::{Mail, MailAgent, RetryAgentMessage},
 };
-use log::{info, warn};
+use anyhow::Result;
+use log::{debug, error, info, warn};
 use std::{
     collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
     sync::mpsc,
     thread,
     time::{Duration, SystemTime},
 };
 
-use super::MailRetryAgent;
+use super::{retry_delay, MailRetryAgent, QueuedRetryMailModel};
+
+/// File that takes the mails which do not fit into the in-memory queue anymore.
+/// Every line holds one json-serialized mail, in the order in which they were queued.
+/// Mails are taken from the front by advancing a read offset. The file is only rewritten
+/// without them when the agent stops, and removed once all mails are taken.
+struct SpillFile {
+    path: String,
+    len: usize,
+    /// Byte offset of the first mail not taken yet
+    offset: u64,
+}
+impl SpillFile {
+    /// Open the spill file at `path`. Mails left over from a previous run are kept.
+    fn open(path: &str) -> Self {
+        let len = match fs::File::open(path) {
+            Ok(file) => BufReader::new(file).split(b'\n').count(),
+            Err(_) => 0,
+        };
+        Self {
+            path: path.to_owned(),
+            len,
+            offset: 0,
+        }
+    }
+
+    fn push(&mut self, entry: &QueuedRetryMailModel) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take the `cnt` oldest mails from the spill file. Lines that can not be parsed are
+    /// skipped with a warning, so they do not block the mails behind them.
+    fn take(&mut self, cnt: usize, log_target: &str) -> Result<Vec<QueuedRetryMailModel>> {
+        let mut file = fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let taken = self.read_entries(BufReader::new(file), cnt, log_target)?;
+        if self.len == 0 {
+            match fs::remove_file(&self.path) {
+                Ok(()) => self.offset = 0,
+                // the offset stays behind the taken mails, which are not read again
+                Err(e) => error!(
+                    target: log_target,
+                    "Failed to remove spill file: {}: {}", self.path, e
+                ),
+            }
+        }
+        Ok(taken)
+    }
+
+    /// Read up to `cnt` mails from `reader`, which is positioned at `offset`. The offset only
+    /// advances over completely read lines. If reading fails after some mails were read,
+    /// they are returned, and the rest is read by the next call.
+    fn read_entries(
+        &mut self,
+        mut reader: impl BufRead,
+        cnt: usize,
+        log_target: &str,
+    ) -> Result<Vec<QueuedRetryMailModel>> {
+        let mut taken = Vec::new();
+        let mut line = Vec::new();
+        while taken.len() < cnt {
+            line.clear();
+            let read = match reader.read_until(b'\n', &mut line) {
+                Ok(read) => read,
+                Err(e) if !taken.is_empty() => {
+                    error!(
+                        target: log_target,
+                        "Failed to read spill file: {}: {}", self.path, e
+                    );
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if read == 0 {
+                self.len = 0;
+                break;
+            }
+            self.offset += read as u64;
+            self.len = self.len.saturating_sub(1);
+            match serde_json::from_slice(&line) {
+                Ok(entry) => taken.push(entry),
+                Err(e) => warn!(
+                    target: log_target,
+                    "Skipping unreadable mail in spill file: {}: {}", self.path, e
+                ),
+            }
+        }
+        Ok(taken)
+    }
+
+    /// Remove the mails taken already from the file, so the next run does not queue them
+    /// again.
+    fn compact(&mut self) -> Result<()> {
+        if self.offset == 0 {
+            return Ok(());
+        }
+        let mut file = fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let tmp_path = format!("{}.tmp", self.path);
+        io::copy(&mut file, &mut fs::File::create(&tmp_path)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+/// Insert the entry behind all entries due no later than it, keeping the queue sorted.
+/// Most mails are due in the order they are queued in, but some need a longer delay.
+fn insert_by_due_time(
+    queue: &mut VecDeque<(SystemTime, String, Mail)>,
+    entry: (SystemTime, String, Mail),
+) {
+    let index = queue.partition_point(|queued| queued.0 <= entry.0);
+    queue.insert(index, entry);
+}
 
 pub struct MemoryRetryAgent {
     log_target: String,
@@ -40,6 +165,15 @@ impl MailRetryAgent for MemoryRetryAgent {
         let config = self.config.clone();
         let log_target = self.log_target.clone();
 
+        let mut spill_file = config.spill_path.as_deref().map(SpillFile::open);
+        if let Some(spill_file) = spill_file.as_ref().filter(|f| f.len > 0) {
+            info!(
+                target: &log_target,
+                "Found {} mails in spill file: {}", spill_file.len, spill_file.path
+            );
+        }
+        let max_queue_len = config.max_queue_len.unwrap_or(usize::MAX);
+
         self.worker = Some(thread::spawn(move || {
             let mut queue: VecDeque<(SystemTime, String, Mail)> = VecDeque::new();
 
@@ -57,16 +191,72 @@ impl MailRetryAgent for MemoryRetryAgent {
                                 queue.len()
                             );
                         }
+                        let spilled = match spill_file.as_mut().filter(|f| f.len > 0) {
+                            Some(spill_file) => {
+                                if let Err(e) = spill_file.compact() {
+                                    error!(
+                                        target: &log_target,
+                                        "Failed to remove the reloaded mails from spill file: {}. They are queued again on the next start.\n{}",
+                                        spill_file.path,
+                                        e
+                                    );
+                                }
+                                info!(
+                                    target: &log_target,
+                                    "{} mails remain in spill file: {}", spill_file.len, spill_file.path
+                                );
+                                spill_file.len
+                            }
+                            None => 0,
+                        };
+                        channel.report_shutdown(spilled, queue.len());
                         break;
                     }
-                    Ok(RetryAgentMessage::QueueMail { dstname, mail }) => {
-                        let retransmission_timepoint =
-                            SystemTime::now() + Duration::from_secs(config.delay);
+                    Ok(RetryAgentMessage::QueueMail {
+                        dstname,
+                        mail,
+                        min_delay,
+                    }) => {
+                        let delay = retry_delay(config.delay, config.jitter, min_delay);
+                        let retransmission_timepoint = SystemTime::now() + delay;
                         info!(
                             target: &log_target,
-                            "Queueing mail for retransmission in {}s", config.delay
+                            "Queueing mail {} for retransmission in {}s", mail, delay.as_secs()
                         );
-                        queue.push_back((retransmission_timepoint, dstname, mail));
+                        match spill_file.as_mut() {
+                            // once spilling started, newer mails have to go behind the spilled ones
+                            Some(spill_file)
+                                if queue.len() >= max_queue_len || spill_file.len > 0 =>
+                            {
+                                let entry = QueuedRetryMailModel {
+                                    due_time: retransmission_timepoint,
+                                    dstname: dstname.clone(),
+                                    mail_from_src: mail.from_src.clone(),
+                                    mail_data: mail.data.to_vec(),
+                                    nonce: None,
+                                };
+                                match spill_file.push(&entry) {
+                                    Ok(_) => debug!(
+                                        target: &log_target,
+                                        "Queue full, spilled mail {} to disk", mail
+                                    ),
+                                    Err(e) => {
+                                        error!(
+                                            target: &log_target,
+                                            "Failed to spill mail to disk, keeping it in memory:\n{}", e
+                                        );
+                                        insert_by_due_time(
+                                            &mut queue,
+                                            (retransmission_timepoint, dstname, mail),
+                                        );
+                                    }
+                                }
+                            }
+                            _ => insert_by_due_time(
+                                &mut queue,
+                                (retransmission_timepoint, dstname, mail),
+                            ),
+                        }
                     }
                     Ok(RetryAgentMessage::Suspend) => {
                         info!(target: &log_target, "Suspending");
@@ -78,23 +268,107 @@ impl MailRetryAgent for MemoryRetryAgent {
                 if !suspended {
                     // see if any of the queued mails is due
                     let now = SystemTime::now();
-                    for i in 0..queue.len() {
-                        if queue.get(i).unwrap().0 < now {
+                    while let Some((due_time, _, _)) = queue.front() {
+                        if *due_time < now {
+                            let mail = queue.pop_front().unwrap();
                             info!(
                                 target: &log_target,
-                                "Mail due for retransmission. Queueing."
+                                "Mail {} due for retransmission. Queueing.", mail.2
                             );
-                            let mail = queue.pop_front().unwrap();
                             channel.notify_retry_mail(mail.1, mail.2)
                         } else {
-                            // The mails are stored in the order in which they were queued.
+                            // The mails are sorted by due time.
                             // If the first isn't due, neither is every mail behind that.
                             break;
                         }
                     }
+
+                    // refill the in-memory queue from disk, once there is room again
+                    if let Some(spill_file) = spill_file.as_mut() {
+                        if spill_file.len > 0 && queue.len() < max_queue_len {
+                            match spill_file.take(max_queue_len - queue.len(), &log_target) {
+                                Ok(entries) => {
+                                    debug!(
+                                        target: &log_target,
+                                        "Reloaded {} mails from spill file", entries.len()
+                                    );
+                                    queue.extend(entries.into_iter().map(|entry| {
+                                        (
+                                            entry.due_time,
+                                            entry.dstname,
+                                            Mail::from_rfc822(entry.mail_from_src, entry.mail_data),
+                                        )
+                                    }));
+                                    // mails left over from a previous run might be out of order
+                                    queue.make_contiguous().sort_by_key(|entry| entry.0);
+                                }
+                                Err(e) => {
+                                    error!(
+                                        target: &log_target,
+                                        "Failed to reload mails from spill file: {}. Leaving it untouched.\n{}",
+                                        spill_file.path,
+                                        e
+                                    );
+                                    // don't retry every second. New mails are appended again,
+                                    // once the in-memory queue is full.
+                                    spill_file.len = 0;
+                                }
+                            }
+                        }
+                    }
                 }
             }
             info!(target: &log_target, "Stopping");
         }));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Read;
+
+    /// Fails every read, like a disk error in the middle of the file
+    struct FailingReader;
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk error"))
+        }
+    }
+
+    #[test]
+    fn test_spill_file_read_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spill.jsonl");
+        let mut spill_file = SpillFile::open(path.to_str().unwrap());
+        for dstname in ["a", "b", "c"] {
+            let entry = QueuedRetryMailModel {
+                due_time: SystemTime::UNIX_EPOCH,
+                dstname: dstname.to_owned(),
+                mail_from_src: "src".to_owned(),
+                mail_data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+                nonce: None,
+            };
+            spill_file.push(&entry).unwrap();
+        }
+        let content = fs::read(&path).unwrap();
+        let first_line = content.iter().position(|b| *b == b'\n').unwrap() + 1;
+
+        // the first mail is returned, and the failed line is read again by the next call
+        let reader = BufReader::new((&content[..first_line]).chain(FailingReader));
+        let taken = spill_file.read_entries(reader, 3, "test").unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(spill_file.offset, first_line as u64);
+        assert_eq!(spill_file.len, 2);
+
+        // nothing read at all is an error, and takes nothing
+        let reader = BufReader::new(FailingReader);
+        assert!(spill_file.read_entries(reader, 3, "test").is_err());
+        assert_eq!(spill_file.offset, first_line as u64);
+
+        let taken = spill_file.take(3, "test").unwrap();
+        let dstnames: Vec<_> = taken.iter().map(|entry| entry.dstname.as_str()).collect();
+        assert_eq!(dstnames, ["b", "c"]);
+        assert!(!path.exists());
+    }
+}
@@ -1,4 +1,10 @@
 use crate::hub::{HubRetryAgentChannel, MailAgent};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
 
 pub mod filesystem;
 pub mod memory;
@@ -6,3 +12,31 @@ pub mod memory;
 pub trait MailRetryAgent: MailAgent {
     fn start(&mut self, channel: HubRetryAgentChannel);
 }
+
+/// Delay until a failed mail is retried: the configured `delay` (at least `min_delay`, if the
+/// destination asked for it), plus a random share of `jitter` seconds. Without jitter, all
+/// mails queued during an outage would be retried in a single burst.
+pub fn retry_delay(delay: u64, jitter: Option<u64>, min_delay: Option<Duration>) -> Duration {
+    let delay = Duration::from_secs(delay).max(min_delay.unwrap_or_default());
+    let jitter_ms = jitter.unwrap_or(0).saturating_mul(1000);
+    if jitter_ms == 0 {
+        return delay;
+    }
+    // the hasher's keys are random, which is enough to spread the mails
+    let random = RandomState::new().build_hasher().finish();
+    delay + Duration::from_millis(random % (jitter_ms + 1))
+}
+
+/// Serialization format of a mail queued for retry.
+/// Shared between all persisting RetryAgents, so queues can be migrated between them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueuedRetryMailModel {
+    pub due_time: SystemTime,
+    pub dstname: String,
+    pub mail_from_src: String,
+    pub mail_data: Vec<u8>,
+    /// Set if `mail_data` is encrypted (AES-256-GCM) with the agent's key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Vec<u8>>,
+}
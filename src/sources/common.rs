@@ -1,20 +1,78 @@
-use crate::config::AuthMethod;
+use super::transport::{ImapStream, ImapTransport};
+use crate::{
+    config::{AuthMethod, ImapConnectionConfig, ImapEncryption, ImapEndpoint, TlsVersion},
+    error::ErrorReport,
+    hub::{ConnectionLimit, ConnectionPermit, HubSourceChannel},
+    mime::{self, Attachment},
+};
 use anyhow::{anyhow, Context, Result};
-use async_imap::types::Seq;
-use async_native_tls::{TlsConnector, TlsStream};
+use async_imap::{
+    error::Error as ImapError,
+    imap_proto::{
+        BodyParams, BodyStructure, Capability, MailboxDatum, Response, ResponseCode, Status,
+    },
+    types::{Flag, Mailbox, NameAttribute, Seq},
+    Authenticator,
+};
+use async_native_tls::{Certificate, Protocol, TlsConnector, TlsStream};
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
 use async_std::{
+    future,
     net::TcpStream,
     sync::{Mutex, MutexGuard},
     task,
 };
-use futures::StreamExt;
-use std::{collections::VecDeque, vec};
+use futures::{future::join_all, AsyncRead, AsyncReadExt, AsyncWriteExt, StreamExt};
+use log::{debug, error, info, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fmt, fs,
+    io::Read,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{self as std_sync, mpsc, Arc, Weak},
+    time::{Duration, Instant},
+    vec,
+};
+use time::OffsetDateTime;
 
-pub type ImapClient = async_imap::Client<TlsStream<TcpStream>>;
+pub type ImapClient = async_imap::Client<ImapTransport>;
 pub type MailboxName = async_imap::types::Name;
-pub type ImapSession = async_imap::Session<TlsStream<TcpStream>>;
+pub type ImapSession = async_imap::Session<ImapTransport>;
 pub type ImapResult<T> = async_imap::error::Result<T>;
-pub type ImapIdleHandle = async_imap::extensions::idle::Handle<TlsStream<TcpStream>>;
+pub type ImapIdleHandle = async_imap::extensions::idle::Handle<ImapTransport>;
+
+/// Delay before the second attempt to establish the first connection at startup.
+const STARTUP_RETRY_DELAY_MIN: Duration = Duration::from_secs(1);
+/// Upper bound for the exponential backoff between startup connection attempts.
+const STARTUP_RETRY_DELAY_MAX: Duration = Duration::from_secs(60);
+/// Difference between the clocks of the server and this host, up to which the arrival date of
+/// a mail in the future is trusted, see `with_min_age`.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(3600);
+/// Time the server has to answer the logout when the connection is closed.
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of mails fetched from a mailbox between progress messages, see `progress_every`
+pub const DEFAULT_PROGRESS_EVERY: usize = 500;
+/// Times fetching a single mail is retried, see `with_fetch_retries`
+const DEFAULT_FETCH_RETRIES: u32 = 2;
+/// Time the token_command has to print the access token, before it is killed.
+const TOKEN_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Context attached to errors caused by the IMAP server not being reachable at all
+/// (e.g. the network is down), as opposed to errors from an established connection.
+#[derive(Debug)]
+pub struct ServerUnreachable;
+impl fmt::Display for ServerUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to connect to IMAP server.")
+    }
+}
+impl ServerUnreachable {
+    pub fn caused(err: &anyhow::Error) -> bool {
+        err.downcast_ref::<ServerUnreachable>().is_some()
+    }
+}
 
 struct SessionHandle<'a> {
     session: MutexGuard<'a, Option<ImapSession>>,
@@ -36,8 +94,44 @@ impl<'a> SessionHandle<'a> {
     }
 }
 
+/// Connections shared between sources, by a key identifying the account and all settings of
+/// the connection. A connection is closed once no source uses it anymore.
+#[derive(Clone, Default)]
+pub struct SharedConnections(Arc<std_sync::Mutex<HashMap<String, Weak<ImapConnection>>>>);
+impl SharedConnections {
+    /// The connection registered for `key`, or the one returned by `connect`, which is then
+    /// registered for it.
+    pub fn get_or_insert(
+        &self,
+        key: String,
+        connect: impl FnOnce() -> ImapConnection,
+    ) -> Arc<ImapConnection> {
+        let mut connections = self.0.lock().unwrap();
+        if let Some(con) = connections.get(&key).and_then(Weak::upgrade) {
+            return con;
+        }
+        let con = Arc::new(connect());
+        connections.insert(key, Arc::downgrade(&con));
+        con
+    }
+}
+
+/// Additional session of the pool, only used to fetch mails in parallel.
+#[derive(Default)]
+struct PooledSession {
+    session: Option<ImapSession>,
+    /// Name of the currently selected mailbox, and whether it was opened readonly (EXAMINE)
+    selected: Option<(String, bool)>,
+    /// Set when the server refused the connection. The slot is then not used anymore.
+    disabled: bool,
+}
+
 pub trait MailPath {
     fn path(&self) -> String;
+    /// Whether the mailbox is selected by the `/`-delimited path `filter`, see `path_filter_matches`.
+    fn matches_path_filter(&self, filter: &str) -> bool;
+    /// Whether the mailbox has any of the given LIST attributes (e.g. `\Trash`).
+    fn has_any_attribute(&self, attributes: &[String]) -> bool;
 }
 impl MailPath for MailboxName {
     fn path(&self) -> String {
@@ -46,47 +140,486 @@ impl MailPath for MailboxName {
             None => self.name().to_owned(),
         }
     }
+    fn matches_path_filter(&self, filter: &str) -> bool {
+        path_filter_matches(&self.path(), self.delimiter().is_some(), filter)
+    }
+    fn has_any_attribute(&self, attributes: &[String]) -> bool {
+        self.attributes().iter().any(|attribute| {
+            let attribute = match attribute {
+                NameAttribute::NoInferiors => "\\Noinferiors",
+                NameAttribute::NoSelect => "\\Noselect",
+                NameAttribute::Marked => "\\Marked",
+                NameAttribute::Unmarked => "\\Unmarked",
+                NameAttribute::Custom(attribute) => attribute,
+            };
+            attributes.iter().any(|a| attribute_matches(attribute, a))
+        })
+    }
+}
+
+/// Attributes are case-insensitive. The leading backslash may be left out in the configuration.
+fn attribute_matches(attribute: &str, configured: &str) -> bool {
+    attribute
+        .trim_start_matches('\\')
+        .eq_ignore_ascii_case(configured.trim_start_matches('\\'))
+}
+
+/// Match the `/`-delimited `path` of a mailbox against `filter`.
+/// On servers with a hierarchy (`hierarchical`), the filter selects the folder itself and
+/// all of its subfolders. Servers without a delimiter have a flat namespace, in which every
+/// name (even one containing `/`) is a folder of its own, so only the exact name matches.
+/// An empty filter (or `/`) matches everything.
+fn path_filter_matches(path: &str, hierarchical: bool, filter: &str) -> bool {
+    let filter = filter.trim_end_matches('/');
+    if filter.is_empty() {
+        return true;
+    }
+    if !hierarchical {
+        return path == filter;
+    }
+    match path.strip_prefix(filter) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// What a source searches for in a mailbox and how the mails are fetched, see `iter_unseen`.
+/// Sources sharing a connection can differ in these.
+pub struct UnseenOptions {
+    /// SORT criteria for unseen mails
+    sort: Option<String>,
+    /// Keyword marking forwarded mails, which are then left unread
+    forwarded_keyword: Option<String>,
+    /// Additional SEARCH criteria for unseen mails
+    search: Option<String>,
+    /// Only mails with an attachment matching this pattern are fetched
+    attachment_filter: Option<String>,
+    /// Mails that arrived more than this many days ago are not fetched
+    max_age_days: Option<u32>,
+    /// Mails that arrived less than this long ago are left for a later cycle
+    min_age: Option<Duration>,
+    /// Set for readonly sources, which EXAMINE mailboxes and track forwarded mails themselves
+    readonly: Option<ReadonlyState>,
+    /// Return unseen mails in reverse order, newest first
+    newest_first: bool,
+    /// Times fetching a single mail is retried, before it is skipped
+    fetch_retries: u32,
+}
+impl Default for UnseenOptions {
+    fn default() -> Self {
+        Self {
+            sort: None,
+            forwarded_keyword: None,
+            search: None,
+            attachment_filter: None,
+            max_age_days: None,
+            min_age: None,
+            readonly: None,
+            newest_first: false,
+            fetch_retries: DEFAULT_FETCH_RETRIES,
+        }
+    }
+}
+impl UnseenOptions {
+    /// Return unseen mails in the order of the `sort` criteria (e.g. `DATE`), if the server
+    /// supports SORT. Otherwise, they are returned in the order they arrived in.
+    pub fn with_sort(mut self, sort: Option<String>) -> Self {
+        self.sort = sort;
+        self
+    }
+    /// Mark forwarded mails with `keyword` instead of `\Seen`, and skip mails carrying it.
+    pub fn with_forwarded_keyword(mut self, keyword: Option<String>) -> Self {
+        self.forwarded_keyword = keyword;
+        self
+    }
+    /// Only return unseen mails that also match the SEARCH `criteria` (e.g. `TEXT "invoice"`),
+    /// so the server filters them, instead of all unseen mails being fetched.
+    pub fn with_search(mut self, criteria: Option<String>) -> Self {
+        self.search = criteria;
+        self
+    }
+    /// Only return unseen mails with an attachment whose filename or MIME type matches
+    /// `pattern` (e.g. `*.pdf`). Their BODYSTRUCTURE is checked first, so other mails are
+    /// not downloaded.
+    pub fn with_attachment_filter(mut self, pattern: Option<String>) -> Self {
+        self.attachment_filter = pattern;
+        self
+    }
+    /// Only return unseen mails that arrived in the mailbox at least `min_age` ago, by their
+    /// INTERNALDATE. The others are left unread, and returned by a later call.
+    pub fn with_min_age(mut self, min_age: Option<Duration>) -> Self {
+        self.min_age = min_age;
+        self
+    }
+    /// Only return unseen mails that arrived in the mailbox within the last `days` days.
+    pub fn with_max_age(mut self, days: Option<u32>) -> Self {
+        self.max_age_days = days;
+        self
+    }
+    /// Return unseen mails in reverse order (of arrival, or of the `sort` criteria), so that
+    /// the newest are fetched first.
+    pub fn with_newest_first(mut self, newest_first: bool) -> Self {
+        self.newest_first = newest_first;
+        self
+    }
+    /// Retry fetching a mail up to `retries` times (2 if unset), e.g. when the server fails to
+    /// return one corrupt mail. It is then skipped, and left unread for the next cycle, while
+    /// the other mails are fetched. A lost connection is not retried per mail.
+    pub fn with_fetch_retries(mut self, retries: Option<u32>) -> Self {
+        self.fetch_retries = retries.unwrap_or(DEFAULT_FETCH_RETRIES);
+        self
+    }
+    /// Never change the mailboxes: they are opened with EXAMINE, and `state` records which
+    /// mails were forwarded already, instead of flags on the server.
+    pub fn with_readonly(mut self, state: Option<ReadonlyState>) -> Self {
+        self.readonly = state;
+        self
+    }
 }
 
 pub struct ImapConnection {
+    /// Name of the server in the log, and the hostname expected by TLS
     server: String,
-    port: u16,
+    endpoint: ImapEndpoint,
     auth: AuthMethod,
+    /// How the connection to `server` is encrypted
+    encryption: ImapEncryption,
+    /// Session used for all commands
     session: Mutex<Option<ImapSession>>,
+    /// Additional sessions, that are only opened to fetch mails in parallel
+    pool: Vec<Mutex<PooledSession>>,
+    /// Whether the server supports SORT, once known
+    sort_supported: Mutex<Option<bool>>,
+    /// Whether the server supports MOVE, once known
+    move_supported: Mutex<Option<bool>>,
+    connection_limit: ConnectionLimit,
+    min_tls_version: Option<TlsVersion>,
+    max_tls_version: Option<TlsVersion>,
+    /// Hostname for SNI and the certificate check, if it differs from `server`
+    tls_hostname: Option<String>,
+    /// PEM file of an additional CA trusted for the server's certificate
+    ca_cert: Option<PathBuf>,
+    accept_invalid_certs: bool,
+    accept_invalid_hostnames: bool,
+    /// Extensions to ENABLE after login, if the server advertises them
+    enable: Vec<String>,
+    /// Pause between reading the greeting and authenticating, for providers that throttle
+    /// clients logging in too quickly
+    login_delay: Duration,
+    /// Held by a source for a whole cycle, while the connection is shared with other sources
+    cycle: Mutex<()>,
 }
 impl ImapConnection {
-    pub fn new(server: String, port: u16, auth: AuthMethod) -> Self {
+    /// Connection with the settings of `config`. `pool_size` is the maximum number of
+    /// sessions opened to the server at the same time.
+    pub fn from_config(config: &ImapConnectionConfig, connection_limit: ConnectionLimit) -> Self {
+        let endpoint = config
+            .endpoint()
+            .expect("Server or socket is checked when parsing the configuration");
+        // without a server, the connection is named after its socket in the log
+        let server = match &endpoint {
+            ImapEndpoint::Server { server, .. } => server.clone(),
+            ImapEndpoint::Socket { socket_path } => socket_path.clone(),
+        };
+        let pool_size = config.pool_size.unwrap_or(1);
         Self {
             server,
-            port,
-            auth,
+            endpoint,
+            auth: config.auth.clone(),
+            encryption: config.encryption,
             session: Mutex::new(None),
+            pool: (1..pool_size).map(|_| Mutex::default()).collect(),
+            sort_supported: Mutex::new(None),
+            move_supported: Mutex::new(None),
+            connection_limit,
+            min_tls_version: config.min_tls_version,
+            max_tls_version: config.max_tls_version,
+            tls_hostname: config.tls_hostname.clone(),
+            ca_cert: config.ca_cert.as_ref().map(PathBuf::from),
+            accept_invalid_certs: config.accept_invalid_certs,
+            accept_invalid_hostnames: config.accept_invalid_hostnames,
+            enable: config.enable.clone().unwrap_or_default(),
+            login_delay: Duration::from_millis(config.login_delay_ms.unwrap_or(0)),
+            cycle: Mutex::new(()),
+        }
+    }
+    /// Open `mailbox` with EXAMINE for readonly sources, otherwise with SELECT.
+    pub async fn open_mailbox(
+        sess: &mut ImapSession,
+        mailbox: &str,
+        readonly: bool,
+    ) -> ImapResult<Mailbox> {
+        if readonly {
+            sess.examine(mailbox).await
+        } else {
+            sess.select(mailbox).await
         }
     }
-    fn client(&self) -> Result<ImapClient> {
-        let tls = TlsConnector::new();
-        let client = task::block_on(async_imap::connect(
-            (self.server.as_str(), self.port),
-            self.server.clone(),
-            tls,
-        ))
-        .context("Failed to connect to IMAP server.")?;
-        Ok(client)
+    /// Wait until no other source sharing this connection is in the middle of a cycle. The
+    /// selected mailbox and the sequence numbers of mails belong to the session, so sources
+    /// sharing it have to take turns.
+    pub async fn lock_cycle(&self) -> MutexGuard<'_, ()> {
+        self.cycle.lock().await
     }
-    async fn session(&self) -> Result<SessionHandle<'_>> {
-        if self.session.lock().await.is_none() {
-            let client = self.client()?;
-            let session = match self.auth.clone() {
-                AuthMethod::Login { user, password } => {
-                    task::block_on(client.login(user, password))
+    /// Set up TLS on a connection to the server.
+    async fn tls_handshake(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let mut connector = TlsConnector::new();
+        if let Some(min) = self.min_tls_version {
+            connector = connector.min_protocol_version(Some(tls_protocol(min)));
+        }
+        if let Some(max) = self.max_tls_version {
+            connector = connector.max_protocol_version(Some(tls_protocol(max)));
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = fs::read(path)
+                .with_context(|| format!("Failed to read ca_cert {}", path.display()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid ca_cert {}", path.display()))?;
+            connector = connector.add_root_certificate(certificate);
+        }
+        connector = connector
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .danger_accept_invalid_hostnames(self.accept_invalid_hostnames);
+        let hostname = self.tls_hostname.as_ref().unwrap_or(&self.server);
+        connector
+            .connect(hostname.as_str(), stream)
+            .await
+            .context(ServerUnreachable)
+    }
+    /// Upgrade an unencrypted connection to TLS with the STARTTLS command.
+    async fn starttls(&self, mut stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        // async-imap numbers its tags A0001, A0002, ..., so this can not collide
+        stream.write_all(b"C0 STARTTLS\r\n").await?;
+        loop {
+            let line = read_line(&mut stream)
+                .await?
+                .ok_or_else(|| anyhow!("Connection closed while starting TLS"))?;
+            if let Some(result) = line.strip_prefix(b"C0 ") {
+                if !result.starts_with(b"OK") {
+                    return Err(anyhow!(
+                        "Server refused STARTTLS: {}",
+                        String::from_utf8_lossy(result).trim_end()
+                    ));
+                }
+                return self.tls_handshake(stream).await;
+            }
+        }
+    }
+    /// Connect to the server. Returns the client, and whether the server greeted with PREAUTH.
+    /// `permit` is held until the client (or the session it turns into) is dropped.
+    async fn client(&self, permit: ConnectionPermit) -> Result<(ImapClient, bool)> {
+        let mut stream = match &self.endpoint {
+            #[cfg(unix)]
+            ImapEndpoint::Socket { socket_path } => ImapStream::Unix(
+                UnixStream::connect(socket_path)
+                    .await
+                    .context(ServerUnreachable)?,
+            ),
+            #[cfg(not(unix))]
+            ImapEndpoint::Socket { .. } => {
+                return Err(anyhow!("Unix sockets are not supported on this platform"))
+            }
+            ImapEndpoint::Server { server, port } => {
+                let stream = TcpStream::connect((server.as_str(), *port))
+                    .await
+                    .context(ServerUnreachable)?;
+                match self.encryption {
+                    ImapEncryption::Tls => ImapStream::Tls(self.tls_handshake(stream).await?),
+                    ImapEncryption::Starttls | ImapEncryption::None => ImapStream::Plain(stream),
+                }
+            }
+        };
+
+        // The greeting is read here instead of by async-imap, since a PREAUTH greeting
+        // changes how the transport has to be set up.
+        let greeting = read_line(&mut stream)
+            .await?
+            .ok_or_else(|| anyhow!("Could not read server greeting after connect"))?;
+        let (status, mut greeting_login_disabled) = match Response::from_bytes(&greeting) {
+            Ok((_, Response::Data { status, code, .. })) => match code {
+                Some(ResponseCode::Capabilities(capabilities)) => {
+                    (status, Some(has_login_disabled(&capabilities)))
+                }
+                _ => (status, None),
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Invalid server greeting: {}",
+                    String::from_utf8_lossy(&greeting).trim_end()
+                ))
+            }
+        };
+        let mut stream = match stream {
+            ImapStream::Plain(stream) if self.encryption == ImapEncryption::Starttls => {
+                if status != Status::Ok {
+                    return Err(anyhow!(
+                        "Server greeting does not allow STARTTLS: {}",
+                        String::from_utf8_lossy(&greeting).trim_end()
+                    ));
+                }
+                // capabilities announced before TLS (e.g. LOGINDISABLED) do not apply after it
+                greeting_login_disabled = None;
+                ImapStream::Tls(self.starttls(stream).await?)
+            }
+            stream => stream,
+        };
+        match status {
+            Status::Ok => {
+                if let AuthMethod::Login { .. } = self.auth {
+                    let login_disabled = match greeting_login_disabled {
+                        Some(login_disabled) => login_disabled,
+                        None => query_login_disabled(&mut stream).await?,
+                    };
+                    if login_disabled {
+                        return Err(anyhow!(
+                            "The server does not allow LOGIN on this connection (LOGINDISABLED). It requires TLS / STARTTLS, or a different authentication mechanism."
+                        ));
+                    }
                 }
-                _ => {
-                    //TODO: implement
-                    unimplemented!();
+                Ok((ImapClient::new(ImapTransport::new(stream, permit)), false))
+            }
+            Status::PreAuth => {
+                let mut transport = ImapTransport::new(stream, permit);
+                // async-imap can only create a session by logging in. The server considers
+                // the connection authenticated already, so the login is answered locally.
+                transport.answer_next_command();
+                Ok((ImapClient::new(transport), true))
+            }
+            _ => Err(anyhow!(
+                "Server refused connection: {}",
+                String::from_utf8_lossy(&greeting).trim_end()
+            )),
+        }
+    }
+    async fn connect(&self) -> Result<ImapSession> {
+        let token = self.access_token()?;
+        let permit = self.connection_limit.acquire().await;
+        self.connect_with(permit, token).await
+    }
+    /// Open an additional session for the pool, unless all connections are taken right now.
+    /// Waiting could wait for the main session of this same cycle, which is used instead.
+    async fn connect_pooled(&self) -> Option<Result<ImapSession>> {
+        let token = match self.access_token() {
+            Ok(token) => token,
+            Err(e) => return Some(Err(e)),
+        };
+        let permit = self.connection_limit.try_acquire()?;
+        Some(self.connect_with(permit, token).await)
+    }
+    /// The access token for XOAUTH2, `None` for other authentication methods. It is read
+    /// before connecting, so a slow token_command does not hold a connection permit.
+    fn access_token(&self) -> Result<Option<String>> {
+        let AuthMethod::XOAuth2 {
+            token_command,
+            access_token,
+            access_token_env,
+            ..
+        } = &self.auth
+        else {
+            return Ok(None);
+        };
+        let token = match (token_command, access_token, access_token_env) {
+            (Some(token_command), _, _) => run_token_command(token_command)?,
+            (None, Some(token), _) => token.clone(),
+            (None, None, Some(variable)) => env::var(variable)
+                .with_context(|| format!("Failed to read the access token from {}", variable))?
+                .trim()
+                .to_owned(),
+            (None, None, None) => return Err(anyhow!("No access token configured")),
+        };
+        Ok(Some(token))
+    }
+    /// Establish a session, which holds `permit` until it is closed. `token` is the access
+    /// token for XOAUTH2.
+    async fn connect_with(
+        &self,
+        permit: ConnectionPermit,
+        token: Option<String>,
+    ) -> Result<ImapSession> {
+        let (client, preauth) = self.client(permit).await?;
+        if !preauth && !self.login_delay.is_zero() {
+            task::sleep(self.login_delay).await;
+        }
+        let session = match (preauth, self.auth.clone()) {
+            (true, _) => client.login("preauth", "preauth").await,
+            (false, AuthMethod::None | AuthMethod::Preauth) => {
+                return Err(anyhow!(
+                    "Server did not greet with PREAUTH, but no credentials are configured."
+                ))
+            }
+            (false, AuthMethod::Login { user, password }) => client.login(user, password).await,
+            (
+                false,
+                AuthMethod::XOAuth2 {
+                    user,
+                    token_command,
+                    ..
+                },
+            ) => {
+                let token = token.ok_or_else(|| anyhow!("No access token configured"))?;
+                match (
+                    authenticate_xoauth2(client, &user, &token).await,
+                    token_command,
+                ) {
+                    // the token might have expired just now, so a fresh one is tried once
+                    (Err((e, client)), Some(token_command)) => {
+                        warn!(
+                            target: &format!("ImapConnection[{}]", self.server),
+                            "XOAUTH2 authentication failed, retrying with a new token: {}", e
+                        );
+                        let token = run_token_command(&token_command)?;
+                        authenticate_xoauth2(client, &user, &token).await
+                    }
+                    (result, _) => result,
                 }
             }
-            .map_err(|(e, _)| e)
-            .context("Failed to authenticate with the IMAP server.")?;
+            (false, AuthMethod::Plain { user, password }) => {
+                client
+                    .authenticate("PLAIN", SaslPlain::new(&user, &password))
+                    .await
+            }
+        }
+        .map_err(|(e, _)| {
+            // the server's answer tells why, e.g. wrong credentials or a missing OAuth2 scope
+            let message = format!("Failed to authenticate with the IMAP server: {}", e);
+            anyhow::Error::new(e).context(message)
+        })?;
+        let mut session = session;
+        if !self.enable.is_empty() {
+            // the session is still usable, just without the extensions
+            if let Err(e) = self.enable_extensions(&mut session).await {
+                warn!(target: &format!("ImapConnection[{}]", self.server), "{}", ErrorReport(&e));
+            }
+        }
+        Ok(session)
+    }
+    /// Issue ENABLE for all configured extensions the server advertises. Extensions that are
+    /// not advertised are skipped, since servers reject ENABLE for them.
+    async fn enable_extensions(&self, session: &mut ImapSession) -> Result<()> {
+        let log_target = format!("ImapConnection[{}]", self.server);
+        let capabilities = session
+            .capabilities()
+            .await
+            .context("Failed to query capabilities")?;
+        let (advertised, missing): (Vec<&String>, Vec<&String>) = self
+            .enable
+            .iter()
+            .partition(|extension| capabilities.has_str(extension));
+        for extension in missing {
+            warn!(target: &log_target, "Server does not advertise {}, not enabling it", extension);
+        }
+        if advertised.is_empty() {
+            return Ok(());
+        }
+        let extensions: Vec<&str> = advertised.iter().map(|e| e.as_str()).collect();
+        session
+            .run_command_and_check_ok(format!("ENABLE {}", extensions.join(" ")))
+            .await
+            .with_context(|| format!("Failed to enable {}", extensions.join(", ")))
+    }
+    async fn session(&self) -> Result<SessionHandle<'_>> {
+        if self.session.lock().await.is_none() {
+            let session = self.connect().await?;
             self.session.lock().await.replace(session);
         }
 
@@ -98,6 +631,50 @@ impl ImapConnection {
             .take()
             .ok_or_else(|| anyhow!("Failed to take IMAP session"))
     }
+    /// Keep trying to establish the first session while the server is unreachable (e.g. the
+    /// network is not up yet at boot), with exponential backoff, for at most `duration`.
+    /// Returns `false` if the source should stop, because the server did not become reachable
+    /// in time, or because it was requested to stop.
+    pub fn connect_at_startup(
+        &self,
+        duration: Duration,
+        channel: &HubSourceChannel,
+        log_target: &str,
+    ) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut delay = STARTUP_RETRY_DELAY_MIN;
+        loop {
+            let e = match task::block_on(self.session()) {
+                Ok(_) => return true,
+                // everything else is not caused by the network, waiting would not help
+                Err(e) if !ServerUnreachable::caused(&e) => return true,
+                Err(e) => e,
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                error!(
+                    target: log_target,
+                    "Server not reachable within {}s after startup, giving up: {}",
+                    duration.as_secs(),
+                    ErrorReport(&e)
+                );
+                return false;
+            }
+            let delay_now = delay.min(deadline - now);
+            info!(
+                target: log_target,
+                "Server not reachable yet, retrying in {}s: {}",
+                delay_now.as_secs_f64().ceil(),
+                ErrorReport(&e)
+            );
+            match channel.next_timeout(delay_now) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return false, // shutdown
+                _ => panic!(), // There currently are no SourceMessages
+            }
+            delay = (delay * 2).min(STARTUP_RETRY_DELAY_MAX);
+        }
+    }
     pub async fn run<F, R>(&self, runfn: F) -> Result<R>
     where
         F: Fn(&mut ImapSession) -> ImapResult<R>,
@@ -122,39 +699,96 @@ impl ImapConnection {
         }
     }
 
+    /// Log out of the main session and all sessions of the pool. The connection stays usable,
+    /// the next request connects again.
+    pub async fn disconnect(&self) {
+        let log_target = format!("ImapConnection[{}]", self.server);
+        let main_session = self.session.lock().await.take();
+        let mut sessions: Vec<ImapSession> = main_session.into_iter().collect();
+        for pooled in &self.pool {
+            let mut pooled = pooled.lock().await;
+            pooled.selected = None;
+            sessions.extend(pooled.session.take());
+        }
+        if !sessions.is_empty() {
+            debug!(target: &log_target, "Closing {} idle session(s)", sessions.len());
+        }
+        for session in sessions {
+            logout(session, &log_target).await;
+        }
+    }
+
     async fn recursive_mailbox_list(&self) -> Result<Vec<async_imap::types::Name>> {
         let mut session_handle = self.session().await?;
-        let result: Vec<ImapResult<_>> = session_handle
-            .get()
-            .list(None, Some("*"))
-            .await
-            .context("Failed to acquire recursive list of mailboxes")?
-            .collect::<_>()
-            .await;
-        // fail if any single item in the stream failed
-        let result: ImapResult<Vec<_>> = result.into_iter().collect();
-        Ok(result?)
+        let result: ImapResult<Vec<_>> = match session_handle.get().list(None, Some("*")).await {
+            // fail if any single item in the stream failed
+            Ok(stream) => stream.collect::<Vec<_>>().await.into_iter().collect(),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            // This is the first request of every cycle. If it fails, the cached session is
+            // most likely dead (e.g. after a network change), so reconnect next time.
+            let _ = session_handle.replace(None);
+        }
+        result.context("Failed to acquire recursive list of mailboxes")
     }
 
-    async fn fetch_mail(&self, message_id: String) -> Result<async_imap::types::Fetch> {
+    async fn fetch_mail(&self, message_id: String, peek: bool) -> Result<async_imap::types::Fetch> {
         let mut session_borrow = self.session().await?;
-        let session_borrow = session_borrow.get();
-        let mut message_stream = session_borrow.fetch(&message_id, "RFC822").await?;
-        if let Some(message) = message_stream.next().await {
-            Ok(message?)
-        } else {
-            Err(anyhow!("Failed to fetch message: {}", message_id))
+        fetch_from(session_borrow.get(), &message_id, peek).await
+    }
+
+    /// Fetch a mail using the session at `slot` of the pool, which is opened on demand.
+    /// Falls back to the main session, if the pooled session is not available.
+    async fn fetch_mail_pooled(
+        &self,
+        slot: usize,
+        mailbox: &str,
+        readonly: bool,
+        message_id: String,
+        peek: bool,
+    ) -> Result<async_imap::types::Fetch> {
+        let mut pooled = self.pool[slot].lock().await;
+        if !pooled.disabled {
+            if pooled.session.is_none() {
+                match self.connect_pooled().await {
+                    Some(Ok(session)) => pooled.session = Some(session),
+                    None => {}
+                    Some(Err(e)) => {
+                        // Most likely the provider's limit of concurrent connections
+                        warn!(
+                            target: &format!("ImapConnection[{}]", self.server),
+                            "Failed to open additional session, continuing with fewer: {:#}", e
+                        );
+                        pooled.disabled = true;
+                    }
+                }
+            }
+            if let Some(mut session) = pooled.session.take() {
+                let mut result = Ok(());
+                let selected = (mailbox.to_owned(), readonly);
+                if pooled.selected.as_ref() != Some(&selected) {
+                    result = Self::open_mailbox(&mut session, mailbox, readonly)
+                        .await
+                        .map(|_| ());
+                }
+                if result.is_ok() {
+                    pooled.selected = Some(selected);
+                    if let Ok(fetch) = fetch_from(&mut session, &message_id, peek).await {
+                        pooled.session = Some(session);
+                        return Ok(fetch);
+                    }
+                }
+                // the session is in an unknown state, reconnect next time
+                pooled.selected = None;
+            }
         }
+        drop(pooled);
+        self.fetch_mail(message_id, peek).await
     }
 
     pub async fn delete_mails(&self, message_ids: &[Seq]) -> Result<()> {
-        let id_list: String = message_ids.iter().fold("".to_owned(), |a, b| {
-            if a.is_empty() {
-                b.to_string()
-            } else {
-                format!("{},{}", a, b)
-            }
-        });
+        let id_list = sequence_set(message_ids);
 
         // Add \Delete flags to messages
         let flag_result: Vec<ImapResult<_>> = self
@@ -179,43 +813,360 @@ impl ImapConnection {
             .await;
         let expunge_result: ImapResult<Vec<_>> = expunge_result.into_iter().collect();
 
+        // Message sequence numbers changed. Pooled sessions only learn about that with their
+        // next command, so they have to re-select the mailbox before fetching again.
+        for pooled in &self.pool {
+            pooled.lock().await.selected = None;
+        }
+
         // try to expunge the messages that were correctly marked, before throwing
         flag_result?;
         expunge_result?;
         Ok(())
     }
 
+    /// Move the given mails of the selected mailbox to the mailbox `folder`. Uses MOVE
+    /// (RFC 6851) if the server supports it, otherwise the mails are copied and then deleted.
+    pub async fn move_mails(&self, message_ids: &[Seq], folder: &str) -> Result<()> {
+        let id_list = sequence_set(message_ids);
+        let mut move_supported = self.move_supported.lock().await;
+        if move_supported.is_none() {
+            let capabilities = self
+                .run(|sess| task::block_on(sess.capabilities()))
+                .await
+                .context("Failed to query server capabilities")?;
+            let supported = capabilities.has_str("MOVE");
+            if !supported {
+                debug!(
+                    target: &format!("ImapConnection[{}]", self.server),
+                    "Server does not support MOVE, copying and deleting mails instead"
+                );
+            }
+            *move_supported = Some(supported);
+        }
+        if move_supported.unwrap_or_default() {
+            self.session()
+                .await?
+                .get()
+                .mv(&id_list, folder)
+                .await
+                .with_context(|| format!("Failed to move mails to {}", folder))?;
+            // the moved mails are expunged, see `delete_mails`
+            for pooled in &self.pool {
+                pooled.lock().await.selected = None;
+            }
+            return Ok(());
+        }
+        self.session()
+            .await?
+            .get()
+            .copy(&id_list, quote_string(folder))
+            .await
+            .with_context(|| format!("Failed to copy mails to {}", folder))?;
+        self.delete_mails(message_ids).await
+    }
+
+    /// Delete all mails of the selected mailbox that arrived more than `days` days ago, read
+    /// or not. Returns how many were deleted.
+    pub async fn delete_older_than(&self, days: u32) -> Result<usize> {
+        let query = format!("UNDELETED BEFORE {}", days_ago(days));
+        let old_mails: Vec<Seq> = self
+            .run(|sess| task::block_on(sess.search(&query)))
+            .await
+            .context("Failed to search for old mails")?
+            .into_iter()
+            .collect();
+        if !old_mails.is_empty() {
+            self.delete_mails(&old_mails).await?;
+        }
+        Ok(old_mails.len())
+    }
+
+    /// Add the `keyword` flag to the given mails of the selected mailbox.
+    pub async fn add_keyword(&self, message_ids: &[Seq], keyword: &str) -> Result<()> {
+        let flag_result: Vec<ImapResult<_>> = self
+            .session()
+            .await?
+            .get()
+            .store(
+                sequence_set(message_ids),
+                format!("+FLAGS.SILENT ({})", keyword),
+            )
+            .await
+            .with_context(|| format!("Failed to mark mails with {} flag", keyword))?
+            .collect()
+            .await;
+        flag_result.into_iter().collect::<ImapResult<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// The forwarded `keyword`, if it can be stored in the selected `mailbox`. Servers list
+    /// the flags they store in PERMANENTFLAGS, where `\*` allows any new keyword.
+    fn usable_forwarded_keyword<'k>(
+        &self,
+        mailbox: &Mailbox,
+        keyword: Option<&'k str>,
+    ) -> Option<&'k str> {
+        let keyword = keyword?;
+        // without PERMANENTFLAGS, all flags are stored permanently (RFC 3501, 7.1)
+        let storable = mailbox.permanent_flags.is_empty()
+            || mailbox.permanent_flags.iter().any(|flag| match flag {
+                Flag::MayCreate => true,
+                Flag::Custom(custom) => custom.eq_ignore_ascii_case(keyword),
+                _ => false,
+            });
+        if !storable {
+            warn!(
+                target: &format!("ImapConnection[{}]", self.server),
+                "Mailbox does not store the keyword {}, marking mails as read instead", keyword
+            );
+        }
+        storable.then_some(keyword)
+    }
+
+    /// Mailboxes with any of the `exclude_attributes` (LIST attributes like `\Junk`) are skipped.
     pub fn iter_mailboxes_recursive(
         &self,
         path_filter: Option<&str>,
+        exclude_attributes: &[String],
     ) -> Result<vec::IntoIter<MailboxName>> {
         // get a (linearized) list of the folder structure
         let mut mailboxes = task::block_on(self.recursive_mailbox_list())?;
         if let Some(filter) = path_filter {
-            mailboxes.retain(|mailbox| {
-                // Match the given filter against the "/"-delimited absolute path
-                mailbox.path().starts_with(filter)
-            });
+            mailboxes.retain(|mailbox| mailbox.matches_path_filter(filter));
         }
+        mailboxes.retain(|mailbox| !mailbox.has_any_attribute(exclude_attributes));
         Ok(mailboxes.into_iter())
     }
 
-    pub async fn iter_unseen(&self, mailbox: &MailboxName) -> Result<UnseenMailIterator<'_>> {
+    /// The SORT `criteria`, if the server supports SORT.
+    async fn sort_criteria<'c>(&self, criteria: Option<&'c str>) -> Result<Option<&'c str>> {
+        let Some(criteria) = criteria else {
+            return Ok(None);
+        };
+        let mut sort_supported = self.sort_supported.lock().await;
+        if sort_supported.is_none() {
+            let capabilities = self
+                .run(|sess| task::block_on(sess.capabilities()))
+                .await
+                .context("Failed to query server capabilities")?;
+            let supported = capabilities.has_str("SORT");
+            if !supported {
+                warn!(
+                    target: &format!("ImapConnection[{}]", self.server),
+                    "Server does not support SORT, processing mails in the order they arrived in"
+                );
+            }
+            *sort_supported = Some(supported);
+        }
+        Ok(sort_supported.unwrap_or_default().then_some(criteria))
+    }
+
+    /// Iterate over the unread mails of the mailbox, that do not carry the forwarded keyword.
+    /// Fetching them marks them as read, unless the keyword is used instead.
+    pub async fn iter_unseen<'a>(
+        &'a self,
+        mailbox: &MailboxName,
+        options: &'a UnseenOptions,
+    ) -> Result<UnseenMailIterator<'a>> {
+        let criteria = self.sort_criteria(options.sort.as_deref()).await?;
         // select new mailbox and get a list of new/unseen messages
-        let unread_mails: Vec<_> = self
+        let (mut unread_mails, forwarded_keyword, readonly_range) = self
             .run(|sess| {
-                task::block_on(sess.select(mailbox.name()))?;
-                task::block_on(sess.search("UNDELETED UNSEEN"))
+                let readonly = options.readonly.as_ref();
+                let selected =
+                    task::block_on(Self::open_mailbox(sess, mailbox.name(), readonly.is_some()))?;
+                let forwarded_keyword =
+                    self.usable_forwarded_keyword(&selected, options.forwarded_keyword.as_deref());
+                // readonly sources only look at the mails that arrived since the last cycle
+                let readonly_range = match readonly {
+                    Some(state) => {
+                        let (uid_validity, uid_next) = selected
+                            .uid_validity
+                            .zip(selected.uid_next)
+                            .ok_or_else(|| {
+                                ImapError::Bad("Server sent no UIDVALIDITY or UIDNEXT".into())
+                            })?;
+                        let first = state.first_new_uid(mailbox.name(), uid_validity);
+                        if first >= uid_next {
+                            return Ok((Vec::new(), forwarded_keyword, None));
+                        }
+                        Some((uid_validity, first, uid_next - 1))
+                    }
+                    None => None,
+                };
+                let uids = readonly_range.map(|(_, first, last)| (first, last));
+                let since = options
+                    .max_age_days
+                    .map(|days| format!("SINCE {}", days_ago(days)));
+                let query = unseen_query(
+                    forwarded_keyword,
+                    uids,
+                    since.as_deref(),
+                    options.search.as_deref(),
+                );
+                let unread_mails = match criteria {
+                    Some(criteria) => task::block_on(sort(sess, criteria, &query))?,
+                    None => {
+                        // SORT always names the charset, SEARCH only needs it for non-ASCII
+                        let query = if query.is_ascii() {
+                            query
+                        } else {
+                            format!("CHARSET UTF-8 {}", query)
+                        };
+                        let mut unread_mails: Vec<_> =
+                            task::block_on(sess.search(&query))?.into_iter().collect();
+                        // sequence numbers are assigned in the order the mails arrived in
+                        unread_mails.sort_unstable();
+                        unread_mails
+                    }
+                };
+                Ok((unread_mails, forwarded_keyword, readonly_range))
             })
-            .await?
-            .into_iter()
-            .collect();
+            .await?;
+        if let Some(pattern) = &options.attachment_filter {
+            if !unread_mails.is_empty() {
+                let mut matching = HashSet::new();
+                for (id, structure) in self.fetch_structure(&unread_mails).await? {
+                    if structure.attachments.iter().any(|a| a.matches(pattern)) {
+                        matching.insert(id);
+                    } else {
+                        debug!(
+                            target: &format!("ImapConnection[{}]", self.server),
+                            "Skipping mail {} in {} ({} bytes), no attachment matches {}",
+                            id, mailbox.name(), structure.size, pattern
+                        );
+                    }
+                }
+                unread_mails.retain(|id| matching.contains(id));
+            }
+        }
+        if let Some(min_age) = options.min_age {
+            if !unread_mails.is_empty() {
+                let now = OffsetDateTime::now_utc().unix_timestamp();
+                let arrived = self.fetch_internal_dates(&unread_mails).await?;
+                unread_mails.retain(|id| {
+                    let age = match arrived.get(id) {
+                        // clocks of the server and this host differ a little, so mails from
+                        // the future just arrived. Dates far off are broken, not skewed.
+                        Some(&arrived) if arrived - now <= MAX_CLOCK_SKEW.as_secs() as i64 => {
+                            (now - arrived).max(0) as u64
+                        }
+                        _ => return true,
+                    };
+                    let old_enough = age >= min_age.as_secs();
+                    if !old_enough {
+                        debug!(
+                            target: &format!("ImapConnection[{}]", self.server),
+                            "Leaving mail {} in {} for later, it arrived {}s ago",
+                            id, mailbox.name(), age
+                        );
+                    }
+                    old_enough
+                });
+            }
+        }
+        if options.newest_first {
+            unread_mails.reverse();
+        }
         Ok(UnseenMailIterator {
             con: self,
+            options,
+            mailbox: mailbox.name().to_owned(),
+            forwarded_keyword,
             unread_mails: VecDeque::from(unread_mails),
+            fetched: VecDeque::new(),
+            readonly_range,
+            failed: false,
         })
     }
 
+    /// The INTERNALDATE (as unix timestamp) of the given mails in the selected mailbox.
+    /// Mails the server returns no valid date for are left out.
+    async fn fetch_internal_dates(&self, message_ids: &[Seq]) -> Result<HashMap<Seq, i64>> {
+        let fetches = self
+            .run(|sess| {
+                task::block_on(async {
+                    sess.fetch(sequence_set(message_ids), "INTERNALDATE")
+                        .await?
+                        .collect::<Vec<_>>()
+                        .await
+                        .into_iter()
+                        .collect::<ImapResult<Vec<_>>>()
+                })
+            })
+            .await
+            .context("Failed to fetch the arrival date of mails")?;
+        Ok(fetches
+            .iter()
+            .filter_map(|fetch| Some((fetch.message, fetch.internal_date()?.timestamp())))
+            .collect())
+    }
+
+    /// The structure of the given mails in the selected mailbox, without downloading them.
+    /// Mails the server returns no BODYSTRUCTURE for are left out.
+    pub async fn fetch_structure(&self, message_ids: &[Seq]) -> Result<Vec<(Seq, MailStructure)>> {
+        let fetches = self
+            .run(|sess| {
+                task::block_on(async {
+                    sess.fetch(sequence_set(message_ids), "(BODYSTRUCTURE RFC822.SIZE)")
+                        .await?
+                        .collect::<Vec<_>>()
+                        .await
+                        .into_iter()
+                        .collect::<ImapResult<Vec<_>>>()
+                })
+            })
+            .await
+            .context("Failed to fetch the structure of mails")?;
+        Ok(fetches
+            .iter()
+            .filter_map(|fetch| {
+                let mut attachments = Vec::new();
+                collect_attachments(fetch.bodystructure()?, &mut attachments);
+                let size = fetch.size.unwrap_or_default();
+                Some((fetch.message, MailStructure { size, attachments }))
+            })
+            .collect())
+    }
+
+    /// Fetch the mails of `mailbox` within the UID set `uids` (e.g. `1:100`), without
+    /// changing their flags, and hand each to `write` with its UID. Returns how many were
+    /// fetched.
+    pub async fn fetch_uids<F>(&self, mailbox: &str, uids: &str, mut write: F) -> Result<usize>
+    where
+        F: FnMut(u32, &[u8]) -> Result<()>,
+    {
+        let mut session_handle = self.session().await?;
+        let session = session_handle.get();
+        // EXAMINE, so that nothing is changed even by servers that ignore PEEK
+        Self::open_mailbox(session, mailbox, true)
+            .await
+            .with_context(|| format!("Failed to open {}", mailbox))?;
+        let mut fetches = session
+            .uid_fetch(uids, "(UID BODY.PEEK[])")
+            .await
+            .with_context(|| format!("Failed to fetch mails {} of {}", uids, mailbox))?;
+        let mut count = 0;
+        while let Some(fetch) = fetches.next().await {
+            let fetch = fetch.context("Failed to fetch mail")?;
+            // unsolicited responses (e.g. FLAGS of other mails) carry no body
+            if let (Some(uid), Some(body)) = (fetch.uid, fetch.body()) {
+                write(uid, body)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub async fn supports_idle(&self) -> Result<bool> {
+        let capabilities = self
+            .run(|sess| task::block_on(sess.capabilities()))
+            .await
+            .context("Failed to query server capabilities")?;
+        Ok(capabilities.has_str("IDLE"))
+    }
+
     pub async fn idle(&mut self) -> Result<ImapIdleHandle> {
         let mut idle_handle = self.take_session().await?.idle();
         task::block_on(idle_handle.init())
@@ -225,28 +1176,765 @@ impl ImapConnection {
 }
 impl Drop for ImapConnection {
     fn drop(&mut self) {
-        if let Ok(session) = &mut task::block_on(self.take_session()) {
-            let _ = task::block_on(session.logout());
+        // Logging out is best effort: no session is established just to log out, and a
+        // server that is gone can not stall the shutdown.
+        let log_target = format!("ImapConnection[{}]", self.server);
+        let main_session = self.session.get_mut().take();
+        let pooled_sessions = self
+            .pool
+            .iter_mut()
+            .filter_map(|p| p.get_mut().session.take());
+        for session in main_session.into_iter().chain(pooled_sessions) {
+            task::block_on(logout(session, &log_target));
+        }
+    }
+}
+
+/// Log out and close the session, giving the server `LOGOUT_TIMEOUT` to answer.
+async fn logout(mut session: ImapSession, log_target: &str) {
+    match future::timeout(LOGOUT_TIMEOUT, session.logout()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!(target: log_target, "Failed to log out: {}", e),
+        Err(_) => debug!(target: log_target, "Server did not answer the logout"),
+    }
+}
+
+/// Fetch the whole message. Unless `peek` is set, this marks it as read.
+async fn fetch_from(
+    session: &mut ImapSession,
+    message_id: &str,
+    peek: bool,
+) -> Result<async_imap::types::Fetch> {
+    let query = if peek { "BODY.PEEK[]" } else { "RFC822" };
+    let mut message_stream = session.fetch(message_id, query).await?;
+    let mut responses = Vec::new();
+    while let Some(message) = message_stream.next().await {
+        responses.push(message?);
+    }
+    select_fetched(responses, message_id)
+}
+
+/// Whether `err` was caused by the connection to the server breaking down, rather than by
+/// the server failing a single request.
+fn connection_lost(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<ImapError>(),
+            Some(ImapError::ConnectionLost | ImapError::Io(_))
+        )
+    })
+}
+
+/// Pick the response carrying the body of `message_id` from the responses to its fetch.
+/// Servers may send unsolicited updates along (e.g. FLAGS of other mails, or of this mail
+/// in a response of its own), which are ignored.
+fn select_fetched<F: FetchedItem>(responses: Vec<F>, message_id: &str) -> Result<F> {
+    let seq = message_id.parse::<Seq>().ok();
+    let count = responses.len();
+    let mut matching: Vec<F> = responses
+        .into_iter()
+        .filter(|response| seq.is_none() || seq == Some(response.seq()))
+        .collect();
+    if count > 1 {
+        debug!(
+            target: "ImapConnection",
+            "Fetch of mail {} returned {} responses, {} of them for this mail",
+            message_id,
+            count,
+            matching.len()
+        );
+    }
+    // without any body, the first one is returned, see `fetched_content`
+    let index = matching.iter().position(F::has_body).unwrap_or(0);
+    if index < matching.len() {
+        Ok(matching.swap_remove(index))
+    } else {
+        Err(anyhow!("Failed to fetch message: {}", message_id))
+    }
+}
+
+/// What `select_fetched` needs to know about a fetch response
+trait FetchedItem {
+    fn seq(&self) -> Seq;
+    fn has_body(&self) -> bool;
+}
+impl FetchedItem for async_imap::types::Fetch {
+    fn seq(&self) -> Seq {
+        self.message
+    }
+    fn has_body(&self) -> bool {
+        self.body().is_some()
+    }
+}
+
+/// The content of a fetched mail. A server may answer the fetch without the content
+/// (e.g. `RFC822 NIL`), such a mail is forwarded empty instead of failing the whole poll.
+fn fetched_content(server: &str, message_id: Seq, body: Option<&[u8]>) -> Arc<[u8]> {
+    match body {
+        Some(body) => Arc::from(body),
+        None => {
+            warn!(
+                target: &format!("ImapConnection[{}]", server),
+                "Server returned no content for mail {}, forwarding it as empty mail", message_id
+            );
+            Arc::from(&[][..])
+        }
+    }
+}
+
+/// Which mails of each mailbox a readonly source has handled already. As it can not mark
+/// them on the server, it keeps the highest UID handled, per mailbox and UIDVALIDITY.
+pub struct ReadonlyState {
+    /// File the state is kept in across restarts
+    path: Option<PathBuf>,
+    /// Mailbox name => (UIDVALIDITY, highest UID handled)
+    mailboxes: std_sync::Mutex<HashMap<String, (u32, u32)>>,
+}
+impl ReadonlyState {
+    /// Load the state from `path`, if it exists. Without a path, the state is only kept in
+    /// memory, so after a restart all unseen mails are forwarded again.
+    pub fn new(path: Option<PathBuf>) -> Result<Self> {
+        let mailboxes = match &path {
+            Some(path) if path.exists() => {
+                let data = fs::read(path)
+                    .with_context(|| format!("Failed to read state_file: {}", path.display()))?;
+                serde_json::from_slice(&data)
+                    .with_context(|| format!("Failed to parse state_file: {}", path.display()))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            mailboxes: std_sync::Mutex::new(mailboxes),
+        })
+    }
+
+    /// The lowest UID in `mailbox` not handled yet. A new UIDVALIDITY means the UIDs were
+    /// reassigned, so all mails count as new then.
+    fn first_new_uid(&self, mailbox: &str, uid_validity: u32) -> u32 {
+        match self.mailboxes.lock().unwrap().get(mailbox) {
+            Some((validity, last_uid)) if *validity == uid_validity => last_uid + 1,
+            _ => 1,
+        }
+    }
+
+    /// Record that all mails in `mailbox` up to `last_uid` are handled.
+    fn record(&self, mailbox: &str, uid_validity: u32, last_uid: u32) -> Result<()> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes.insert(mailbox.to_owned(), (uid_validity, last_uid));
+        if let Some(path) = &self.path {
+            // written to a temporary file first, so a crash can not leave a truncated state
+            let tmp_path = path.with_extension("tmp");
+            let data = serde_json::to_vec(&*mailboxes).expect("State is serializable");
+            fs::write(&tmp_path, data)
+                .and_then(|_| fs::rename(&tmp_path, path))
+                .with_context(|| format!("Failed to write state_file: {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// What BODYSTRUCTURE tells about a mail, see `ImapConnection::fetch_structure`.
+#[derive(Debug)]
+pub struct MailStructure {
+    /// Size of the whole message in bytes
+    pub size: u32,
+    pub attachments: Vec<Attachment>,
+}
+
+/// Collect the parts with an `attachment` disposition, like `mime::Entity::attachments`.
+fn collect_attachments(structure: &BodyStructure, attachments: &mut Vec<Attachment>) {
+    let (common, octets) = match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for body in bodies {
+                collect_attachments(body, attachments);
+            }
+            return;
+        }
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, other.octets),
+    };
+    let disposition = match &common.disposition {
+        Some(disposition) if disposition.ty.eq_ignore_ascii_case("attachment") => disposition,
+        _ => return,
+    };
+    let param = |params: &BodyParams, name: &str| {
+        params
+            .iter()
+            .flatten()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| mime::decode_header_words(value))
+    };
+    attachments.push(Attachment {
+        mime_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        filename: param(&disposition.params, "filename")
+            .or_else(|| param(&common.ty.params, "name")),
+        size: octets as usize,
+    });
+}
+
+/// `value` as IMAP quoted string (RFC 3501, 4.3)
+fn quote_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Comma separated list of the sequence numbers, as used in commands
+fn sequence_set(message_ids: &[Seq]) -> String {
+    message_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Whether `uids` is a valid IMAP sequence set of UIDs, like `5`, `1:100`, `200:*` or `1,3,7:9`
+pub fn is_uid_set(uids: &str) -> bool {
+    let is_uid = |uid: &str| uid == "*" || uid.parse::<u32>().is_ok_and(|uid| uid > 0);
+    uids.split(',').all(|range| match range.split_once(':') {
+        Some((first, last)) => is_uid(first) && is_uid(last),
+        None => is_uid(range),
+    })
+}
+
+/// SASL PLAIN (RFC 4616), without an authorization identity
+struct SaslPlain {
+    response: Option<String>,
+}
+impl SaslPlain {
+    fn new(user: &str, password: &str) -> Self {
+        Self {
+            response: Some(format!("\0{}\0{}", user, password)),
+        }
+    }
+}
+impl Authenticator for SaslPlain {
+    type Response = String;
+    fn process(&mut self, _challenge: &[u8]) -> String {
+        // the server only sends a further challenge if it failed, which is answered empty
+        self.response.take().unwrap_or_default()
+    }
+}
+
+/// SASL XOAUTH2 (as used by Gmail and Office365).
+struct XOAuth2 {
+    response: Option<String>,
+    /// Details the server sends about a rejected token, e.g. a missing scope
+    error: Option<String>,
+}
+impl XOAuth2 {
+    fn new(user: &str, token: &str) -> Self {
+        Self {
+            response: Some(format!("user={}\x01auth=Bearer {}\x01\x01", user, token)),
+            error: None,
+        }
+    }
+}
+impl Authenticator for &mut XOAuth2 {
+    type Response = String;
+    fn process(&mut self, challenge: &[u8]) -> String {
+        match self.response.take() {
+            Some(response) => response,
+            // a second challenge carries the error details, and has to be answered empty
+            None => {
+                self.error = Some(String::from_utf8_lossy(challenge).into_owned());
+                String::new()
+            }
         }
     }
 }
 
+/// Authenticate with XOAUTH2. If the server rejects the token, the details it sends along
+/// are added to the error, as they tell why (e.g. an expired token, or a missing scope).
+async fn authenticate_xoauth2(
+    client: ImapClient,
+    user: &str,
+    token: &str,
+) -> Result<ImapSession, (ImapError, ImapClient)> {
+    let mut authenticator = XOAuth2::new(user, token);
+    client
+        .authenticate("XOAUTH2", &mut authenticator)
+        .await
+        .map_err(|(e, client)| match (e, authenticator.error.take()) {
+            (ImapError::No(message), Some(details)) => {
+                (ImapError::No(format!("{} {}", message, details)), client)
+            }
+            (e, _) => (e, client),
+        })
+}
+
+/// Run the configured command and return its output (trimmed) as access token.
+fn run_token_command(token_command: &[String]) -> Result<String> {
+    let (program, arguments) = token_command
+        .split_first()
+        .ok_or_else(|| anyhow!("token_command is empty"))?;
+    let mut child = Command::new(program)
+        .args(arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to run token_command: {}", program))?;
+    // read while waiting, the command blocks once it printed more than the pipe holds
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + TOKEN_COMMAND_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .context("Failed to wait for token_command")?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "token_command {} did not finish within {}s",
+                program,
+                TOKEN_COMMAND_TIMEOUT.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        return Err(anyhow!("token_command {} failed with: {}", program, status));
+    }
+    let output = reader
+        .join()
+        .expect("Thread exited with errors")
+        .context("Failed to read the output of token_command")?;
+    let token = String::from_utf8(output).context("token_command printed invalid UTF-8")?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("token_command {} printed no token", program));
+    }
+    Ok(token.to_owned())
+}
+
+/// Run the `on_new_mail_command` of a source after it found `count` new mails. The command
+/// is not waited for, its exit status is only logged.
+pub fn run_new_mail_command(command: &[String], srcname: &str, count: usize, log_target: &str) {
+    let Some((program, arguments)) = command.split_first() else {
+        return;
+    };
+    let child = Command::new(program)
+        .args(arguments)
+        .env("IDLEMAIL_SOURCE", srcname)
+        .env("IDLEMAIL_NEW_MAILS", count.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(target: log_target, "Failed to run on_new_mail_command {}: {}", program, e);
+            return;
+        }
+    };
+    let (program, log_target) = (program.clone(), log_target.to_owned());
+    // reaps the child, so it does not linger as zombie
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            warn!(target: &log_target, "on_new_mail_command {} exited with {}", program, status)
+        }
+        Err(e) => {
+            warn!(target: &log_target, "Failed to wait for on_new_mail_command {}: {}", program, e)
+        }
+    });
+}
+
+/// The native-tls protocol version for a configured TLS version
+fn tls_protocol(version: TlsVersion) -> Protocol {
+    match version {
+        TlsVersion::Tls10 => Protocol::Tlsv10,
+        TlsVersion::Tls11 => Protocol::Tlsv11,
+        TlsVersion::Tls12 => Protocol::Tlsv12,
+    }
+}
+
+/// Read one line (including the line break) from the server. `None` if the connection closed.
+async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await? == 0 {
+            return Ok(None);
+        }
+        line.push(byte[0]);
+    }
+    Ok(Some(line))
+}
+
+fn has_login_disabled(capabilities: &[Capability]) -> bool {
+    capabilities
+        .iter()
+        .any(|c| matches!(c, Capability::Atom(atom) if atom.eq_ignore_ascii_case("LOGINDISABLED")))
+}
+
+/// Ask the server for its capabilities before a client is set up on the stream, and return
+/// whether they include LOGINDISABLED.
+async fn query_login_disabled(stream: &mut ImapStream) -> Result<bool> {
+    // async-imap numbers its tags A0001, A0002, ..., so this can not collide
+    stream.write_all(b"C0 CAPABILITY\r\n").await?;
+    let mut login_disabled = false;
+    loop {
+        let line = read_line(stream)
+            .await?
+            .ok_or_else(|| anyhow!("Connection closed while querying capabilities"))?;
+        if line.starts_with(b"C0 ") {
+            return Ok(login_disabled);
+        }
+        if let Ok((_, Response::Capabilities(capabilities))) = Response::from_bytes(&line) {
+            login_disabled |= has_login_disabled(&capabilities);
+        }
+    }
+}
+
+/// The search query for unseen mails, without mails carrying the `forwarded_keyword`, and
+/// restricted to the `uids` range, the `since` criterion and the configured `search` criteria.
+fn unseen_query(
+    forwarded_keyword: Option<&str>,
+    uids: Option<(u32, u32)>,
+    since: Option<&str>,
+    search: Option<&str>,
+) -> String {
+    let mut query = "UNDELETED UNSEEN".to_owned();
+    if let Some(keyword) = forwarded_keyword {
+        query.push_str(" UNKEYWORD ");
+        query.push_str(keyword);
+    }
+    if let Some((first, last)) = uids {
+        query.push_str(&format!(" UID {}:{}", first, last));
+    }
+    if let Some(since) = since {
+        query.push(' ');
+        query.push_str(since);
+    }
+    if let Some(search) = search {
+        // parenthesized, so OR or NOT in the criteria can not swallow the criteria above
+        query.push_str(&format!(" ({})", search.trim()));
+    }
+    query
+}
+
+/// The date `days` days before today (UTC), formatted for the date criteria of SEARCH.
+fn days_ago(days: u32) -> String {
+    let date = OffsetDateTime::now_utc().date() - time::Duration::days(days.into());
+    let month = date.month().to_string();
+    format!("{:02}-{}-{}", date.day(), &month[..3], date.year())
+}
+
+/// Run a SORT command, which async-imap does not implement. Returns the sequence numbers
+/// of the mails matching the search `query`, in the order of `criteria`.
+async fn sort(session: &mut ImapSession, criteria: &str, query: &str) -> ImapResult<Vec<Seq>> {
+    let id = session
+        .run_command(format!("SORT ({}) UTF-8 {}", criteria, query))
+        .await?;
+    let mut sorted = Vec::new();
+    while let Some(response) = session.read_response().await {
+        let response = response?;
+        match response.parsed() {
+            // rewritten from `* SORT` by the transport
+            Response::MailboxData(MailboxDatum::Search(ids)) => sorted.extend(ids),
+            Response::Done {
+                tag,
+                status,
+                information,
+                ..
+            } if *tag == id => {
+                return match status {
+                    Status::Ok => Ok(sorted),
+                    Status::No => Err(ImapError::No(information.unwrap_or_default().to_owned())),
+                    _ => Err(ImapError::Bad(information.unwrap_or_default().to_owned())),
+                };
+            }
+            // unsolicited responses, e.g. about new mails
+            _ => {}
+        }
+    }
+    Err(ImapError::ConnectionLost)
+}
+
 pub struct UnseenMailIterator<'a> {
     con: &'a ImapConnection,
+    options: &'a UnseenOptions,
+    mailbox: String,
+    /// Set if mails are marked with the forwarded keyword, instead of being marked as read
+    forwarded_keyword: Option<&'a str>,
     unread_mails: VecDeque<Seq>,
+    /// Mails fetched ahead, when fetching in parallel
+    fetched: VecDeque<Result<(Seq, Arc<[u8]>)>>,
+    /// For readonly sources, the (UIDVALIDITY, first UID, last UID) of the mails searched
+    readonly_range: Option<(u32, u32, u32)>,
+    /// Whether fetching any of the mails failed
+    failed: bool,
+}
+impl<'a> UnseenMailIterator<'a> {
+    /// The keyword to add to the mails once they are forwarded, if it is used in this mailbox
+    pub fn forwarded_keyword(&self) -> Option<&'a str> {
+        self.forwarded_keyword
+    }
+
+    /// Number of unseen mails not fetched yet.
+    pub fn remaining(&self) -> usize {
+        self.unread_mails.len()
+    }
+
+    /// Only fetch the first `max` of the remaining mails, the others are left unread.
+    /// Returns the sequence numbers of the mails left out.
+    pub fn limit(&mut self, max: usize) -> Vec<Seq> {
+        let max = max.min(self.unread_mails.len());
+        self.unread_mails.drain(max..).collect()
+    }
+
+    /// Fetch the next mails, one on every session of the connection in parallel.
+    fn fetch_batch(&mut self) {
+        let batch_size = self.unread_mails.len().min(self.con.pool.len() + 1);
+        let batch: Vec<Seq> = self.unread_mails.drain(..batch_size).collect();
+        let (con, mailbox) = (self.con, self.mailbox.as_str());
+        let readonly = self.options.readonly.is_some();
+        let peek = self.forwarded_keyword.is_some() || readonly;
+        let fetch_retries = self.options.fetch_retries;
+        let fetches = batch.iter().enumerate().map(|(i, message_id)| async move {
+            let mut fetch_result = match i {
+                0 => con.fetch_mail(message_id.to_string(), peek).await,
+                i => {
+                    let message_id = message_id.to_string();
+                    con.fetch_mail_pooled(i - 1, mailbox, readonly, message_id, peek)
+                        .await
+                }
+            };
+            let log_target = format!("ImapConnection[{}]", con.server);
+            let mut retries = 0;
+            while let Err(e) = &fetch_result {
+                if retries == fetch_retries || connection_lost(e) {
+                    warn!(
+                        target: &log_target,
+                        "Skipping mail {} in {}, it could not be fetched\n{}",
+                        message_id,
+                        mailbox,
+                        ErrorReport(e)
+                    );
+                    break;
+                }
+                retries += 1;
+                debug!(
+                    target: &log_target,
+                    "Fetching mail {} failed, retrying ({}/{})",
+                    message_id,
+                    retries,
+                    fetch_retries
+                );
+                fetch_result = con.fetch_mail(message_id.to_string(), peek).await;
+            }
+            fetch_result.map(|fetch_result| {
+                let content = fetched_content(&con.server, *message_id, fetch_result.body());
+                (*message_id, content)
+            })
+        });
+        let fetched = task::block_on(join_all(fetches));
+        self.fetched.extend(fetched);
+    }
 }
 impl Iterator for UnseenMailIterator<'_> {
-    type Item = Result<(Seq, Vec<u8>)>;
+    type Item = Result<(Seq, Arc<[u8]>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.unread_mails.pop_front().map(|message_id| {
-            match task::block_on(self.con.fetch_mail(message_id.to_string())) {
-                Ok(fetch_result) => fetch_result
-                    .body()
-                    .map(|body| (message_id, body.to_vec()))
-                    .ok_or_else(|| anyhow!("Failed to fetch message: {}", message_id)),
-                Err(err) => Err(err),
+        if self.fetched.is_empty() && !self.unread_mails.is_empty() {
+            self.fetch_batch();
+        }
+        let next = self.fetched.pop_front();
+        match &next {
+            Some(Err(_)) => self.failed = true,
+            Some(Ok(_)) => {}
+            // all mails are handled, unless one failed, which is then searched again
+            None => {
+                if let (Some(state), Some((uid_validity, _, last_uid)), false) = (
+                    &self.options.readonly,
+                    self.readonly_range.take(),
+                    self.failed,
+                ) {
+                    if let Err(e) = state.record(&self.mailbox, uid_validity, last_uid) {
+                        warn!(
+                            target: &format!("ImapConnection[{}]", self.con.server),
+                            "Failed to record the forwarded mails\n{}",
+                            ErrorReport(&e)
+                        );
+                    }
+                }
             }
-        })
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("INBOX", "INBOX" => true)]
+    #[test_case("INBOX/Lists", "INBOX" => true)]
+    #[test_case("INBOX/Lists/rust", "INBOX/Lists" => true)]
+    #[test_case("INBOX/Lists", "INBOX/Lists/" => true ; "nested trailing delimiter")]
+    #[test_case("INBOX-old", "INBOX" => false)]
+    #[test_case("Archive/INBOX", "INBOX" => false)]
+    #[test_case("INBOX", "INBOX/Lists" => false)]
+    #[test_case("INBOX", "" => true)]
+    #[test_case("INBOX", "/" => true ; "root filter")]
+    fn test_path_filter_hierarchical(path: &str, filter: &str) -> bool {
+        path_filter_matches(path, true, filter)
+    }
+
+    #[test_case("INBOX", "INBOX" => true)]
+    #[test_case("INBOX", "INBOX/" => true ; "trailing delimiter")]
+    #[test_case("INBOX/Lists", "INBOX" => false)]
+    #[test_case("INBOX/Lists", "INBOX/Lists" => true)]
+    #[test_case("INBOX-old", "INBOX" => false)]
+    #[test_case("INBOX", "" => true)]
+    fn test_path_filter_flat(path: &str, filter: &str) -> bool {
+        path_filter_matches(path, false, filter)
+    }
+
+    #[test_case(None, None => "UNDELETED UNSEEN")]
+    #[test_case(Some("$Forwarded"), None => "UNDELETED UNSEEN UNKEYWORD $Forwarded")]
+    #[test_case(None, Some(r#"TEXT "invoice""#) => r#"UNDELETED UNSEEN (TEXT "invoice")"#)]
+    #[test_case(None, Some(r#"OR BODY "a" BODY "b""#) => r#"UNDELETED UNSEEN (OR BODY "a" BODY "b")"# ; "or")]
+    #[test_case(Some("$Forwarded"), Some(r#" BODY "x" "#) => r#"UNDELETED UNSEEN UNKEYWORD $Forwarded (BODY "x")"# ; "both")]
+    fn test_unseen_query(forwarded_keyword: Option<&str>, search: Option<&str>) -> String {
+        unseen_query(forwarded_keyword, None, None, search)
+    }
+
+    #[test_case("5" => true)]
+    #[test_case("1:100" => true)]
+    #[test_case("200:*" => true)]
+    #[test_case("1,3,7:9" => true)]
+    #[test_case("" => false ; "empty")]
+    #[test_case("0:10" => false)]
+    #[test_case("1,,3" => false)]
+    #[test_case("1-100" => false)]
+    #[test_case("1:2:3" => false)]
+    fn test_is_uid_set(uids: &str) -> bool {
+        is_uid_set(uids)
+    }
+
+    #[test]
+    fn test_max_age_query() {
+        let date = days_ago(40);
+        let (day, rest) = date.split_once('-').unwrap();
+        let (month, year) = rest.split_once('-').unwrap();
+        assert_eq!(day.len(), 2);
+        assert!([
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+        ]
+        .contains(&month));
+        assert_eq!(year.len(), 4);
+        assert_eq!(
+            unseen_query(None, None, Some("SINCE 01-Feb-2024"), Some("TEXT \"x\"")),
+            "UNDELETED UNSEEN SINCE 01-Feb-2024 (TEXT \"x\")"
+        );
+    }
+
+    #[test]
+    fn test_readonly_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let state = ReadonlyState::new(Some(path.clone())).unwrap();
+        assert_eq!(state.first_new_uid("INBOX", 7), 1);
+        state.record("INBOX", 7, 42).unwrap();
+        assert_eq!(
+            unseen_query(
+                None,
+                Some((state.first_new_uid("INBOX", 7), 50)),
+                None,
+                None
+            ),
+            "UNDELETED UNSEEN UID 43:50"
+        );
+
+        let state = ReadonlyState::new(Some(path)).unwrap();
+        assert_eq!(state.first_new_uid("INBOX", 7), 43);
+        // the mailbox was recreated, so its UIDs start over
+        assert_eq!(state.first_new_uid("INBOX", 8), 1);
+        assert_eq!(state.first_new_uid("Archive", 7), 1);
+    }
+
+    #[test]
+    fn test_sasl_plain() {
+        use base64::Engine;
+        let mut plain = SaslPlain::new("me@example.org", "secret");
+        let response = plain.process(b"");
+        assert_eq!(response.as_bytes(), b"\0me@example.org\0secret");
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD.encode(response),
+            "AG1lQGV4YW1wbGUub3JnAHNlY3JldA=="
+        );
+        assert_eq!(plain.process(b""), "");
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let lost = anyhow::Error::from(ImapError::ConnectionLost).context("Failed to fetch");
+        assert!(connection_lost(&lost));
+        let refused = anyhow::Error::from(ImapError::No("corrupt message".to_owned()));
+        assert!(!connection_lost(&refused));
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        task::block_on(async {
+            let limit = ConnectionLimit::new(Some(1));
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let permit = limit.acquire().await;
+            let client = ImapClient::new(ImapTransport::new(ImapStream::Plain(stream), permit));
+            let timeout = Duration::from_millis(100);
+            // the permit is held as long as the connection is open
+            assert!(future::timeout(timeout, limit.acquire()).await.is_err());
+            drop(client);
+            assert!(future::timeout(timeout, limit.acquire()).await.is_ok());
+        });
+    }
+
+    #[test_case(Some(b"Subject: x\r\n\r\nbody") => 18)]
+    #[test_case(Some(b"") => 0 ; "zero-length mail")]
+    #[test_case(None => 0 ; "no content")]
+    fn test_fetched_content(body: Option<&[u8]>) -> usize {
+        fetched_content("localhost", 1, body).len()
+    }
+
+    struct TestFetch(Seq, bool);
+    impl FetchedItem for TestFetch {
+        fn seq(&self) -> Seq {
+            self.0
+        }
+        fn has_body(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test_case(vec![(2, true)] => Some((2, true)))]
+    #[test_case(vec![(3, false), (2, true)] => Some((2, true)) ; "other mail first")]
+    #[test_case(vec![(2, false), (2, true)] => Some((2, true)) ; "flags before body")]
+    #[test_case(vec![(2, false)] => Some((2, false)) ; "no body")]
+    #[test_case(vec![(3, true)] => None ; "only other mails")]
+    #[test_case(vec![] => None ; "no response")]
+    fn test_select_fetched(responses: Vec<(Seq, bool)>) -> Option<(Seq, bool)> {
+        let responses = responses
+            .into_iter()
+            .map(|(seq, body)| TestFetch(seq, body));
+        select_fetched(responses.collect(), "2")
+            .ok()
+            .map(|fetch| (fetch.0, fetch.1))
     }
 }
@@ -0,0 +1,48 @@
+use super::common::{is_uid_set, ImapConnection};
+use crate::{
+    config::{ConfigContainer, SourceConfig},
+    hub::ConnectionLimit,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::task;
+use log::debug;
+use std::{fs, path::Path};
+
+/// Export the mails of `folder` within the UID set `uids` (e.g. `1:100`) from the IMAP
+/// source `srcname` into `directory`, as one `<uid>.eml` file per mail. The mails are
+/// not changed on the server. Returns how many were exported.
+pub fn export(
+    config: &ConfigContainer,
+    srcname: &str,
+    folder: &str,
+    uids: &str,
+    directory: &Path,
+) -> Result<usize> {
+    let log_target = format!("Export[{}]", srcname);
+    if !is_uid_set(uids) {
+        bail!(
+            "Invalid UID range: {} (e.g. 5, 1:100, 200:* or 1,3,7:9)",
+            uids
+        );
+    }
+    let source = config
+        .sources
+        .get(srcname)
+        .ok_or_else(|| anyhow!("Unknown source: {}", srcname))?;
+    let connection = match &source.config {
+        SourceConfig::ImapPoll(config) => &config.connection,
+        SourceConfig::ImapIdle(config) => &config.connection,
+        SourceConfig::Test(_) => bail!("Source: {} is no IMAP source", srcname),
+    };
+    // only the settings needed to log in, the rest is about forwarding
+    let con = ImapConnection::from_config(connection, ConnectionLimit::new(None));
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+    let count = task::block_on(con.fetch_uids(folder, uids, |uid, data| {
+        let path = directory.join(format!("{}.eml", uid));
+        debug!(target: &log_target, "Writing {}", path.display());
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }))?;
+    task::block_on(con.disconnect());
+    Ok(count)
+}
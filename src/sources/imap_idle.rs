@@ -1,15 +1,28 @@
 use super::{
-    common::{ImapConnection, MailPath},
+    common::{
+        run_new_mail_command, ImapConnection, MailPath, ReadonlyState, UnseenOptions,
+        DEFAULT_PROGRESS_EVERY,
+    },
     MailSource,
 };
 use crate::{
-    config::ImapIdleSourceConfig,
+    config::{ImapEncryption, ImapIdleSourceConfig},
+    error::ErrorReport,
     hub::{HubSourceChannel, Mail, MailAgent},
 };
+use async_imap::{
+    extensions::idle::IdleResponse,
+    imap_proto::{Response, Status},
+};
 use async_std::task;
 use futures::{future::FutureExt, pin_mut, select};
 use log::{debug, error, info, trace, warn};
-use std::{thread, time::Duration};
+use std::{collections::HashSet, path::PathBuf, thread, time::Duration};
+
+/// Delay before the first reconnect after the server dropped the IDLE session.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+/// Upper bound for the exponential reconnect backoff.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(300);
 
 pub struct ImapIdleSource {
     name: String,
@@ -39,45 +52,162 @@ impl MailAgent for ImapIdleSource {
 impl MailSource for ImapIdleSource {
     fn start(&mut self, channel: HubSourceChannel) {
         info!(target: &self.log_target, "Starting");
+        if self.config.connection.accept_invalid_certs
+            || self.config.connection.accept_invalid_hostnames
+        {
+            warn!(target: &self.log_target, "Certificate validation is relaxed (accept_invalid_certs / accept_invalid_hostnames), the connection is open to interception");
+        }
+        if self.config.connection.encryption == ImapEncryption::None {
+            warn!(target: &self.log_target, "Encryption is none, mails and credentials are sent unencrypted");
+        }
         trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
 
         let name = self.name.clone();
         let log_target = self.log_target.clone();
         let config = self.config.clone();
+        let connection_limit = channel.connection_limit();
 
         self.worker = Some(thread::spawn(move || {
-            let mut con =
-                ImapConnection::new(config.server.clone(), config.port, config.auth.clone());
+            let readonly_state = config
+                .readonly
+                .then(|| ReadonlyState::new(config.state_file.as_ref().map(PathBuf::from)))
+                .transpose();
+            let readonly_state = match readonly_state {
+                Ok(readonly_state) => readonly_state,
+                Err(e) => {
+                    error!(target: &log_target, "{}", ErrorReport(&e));
+                    info!(target: &log_target, "Stopping");
+                    return;
+                }
+            };
+            let mut con = ImapConnection::from_config(&config.connection, connection_limit);
+            let options = UnseenOptions::default()
+                .with_sort(config.sort.clone())
+                .with_forwarded_keyword(config.forwarded_keyword.clone())
+                .with_attachment_filter(config.attachment_filter.clone())
+                .with_fetch_retries(config.fetch_retries)
+                .with_readonly(readonly_state);
+
+            if let Some(startup_retry) = config.startup_retry {
+                let duration = Duration::from_secs(startup_retry);
+                if !con.connect_at_startup(duration, &channel, &log_target) {
+                    info!(target: &log_target, "Stopping");
+                    return;
+                }
+            }
 
             let stop_future = channel.next().fuse();
             pin_mut!(stop_future);
 
+            let progress_every = config.progress_every.unwrap_or(DEFAULT_PROGRESS_EVERY);
+            let mut reconnect_delay = RECONNECT_DELAY_MIN;
+            // determined once the first connection is up
+            let mut idle_supported: Option<bool> = None;
+
             loop {
-                match con.iter_mailboxes_recursive(None) {
+                // mails left for the next cycle, see `max_fetch_per_wakeup`
+                let mut deferred = 0;
+                // mails forwarded in this cycle, see `on_new_mail_command`
+                let mut new_mails = 0;
+                match con.iter_mailboxes_recursive(
+                    None,
+                    config.exclude_attributes.as_deref().unwrap_or_default(),
+                ) {
                     Ok(mailboxes) => {
+                        // Message-IDs forwarded in this cycle, see `dedup_batch`
+                        let mut batch_message_ids = HashSet::new();
+                        let mut fetch_budget = config.max_fetch_per_wakeup.unwrap_or(usize::MAX);
                         mailboxes.for_each(|mailbox| {
                             let mut unread_mails = Vec::new();
-                            task::block_on(con.iter_unseen(&mailbox)).unwrap().for_each(
-                                |unseen_message| {
-                                    if let Ok((message_id, unseen_message)) = unseen_message {
-                                        unread_mails.push(message_id);
-                                        debug!(
+                            let mut unseen_mails =
+                                match task::block_on(con.iter_unseen(&mailbox, &options)) {
+                                    Ok(unseen_mails) => unseen_mails,
+                                    Err(e) => {
+                                        error!(
                                             target: &log_target,
-                                            "Unread mail in {}",
-                                            mailbox.path()
+                                            "Failed to search for unread mails in {}\n{}",
+                                            mailbox.path(),
+                                            ErrorReport(&e)
                                         );
-                                        channel.notify_new_mail(Mail::from_rfc822(
-                                            name.clone(),
-                                            unseen_message,
-                                        ));
+                                        return;
                                     }
-                                },
-                            );
-                            if !config.keep && !unread_mails.is_empty() {
+                                };
+                            let found = unseen_mails.remaining();
+                            let left_out = unseen_mails.limit(fetch_budget).len();
+                            fetch_budget -= found - left_out;
+                            deferred += left_out;
+                            let forwarded_keyword = unseen_mails.forwarded_keyword();
+                            let total = unseen_mails.remaining();
+                            let mut fetched = 0;
+                            unseen_mails.for_each(|unseen_message| {
+                                fetched += 1;
+                                if fetched % progress_every == 0 && fetched < total {
+                                    info!(
+                                        target: &log_target,
+                                        "Fetched {}/{} unread mails from {}",
+                                        fetched,
+                                        total,
+                                        mailbox.path()
+                                    );
+                                }
+                                if let Ok((message_id, unseen_message)) = unseen_message {
+                                    unread_mails.push(message_id);
+                                    debug!(
+                                        target: &log_target,
+                                        "Unread mail in {}",
+                                        mailbox.path()
+                                    );
+                                    let mail = Mail::from_rfc822(name.clone(), unseen_message)
+                                        .with_folder(mailbox.path());
+                                    if config.dedup_batch {
+                                        if let Some(message_id) = &mail.message_id {
+                                            if !batch_message_ids.insert(message_id.clone()) {
+                                                debug!(
+                                                    target: &log_target,
+                                                    "Skipping duplicate of mail {} in {}",
+                                                    mail,
+                                                    mailbox.path()
+                                                );
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    channel.notify_new_mail(mail);
+                                    new_mails += 1;
+                                }
+                            });
+                            if let Some(keyword) = forwarded_keyword {
+                                if !unread_mails.is_empty() {
+                                    if let Err(e) =
+                                        task::block_on(con.add_keyword(&unread_mails, keyword))
+                                    {
+                                        warn!(
+                                            target: &log_target,
+                                            "Failed to mark forwarded messages\n{}",
+                                            ErrorReport(&e)
+                                        );
+                                    }
+                                }
+                            }
+                            if let Some(folder) =
+                                config.move_to.as_ref().filter(|_| !unread_mails.is_empty())
+                            {
+                                if let Err(e) =
+                                    task::block_on(con.move_mails(&unread_mails, folder))
+                                {
+                                    warn!(
+                                        target: &log_target,
+                                        "Failed to move messages to {}\n{}",
+                                        folder,
+                                        ErrorReport(&e)
+                                    );
+                                }
+                            } else if !config.keep && !unread_mails.is_empty() {
                                 if let Err(e) = task::block_on(con.delete_mails(&unread_mails)) {
                                     warn!(
                                         target: &log_target,
-                                        "Failed to deleted messages from mailbox\n{}", e
+                                        "Failed to deleted messages from mailbox\n{}",
+                                        ErrorReport(&e)
                                     );
                                 }
                             }
@@ -87,10 +217,36 @@ impl MailSource for ImapIdleSource {
                         error!(
                             target: &log_target,
                             "Failed to get recursive list of mailboxes to iterate\n{}",
-                            e.backtrace()
+                            ErrorReport(&e)
                         );
                     }
                 }
+                if let Some(command) = config
+                    .on_new_mail_command
+                    .as_ref()
+                    .filter(|_| new_mails > 0)
+                {
+                    run_new_mail_command(command, &name, new_mails, &log_target);
+                }
+                if deferred > 0 {
+                    // no notification will come for these, so they are fetched right away
+                    info!(
+                        target: &log_target,
+                        "Reached max_fetch_per_wakeup, fetching the remaining {} mails in the next cycle",
+                        deferred
+                    );
+                    let should_exit = task::block_on(async {
+                        select! {
+                            _ = stop_future => true,
+                            default => false,
+                        }
+                    });
+                    if should_exit {
+                        info!(target: &log_target, "Stopping");
+                        return;
+                    }
+                    continue;
+                }
 
                 loop {
                     // inner loop used only if something fails while entering IDLE state and we need to retry
@@ -98,15 +254,19 @@ impl MailSource for ImapIdleSource {
                         target: &log_target,
                         "Entering IMAP IDLE to wait for server notification"
                     );
-                    match task::block_on(
-                        con.run(|sess| task::block_on(sess.select(config.path.clone()))),
-                    ) {
+                    match task::block_on(con.run(|sess| {
+                        task::block_on(ImapConnection::open_mailbox(
+                            sess,
+                            &config.path,
+                            config.readonly,
+                        ))
+                    })) {
                         Ok(_) => {}
                         Err(e) => {
                             error!(
                                 target: &log_target,
                                 "Failed to enter IMAP IDLE state:\n{}",
-                                e.backtrace()
+                                ErrorReport(&e)
                             );
                             // connection-lost errors should be handled by the connection, so this could
                             // be an authentication error, or a temporary unavailable server. Wait a bit and retry
@@ -114,13 +274,57 @@ impl MailSource for ImapIdleSource {
                             continue;
                         }
                     }
+                    let idle_supported = match idle_supported {
+                        Some(idle_supported) => idle_supported,
+                        None => match task::block_on(con.supports_idle()) {
+                            Ok(supported) => {
+                                if !supported && config.fallback_to_poll {
+                                    warn!(
+                                        target: &log_target,
+                                        "Server does not support IDLE. Falling back to polling every {}s",
+                                        config.renewinterval
+                                    );
+                                }
+                                *idle_supported.insert(supported)
+                            }
+                            Err(e) => {
+                                error!(target: &log_target, "{}", ErrorReport(&e));
+                                thread::sleep(Duration::from_secs(5));
+                                continue;
+                            }
+                        },
+                    };
+                    if !idle_supported {
+                        if !config.fallback_to_poll {
+                            error!(
+                                target: &log_target,
+                                "Server does not support IDLE. Use an imap_poll source, or set fallback_to_poll. Stopping."
+                            );
+                            return;
+                        }
+                        debug!(
+                            target: &log_target,
+                            "Polling again in {}s", config.renewinterval
+                        );
+                        let should_exit = task::block_on(async {
+                            select! {
+                                _ = task::sleep(Duration::from_secs(config.renewinterval)).fuse() => false,
+                                _ = stop_future => true,
+                            }
+                        });
+                        if should_exit {
+                            info!(target: &log_target, "Stopping");
+                            return;
+                        }
+                        break;
+                    }
                     let mut idle_handle = match task::block_on(con.idle()) {
                         Ok(idle_handle) => idle_handle,
                         Err(e) => {
                             error!(
                                 target: &log_target,
                                 "Failed to enter IMAP IDLE state:\n{}",
-                                e.backtrace()
+                                ErrorReport(&e)
                             );
                             thread::sleep(Duration::from_secs(5));
                             continue;
@@ -133,19 +337,58 @@ impl MailSource for ImapIdleSource {
                     // await either a wake-up from the IMAP server, or a request to shutdown
                     let idle_future = idle_future.fuse();
                     pin_mut!(idle_future);
+                    let idle_result = task::block_on(async {
+                        select! {
+                            idle_result = idle_future => Some(idle_result),
+                            _ = stop_future => None,
+                            complete => unreachable!()
+                        }
+                    });
+                    let disconnect_reason = match idle_result {
+                        None => {
+                            info!(target: &log_target, "Stopping");
+                            return;
+                        }
+                        // servers send an untagged BYE right before evicting idle clients
+                        Some(Ok(IdleResponse::NewData(data)))
+                            if matches!(
+                                data.parsed(),
+                                Response::Data {
+                                    status: Status::Bye,
+                                    ..
+                                }
+                            ) =>
+                        {
+                            "Server closed the IDLE session (BYE)".to_owned()
+                        }
+                        Some(Err(e)) => format!("IDLE session failed: {}", e),
+                        Some(Ok(_)) => {
+                            debug!(target: &log_target, "IDLE interrupted");
+                            reconnect_delay = RECONNECT_DELAY_MIN;
+                            break; // no error -> go to outer loop to fetch mails and return to the IDLE state
+                        }
+                    };
+
+                    // The session is gone. Wait before reconnecting, so servers that evict us
+                    // right away are not hammered with reconnects.
+                    warn!(
+                        target: &log_target,
+                        "{}. Reconnecting in {}s",
+                        disconnect_reason,
+                        reconnect_delay.as_secs()
+                    );
                     let should_exit = task::block_on(async {
                         select! {
-                            _ = idle_future => false,
+                            _ = task::sleep(reconnect_delay).fuse() => false,
                             _ = stop_future => true,
-                            complete => unreachable!()
                         }
                     });
                     if should_exit {
                         info!(target: &log_target, "Stopping");
                         return;
                     }
-                    debug!(target: &log_target, "IDLE interrupted");
-                    break; // no error -> go to outer loop to fetch mails and return to the IDLE state
+                    reconnect_delay = (reconnect_delay * 2).min(RECONNECT_DELAY_MAX);
+                    break; // the mailboxes are re-scanned for mails that arrived in the meantime
                 }
             }
         }));
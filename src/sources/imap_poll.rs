@@ -1,14 +1,29 @@
 use super::{
-    common::{ImapConnection, MailPath},
+    common::{
+        run_new_mail_command, ImapConnection, MailPath, ReadonlyState, ServerUnreachable,
+        UnseenOptions, DEFAULT_PROGRESS_EVERY,
+    },
     MailSource,
 };
 use crate::{
-    config::ImapPollSourceConfig,
+    config::{AuthMethod, ImapConnectionConfig, ImapEncryption, ImapPollSourceConfig},
+    error::ErrorReport,
     hub::{HubSourceChannel, Mail, MailAgent},
 };
 use async_std::task;
 use log::{debug, error, info, trace, warn};
-use std::{sync::mpsc, thread, time::Duration};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+/// Upper bound for the poll interval while the server is unreachable.
+const OFFLINE_INTERVAL_MAX: Duration = Duration::from_secs(600);
+/// Unread mails at startup, above which the source refuses to forward, see `max_initial`
+const DEFAULT_MAX_INITIAL: usize = 1000;
 
 pub struct ImapPollSource {
     name: String,
@@ -38,22 +53,208 @@ impl MailAgent for ImapPollSource {
 impl MailSource for ImapPollSource {
     fn start(&mut self, channel: HubSourceChannel) {
         info!(target: &self.log_target, "Starting");
+        if self.config.connection.accept_invalid_certs
+            || self.config.connection.accept_invalid_hostnames
+        {
+            warn!(target: &self.log_target, "Certificate validation is relaxed (accept_invalid_certs / accept_invalid_hostnames), the connection is open to interception");
+        }
+        if self.config.connection.encryption == ImapEncryption::None {
+            warn!(target: &self.log_target, "Encryption is none, mails and credentials are sent unencrypted");
+        }
         trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
 
         let name = self.name.clone();
         let log_target = self.log_target.clone();
         let config = self.config.clone();
+        let connection_limit = channel.connection_limit();
 
         self.worker = Some(thread::spawn(move || {
-            let con = ImapConnection::new(config.server.clone(), config.port, config.auth.clone());
+            let readonly_state = config
+                .readonly
+                .then(|| ReadonlyState::new(config.state_file.as_ref().map(PathBuf::from)))
+                .transpose();
+            let readonly_state = match readonly_state {
+                Ok(readonly_state) => readonly_state,
+                Err(e) => {
+                    error!(target: &log_target, "{}", ErrorReport(&e));
+                    info!(target: &log_target, "Stopping");
+                    return;
+                }
+            };
+            let options = UnseenOptions::default()
+                .with_sort(config.sort.clone())
+                .with_forwarded_keyword(config.forwarded_keyword.clone())
+                .with_attachment_filter(config.attachment_filter.clone())
+                .with_fetch_retries(config.fetch_retries)
+                .with_search(config.search.clone())
+                .with_max_age(config.max_forward_age)
+                .with_readonly(readonly_state)
+                .with_newest_first(config.newest_first)
+                .with_min_age(
+                    config
+                        .grace_minutes
+                        .map(|minutes| Duration::from_secs(minutes * 60)),
+                );
+            let connect = |auth: &AuthMethod| {
+                let connection = ImapConnectionConfig {
+                    auth: auth.clone(),
+                    ..config.connection.clone()
+                };
+                ImapConnection::from_config(&connection, connection_limit.clone())
+            };
+            let con = if config.share_connection {
+                channel
+                    .shared_connections()
+                    .get_or_insert(config.connection_key(), || connect(&config.connection.auth))
+            } else {
+                Arc::new(connect(&config.connection.auth))
+            };
+            // Folders with credentials of their own are searched through a connection each,
+            // and skipped by the main connection.
+            let folder_auths = config.folder_auth.as_deref().unwrap_or_default();
+            let mut connections = vec![(None, con)];
+            for folder_auth in folder_auths {
+                let folder = Some(folder_auth.folder.as_str());
+                connections.push((folder, Arc::new(connect(&folder_auth.auth))));
+            }
+            if let Some(startup_retry) = config.startup_retry {
+                let duration = Duration::from_secs(startup_retry);
+                for (_, con) in &connections {
+                    if !con.connect_at_startup(duration, &channel, &log_target) {
+                        info!(target: &log_target, "Stopping");
+                        return;
+                    }
+                }
+            }
+            let exclude_attributes = config.exclude_attributes.as_deref().unwrap_or_default();
+            let mailboxes_of = |folder: Option<&str>, con: &ImapConnection| {
+                let mailboxes = con.iter_mailboxes_recursive(folder, exclude_attributes)?;
+                let skip_overrides = folder.is_none();
+                Ok::<_, anyhow::Error>(mailboxes.filter(move |mailbox| {
+                    !skip_overrides
+                        || !folder_auths
+                            .iter()
+                            .any(|other| mailbox.matches_path_filter(&other.folder))
+                }))
+            };
+            let count_unseen = || {
+                let mut count = 0;
+                for &(folder, ref con) in &connections {
+                    let _cycle = task::block_on(con.lock_cycle());
+                    for mailbox in mailboxes_of(folder, con)? {
+                        count += task::block_on(con.iter_unseen(&mailbox, &options))?.remaining();
+                    }
+                }
+                Ok::<_, anyhow::Error>(count)
+            };
+            // Kept mails are forwarded again, if they are not marked, so a misconfigured source
+            // would forward the whole account. Checked once, before anything is forwarded.
+            let filtered = config.search.is_some()
+                || config.max_forward_age.is_some()
+                || config.mark_skipped_seen;
+            let mut initial_checked = config.confirm_bulk || !config.keep || filtered;
+            let max_initial = config.max_initial.unwrap_or(DEFAULT_MAX_INITIAL);
+            let interval = Duration::from_secs(config.interval);
+            let close_idle_after = config.close_idle_after.map(Duration::from_secs);
+            let progress_every = config.progress_every.unwrap_or(DEFAULT_PROGRESS_EVERY);
+            // Set while the server is unreachable. Polling then backs off exponentially.
+            let mut offline_interval: Option<Duration> = None;
             loop {
-                debug!(target: &log_target, "Polling for unread mails");
-                match con.iter_mailboxes_recursive(None) {
-                    Ok(mailboxes) => {
-                        mailboxes.for_each(|mailbox| {
-                            let mut unread_mails = Vec::new();
-                            task::block_on(con.iter_unseen(&mailbox)).unwrap().for_each(
-                                |unseen_message| {
+                if !initial_checked {
+                    match count_unseen() {
+                        Ok(count) if count > max_initial => {
+                            error!(
+                                target: &log_target,
+                                "Found {} unread mails, more than max_initial ({}). Not forwarding any, to not flood the destinations with the whole account. Narrow the mails down with search or max_forward_age, mark the old mails as read, or set confirm_bulk to forward them all.",
+                                count,
+                                max_initial
+                            );
+                            info!(target: &log_target, "Stopping");
+                            return;
+                        }
+                        Ok(count) => {
+                            debug!(target: &log_target, "Found {} unread mails at startup", count);
+                            initial_checked = true;
+                        }
+                        Err(e) => {
+                            warn!(
+                                target: &log_target,
+                                "Failed to count the unread mails before forwarding, trying again\n{}",
+                                ErrorReport(&e)
+                            );
+                        }
+                    }
+                }
+                // Message-IDs forwarded in this cycle, see `dedup_batch`
+                let mut batch_message_ids = HashSet::new();
+                // mails forwarded in this cycle, see `on_new_mail_command`
+                let mut new_mails = 0;
+                let mut fetch_budget = config.max_per_poll.unwrap_or(usize::MAX);
+                // nothing is forwarded, until the unread mails could be counted
+                for &(folder, ref con) in connections.iter().filter(|_| initial_checked) {
+                    let cycle = task::block_on(con.lock_cycle());
+                    debug!(target: &log_target, "Polling for unread mails");
+                    match mailboxes_of(folder, con) {
+                        Ok(mailboxes) => {
+                            if offline_interval.take().is_some() {
+                                info!(
+                                    target: &log_target,
+                                    "Source online again, resuming normal polling"
+                                );
+                            }
+                            mailboxes.for_each(|mailbox| {
+                                let mut unread_mails = Vec::new();
+                                let mut unseen_mails = match task::block_on(con.iter_unseen(&mailbox, &options)) {
+                                    Ok(unseen_mails) => unseen_mails,
+                                    Err(e) => {
+                                        error!(
+                                            target: &log_target,
+                                            "Failed to search for unread mails in {}\n{}",
+                                            mailbox.path(),
+                                            ErrorReport(&e)
+                                        );
+                                        return;
+                                    }
+                                };
+                                let skipped = unseen_mails.limit(fetch_budget);
+                                fetch_budget -= unseen_mails.remaining();
+                                let forwarded_keyword = unseen_mails.forwarded_keyword();
+                                if !skipped.is_empty() && config.mark_skipped_seen {
+                                    let flag = forwarded_keyword.unwrap_or("\\Seen");
+                                    match task::block_on(con.add_keyword(&skipped, flag)) {
+                                        Ok(()) => info!(
+                                            target: &log_target,
+                                            "Reached max_per_poll, marked {} mails in {} as read without forwarding them",
+                                            skipped.len(),
+                                            mailbox.path()
+                                        ),
+                                        Err(e) => warn!(
+                                            target: &log_target,
+                                            "Failed to mark skipped messages\n{}",
+                                            ErrorReport(&e)
+                                        ),
+                                    }
+                                } else if !skipped.is_empty() {
+                                    info!(
+                                        target: &log_target,
+                                        "Reached max_per_poll, leaving {} mails in {} for the next poll",
+                                        skipped.len(),
+                                        mailbox.path()
+                                    );
+                                }
+                                let total = unseen_mails.remaining();
+                                let mut fetched = 0;
+                                unseen_mails.for_each(|unseen_message| {
+                                    fetched += 1;
+                                    if fetched % progress_every == 0 && fetched < total {
+                                        info!(
+                                            target: &log_target,
+                                            "Fetched {}/{} unread mails from {}",
+                                            fetched,
+                                            total,
+                                            mailbox.path()
+                                        );
+                                    }
                                     if let Ok((message_id, unseen_message)) = unseen_message {
                                         unread_mails.push(message_id);
                                         debug!(
@@ -61,34 +262,131 @@ impl MailSource for ImapPollSource {
                                             "Unread mail in {}",
                                             mailbox.path()
                                         );
-                                        channel.notify_new_mail(Mail::from_rfc822(
-                                            name.clone(),
-                                            unseen_message,
-                                        ));
+                                        let mail = Mail::from_rfc822(name.clone(), unseen_message)
+                                            .with_folder(mailbox.path());
+                                        if config.dedup_batch {
+                                            if let Some(message_id) = &mail.message_id {
+                                                if !batch_message_ids.insert(message_id.clone()) {
+                                                    debug!(
+                                                        target: &log_target,
+                                                        "Skipping duplicate of mail {} in {}",
+                                                        mail,
+                                                        mailbox.path()
+                                                    );
+                                                    return;
+                                                }
+                                            }
+                                        }
+                                        channel.notify_new_mail(mail);
+                                        new_mails += 1;
                                     }
-                                },
-                            );
-                            if !config.keep {
-                                if let Err(e) = task::block_on(con.delete_mails(&unread_mails)) {
+                                });
+                                if let Some(keyword) = forwarded_keyword {
+                                    if !unread_mails.is_empty() {
+                                        if let Err(e) =
+                                            task::block_on(con.add_keyword(&unread_mails, keyword))
+                                        {
+                                            warn!(
+                                                target: &log_target,
+                                                "Failed to mark forwarded messages\n{}",
+                                                ErrorReport(&e)
+                                            );
+                                        }
+                                    }
+                                }
+                                if let Some(folder) = &config.move_to {
+                                    if !unread_mails.is_empty() {
+                                        if let Err(e) =
+                                            task::block_on(con.move_mails(&unread_mails, folder))
+                                        {
+                                            warn!(
+                                                target: &log_target,
+                                                "Failed to move messages to {}\n{}",
+                                                folder,
+                                                ErrorReport(&e)
+                                            );
+                                        }
+                                    }
+                                } else if !config.keep {
+                                    if let Err(e) = task::block_on(con.delete_mails(&unread_mails))
+                                    {
+                                        warn!(
+                                            target: &log_target,
+                                            "Failed to deleted messages from mailbox\n{}",
+                                            ErrorReport(&e)
+                                        );
+                                    }
+                                }
+                                if let Some(days) = config.delete_older_than {
+                                    match task::block_on(con.delete_older_than(days)) {
+                                        Ok(0) => {}
+                                        Ok(deleted) => info!(
+                                            target: &log_target,
+                                            "Deleted {} mails older than {} days from {}",
+                                            deleted,
+                                            days,
+                                            mailbox.path()
+                                        ),
+                                        Err(e) => warn!(
+                                            target: &log_target,
+                                            "Failed to delete old mails from {}\n{}",
+                                            mailbox.path(),
+                                            ErrorReport(&e)
+                                        ),
+                                    }
+                                }
+                            });
+                        }
+                        Err(e) if ServerUnreachable::caused(&e) => {
+                            offline_interval = Some(match offline_interval {
+                                None => {
                                     warn!(
                                         target: &log_target,
-                                        "Failed to deleted messages from mailbox\n{}", e
+                                        "Source offline, backing off until the server is reachable again: {}",
+                                        ErrorReport(&e)
                                     );
+                                    interval
                                 }
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        error!(
-                            target: &log_target,
-                            "Failed to get recursive list of mailboxes to iterate\n{}",
-                            e.backtrace()
-                        );
+                                Some(offline_interval) => {
+                                    debug!(target: &log_target, "Source still offline: {}", ErrorReport(&e));
+                                    (offline_interval * 2).min(OFFLINE_INTERVAL_MAX.max(interval))
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!(
+                                target: &log_target,
+                                "Failed to get recursive list of mailboxes to iterate\n{}",
+                                ErrorReport(&e)
+                            );
+                        }
                     }
+
+                    drop(cycle);
+                }
+                if let Some(command) = config
+                    .on_new_mail_command
+                    .as_ref()
+                    .filter(|_| new_mails > 0)
+                {
+                    run_new_mail_command(command, &name, new_mails, &log_target);
                 }
 
                 // sleep until next poll is due - interrupt if requested to stop
-                match channel.next_timeout(Duration::from_secs(config.interval)) {
+                let mut wait = offline_interval.unwrap_or(interval);
+                if let Some(idle) = close_idle_after.filter(|idle| *idle < wait) {
+                    match channel.next_timeout(idle) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break, // shutdown
+                        _ => panic!(), // There currently are no SourceMessages
+                    }
+                    for (_, con) in &connections {
+                        let _cycle = task::block_on(con.lock_cycle());
+                        task::block_on(con.disconnect());
+                    }
+                    wait -= idle;
+                }
+                match channel.next_timeout(wait) {
                     Err(mpsc::RecvTimeoutError::Timeout) => {}
                     Err(mpsc::RecvTimeoutError::Disconnected) => break, // shutdown
                     _ => panic!(), // There currently are no SourceMessages
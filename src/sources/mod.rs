@@ -1,9 +1,13 @@
 use crate::hub::{HubSourceChannel, MailAgent};
 
 mod common;
+pub mod export;
 pub mod imap_idle;
 pub mod imap_poll;
 pub mod testsrc;
+mod transport;
+
+pub use common::SharedConnections;
 
 pub trait MailSource: MailAgent {
     fn start(&mut self, channel: HubSourceChannel);
@@ -0,0 +1,216 @@
+use crate::hub::ConnectionPermit;
+use async_native_tls::TlsStream;
+use async_std::net::TcpStream;
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Connection to the IMAP server
+#[derive(Debug)]
+pub enum ImapStream {
+    Tls(TlsStream<TcpStream>),
+    /// Unencrypted, with encryption `none`
+    Plain(TcpStream),
+    /// Local socket (e.g. of dovecot), which needs no encryption
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+impl AsyncRead for ImapStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ImapStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for ImapStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ImapStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ImapStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_close(cx),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            #[cfg(unix)]
+            ImapStream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// Stream underneath every IMAP client.
+///
+/// async-imap only hands out a `Session` after a successful LOGIN / AUTHENTICATE.
+/// For servers that greet with PREAUTH, the transport can answer the next command
+/// itself (see `answer_next_command`), so that LOGIN never reaches the server.
+///
+/// async-imap can also not parse the `* SORT` response. While a SORT command is in flight,
+/// its responses are rewritten to `* SEARCH`, which carries the same list of ids.
+#[derive(Debug)]
+pub struct ImapTransport {
+    inner: ImapStream,
+    /// Bytes returned to the client before anything else is read from the server
+    injected: VecDeque<u8>,
+    /// Set while the next command is being answered locally. Collects its bytes.
+    answered_command: Option<Vec<u8>>,
+    /// Command currently being written to the server, to detect SORT commands
+    command: Vec<u8>,
+    /// Tag of the SORT command in flight
+    sort_tag: Option<Vec<u8>>,
+    /// Incomplete response line, held back while rewriting SORT responses
+    line: Vec<u8>,
+    /// Released when the session is closed, to let other sources connect
+    _permit: ConnectionPermit,
+}
+impl ImapTransport {
+    pub fn new(inner: ImapStream, permit: ConnectionPermit) -> Self {
+        Self {
+            inner,
+            injected: VecDeque::new(),
+            answered_command: None,
+            command: Vec::new(),
+            sort_tag: None,
+            line: Vec::new(),
+            _permit: permit,
+        }
+    }
+
+    /// Do not send the next command to the server, but answer it with a tagged OK.
+    pub fn answer_next_command(&mut self) {
+        self.answered_command = Some(Vec::new());
+    }
+
+    /// Returns the tag of `command`, if it is a (UID) SORT command.
+    fn sort_command_tag(command: &[u8]) -> Option<Vec<u8>> {
+        let mut words = command.split(|b| *b == b' ');
+        let tag = words.next()?;
+        let mut name = words.next()?;
+        if name.eq_ignore_ascii_case(b"UID") {
+            name = words.next()?;
+        }
+        name.eq_ignore_ascii_case(b"SORT").then(|| tag.to_vec())
+    }
+
+    /// Move all complete lines to the bytes returned to the client, rewriting SORT responses.
+    fn rewrite_sort_responses(&mut self) {
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            match line.strip_prefix(b"* SORT") {
+                Some(ids) => {
+                    self.injected.extend(b"* SEARCH");
+                    self.injected.extend(ids);
+                }
+                None => self.injected.extend(&line),
+            }
+            if let Some(tag) = &self.sort_tag {
+                if line.starts_with(tag) && line.get(tag.len()) == Some(&b' ') {
+                    // the command completed, everything after this is passed through
+                    self.sort_tag = None;
+                    self.injected.extend(self.line.drain(..));
+                    return;
+                }
+            }
+        }
+    }
+}
+impl AsyncRead for ImapTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.injected.is_empty() {
+            let len = buf.len().min(self.injected.len());
+            for (dst, src) in buf.iter_mut().zip(self.injected.drain(..len)) {
+                *dst = src;
+            }
+            return Poll::Ready(Ok(len));
+        }
+        if self.sort_tag.is_none() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        loop {
+            let mut chunk = [0u8; 1024];
+            let len = match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(len)) => len,
+                other => return other,
+            };
+            self.line.extend_from_slice(&chunk[..len]);
+            self.rewrite_sort_responses();
+            if !self.injected.is_empty() {
+                return self.poll_read(cx, buf);
+            }
+        }
+    }
+}
+impl AsyncWrite for ImapTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match this.answered_command.as_mut() {
+            Some(command) => {
+                command.extend_from_slice(buf);
+                if command.ends_with(b"\r\n") {
+                    let tag = command.split(|b| *b == b' ').next().unwrap_or_default();
+                    this.injected.extend(tag);
+                    this.injected
+                        .extend(b" OK Already authenticated (PREAUTH)\r\n");
+                    this.answered_command = None;
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+            None => {
+                let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+                if let Poll::Ready(Ok(len)) = result {
+                    // async-imap writes the tag and the command separately
+                    this.command.extend_from_slice(&buf[..len]);
+                    if this.command.ends_with(b"\r\n") {
+                        if let Some(tag) = Self::sort_command_tag(&this.command) {
+                            this.sort_tag = Some(tag);
+                        }
+                        this.command.clear();
+                    }
+                }
+                result
+            }
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}